@@ -0,0 +1,409 @@
+use crate::config::LockoutConfig;
+use crate::r#trait::ErrorMessage;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Lockout events kept for `/admin/lockouts`.
+const MAX_EVENTS: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum AttemptKey {
+    Ip(IpAddr),
+    Username(String),
+}
+
+impl std::fmt::Display for AttemptKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttemptKey::Ip(ip) => write!(f, "ip {}", ip),
+            AttemptKey::Username(username) => write!(f, "user '{}'", username),
+        }
+    }
+}
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+    locked: bool,
+    /// Attempts still checking their password
+    pending: u32,
+}
+
+impl Attempts {
+    fn new(now: Instant) -> Self {
+        Self {
+            failures: 0,
+            last_failure: now,
+            blocked_until: None,
+            locked: false,
+            pending: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LockoutEvent {
+    pub key: AttemptKey,
+    pub failures: u32,
+    /// Unix time in seconds
+    pub at: u64,
+    pub until: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockedKey {
+    pub key: AttemptKey,
+    pub failures: u32,
+    pub locked: bool,
+    pub retry_after_secs: u64,
+}
+
+/// A login that passed the guard. It counts as pending until its
+/// password is checked, so parallel attempts cannot all slip past a
+/// backoff that the first failures would have set.
+pub struct LoginAttempt<'a> {
+    guard: &'a LoginGuard,
+    ip: IpAddr,
+    username: Option<String>,
+}
+
+impl LoginAttempt<'_> {
+    pub fn failed(self) {
+        self.guard.record_failure(self.ip, self.username.as_deref());
+    }
+
+    pub fn succeeded(self) {
+        if let Some(username) = &self.username {
+            self.guard.record_success(username);
+        }
+    }
+}
+
+impl Drop for LoginAttempt<'_> {
+    fn drop(&mut self) {
+        let mut attempts = self.guard.attempts.lock().unwrap();
+        for key in keys(self.ip, self.username.as_deref()) {
+            if let Some(entry) = attempts.get_mut(&key) {
+                entry.pending = entry.pending.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Tracks failed logins per client IP and per username. After
+/// `free_attempts` failures each further one doubles a backoff delay, and
+/// at `threshold` failures the key is locked out for `duration_secs`.
+pub struct LoginGuard {
+    config: LockoutConfig,
+    attempts: Mutex<HashMap<AttemptKey, Attempts>>,
+    events: Mutex<VecDeque<LockoutEvent>>,
+}
+
+fn unix_secs(at: Instant) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let offset = at.saturating_duration_since(Instant::now());
+    (now + offset).as_secs()
+}
+
+fn keys(ip: IpAddr, username: Option<&str>) -> Vec<AttemptKey> {
+    let mut keys = vec![AttemptKey::Ip(ip)];
+    if let Some(username) = username.filter(|u| !u.is_empty()) {
+        keys.push(AttemptKey::Username(username.to_string()));
+    }
+    keys
+}
+
+impl LoginGuard {
+    pub fn new(config: LockoutConfig) -> Self {
+        Self {
+            config,
+            attempts: Mutex::new(HashMap::new()),
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Refuses the attempt while the client IP or the username is backing
+    /// off or locked out. Once the free attempts are used up by failures
+    /// and pending attempts, a key gets one attempt at a time, as if the
+    /// clients had waited for each answer.
+    pub fn begin(&self, ip: IpAddr, username: Option<&str>) -> Result<LoginAttempt<'_>, ErrorMessage> {
+        let now = Instant::now();
+        let keys = keys(ip, username);
+        let mut attempts = self.attempts.lock().unwrap();
+        let wait = keys.iter()
+            .filter_map(|key| attempts.get(key)?.blocked_until)
+            .filter(|until| *until > now)
+            .max();
+        if let Some(until) = wait {
+            let retry_after = until.duration_since(now).as_secs().max(1);
+            return Err(ErrorMessage::locked_out(retry_after));
+        }
+
+        let busy = keys.iter()
+            .filter_map(|key| attempts.get(key))
+            .any(|a| a.pending > 0 && a.failures + a.pending >= self.config.free_attempts);
+        if busy {
+            return Err(ErrorMessage::locked_out(1));
+        }
+
+        for key in keys {
+            attempts.entry(key).or_insert_with(|| Attempts::new(now)).pending += 1;
+        }
+        Ok(LoginAttempt {
+            guard: self,
+            ip,
+            username: username.map(str::to_string),
+        })
+    }
+
+    fn record_failure(&self, ip: IpAddr, username: Option<&str>) {
+        let now = Instant::now();
+        let forget_after = Duration::from_secs(self.config.duration_secs);
+        let mut attempts = self.attempts.lock().unwrap();
+        // Forget keys that have been quiet for a whole lockout period
+        attempts.retain(|_, a| {
+            a.pending > 0
+                || now.duration_since(a.last_failure) < forget_after
+                || a.blocked_until.is_some_and(|until| until > now)
+        });
+
+        for key in keys(ip, username) {
+            let entry = attempts.entry(key.clone()).or_insert_with(|| Attempts::new(now));
+            if entry.locked && entry.blocked_until.is_none_or(|until| until <= now) {
+                // Lockout served, the next one is a new event
+                entry.locked = false;
+            }
+            entry.failures += 1;
+            entry.last_failure = now;
+
+            if entry.failures >= self.config.threshold {
+                let until = now + Duration::from_secs(self.config.duration_secs);
+                entry.blocked_until = Some(until);
+                if !entry.locked {
+                    entry.locked = true;
+                    self.lockout_event(key, entry.failures, until);
+                }
+            } else if entry.failures > self.config.free_attempts {
+                let exponent = (entry.failures - self.config.free_attempts - 1).min(16);
+                let delay = Duration::from_secs(1 << exponent)
+                    .min(Duration::from_secs(self.config.max_backoff_secs));
+                entry.blocked_until = Some(now + delay);
+                println!("Failed login for {}: {} failures, backing off {:?}", key, entry.failures, delay);
+            }
+        }
+    }
+
+    /// A good password clears the username's record. The IP keeps its
+    /// record so one valid account does not reset a stuffing run.
+    fn record_success(&self, username: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        if let Some(entry) = attempts.get_mut(&AttemptKey::Username(username.to_string())) {
+            // Other attempts on the account may still be pending
+            *entry = Attempts {
+                pending: entry.pending,
+                ..Attempts::new(Instant::now())
+            };
+        }
+    }
+
+    fn lockout_event(&self, key: AttemptKey, failures: u32, until: Instant) {
+        let event = LockoutEvent {
+            key,
+            failures,
+            at: unix_secs(Instant::now()),
+            until: unix_secs(until),
+        };
+        println!(
+            "Locked out {} after {} failed logins for {} seconds",
+            event.key, failures, self.config.duration_secs
+        );
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    pub fn blocked(&self) -> Vec<BlockedKey> {
+        let now = Instant::now();
+        self.attempts.lock().unwrap().iter()
+            .filter_map(|(key, a)| {
+                let until = a.blocked_until.filter(|until| *until > now)?;
+                Some(BlockedKey {
+                    key: key.clone(),
+                    failures: a.failures,
+                    locked: a.locked,
+                    retry_after_secs: until.duration_since(now).as_secs().max(1),
+                })
+            })
+            .collect()
+    }
+
+    pub fn events(&self) -> Vec<LockoutEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Lifts every backoff and lockout, the event log is kept.
+    pub fn clear(&self) {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, a| a.pending > 0);
+        for entry in attempts.values_mut() {
+            *entry = Attempts {
+                pending: entry.pending,
+                ..Attempts::new(now)
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ErrorCode;
+
+    fn ip() -> IpAddr {
+        "192.0.2.1".parse().unwrap()
+    }
+
+    fn guard(free_attempts: u32, max_backoff_secs: u64, threshold: u32) -> LoginGuard {
+        LoginGuard::new(LockoutConfig {
+            free_attempts,
+            max_backoff_secs,
+            threshold,
+            duration_secs: 900,
+        })
+    }
+
+    fn fail(guard: &LoginGuard, times: u32) {
+        for _ in 0..times {
+            guard.record_failure(ip(), Some("alice"));
+        }
+    }
+
+    fn retry_after(guard: &LoginGuard) -> Option<u64> {
+        guard.begin(ip(), Some("alice")).err().and_then(|e| e.retry_after_secs)
+    }
+
+    #[test]
+    fn free_attempts_are_not_delayed() {
+        let guard = guard(3, 60, 10);
+        fail(&guard, 3);
+        assert!(guard.begin(ip(), Some("alice")).is_ok());
+        assert!(guard.blocked().is_empty());
+    }
+
+    #[test]
+    fn backoff_starts_after_the_free_attempts() {
+        let guard = guard(3, 60, 10);
+        fail(&guard, 4);
+        let error = guard.begin(ip(), Some("alice")).err().unwrap();
+        assert_eq!(error.code, ErrorCode::LockedOut);
+        assert_eq!(error.retry_after_secs, Some(1));
+    }
+
+    #[test]
+    fn backoff_doubles_with_every_failure() {
+        let guard = guard(0, 3600, 100);
+        fail(&guard, 5);
+        // 16 seconds, a moment of which has passed
+        assert!(matches!(retry_after(&guard), Some(15 | 16)));
+    }
+
+    #[test]
+    fn backoff_is_capped_at_max_backoff_secs() {
+        let guard = guard(0, 10, 100);
+        fail(&guard, 20);
+        assert!(matches!(retry_after(&guard), Some(9 | 10)));
+    }
+
+    #[test]
+    fn backoff_exponent_is_capped() {
+        let guard = guard(0, u64::MAX, 100);
+        fail(&guard, 40);
+        assert!(matches!(retry_after(&guard), Some(65_535 | 65_536)));
+    }
+
+    #[test]
+    fn threshold_locks_out_ip_and_username() {
+        let guard = guard(10, 60, 3);
+        fail(&guard, 2);
+        assert!(guard.begin(ip(), Some("alice")).is_ok());
+
+        fail(&guard, 1);
+        assert!(matches!(retry_after(&guard), Some(899 | 900)));
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(guard.begin(other_ip, Some("alice")).is_err());
+        assert!(guard.begin(ip(), Some("bob")).is_err());
+        assert!(guard.begin(other_ip, Some("bob")).is_ok());
+
+        let blocked = guard.blocked();
+        assert_eq!(blocked.len(), 2);
+        assert!(blocked.iter().all(|key| key.locked && key.failures == 3));
+        assert_eq!(guard.events().len(), 2);
+    }
+
+    #[test]
+    fn failures_while_locked_out_are_one_event() {
+        let guard = guard(10, 60, 3);
+        fail(&guard, 6);
+        assert_eq!(guard.events().len(), 2);
+    }
+
+    #[test]
+    fn success_clears_the_username_but_not_the_ip() {
+        let guard = guard(10, 60, 3);
+        fail(&guard, 3);
+        guard.record_success("alice");
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(guard.begin(other_ip, Some("alice")).is_ok());
+        assert!(guard.begin(ip(), Some("alice")).is_err());
+    }
+
+    #[test]
+    fn parallel_attempts_do_not_skip_the_backoff() {
+        let guard = guard(2, 60, 10);
+        let first = guard.begin(ip(), Some("alice")).unwrap();
+        let second = guard.begin(ip(), Some("alice")).unwrap();
+        // Both free attempts are pending, a third has to wait for them
+        assert_eq!(guard.begin(ip(), Some("alice")).err().unwrap().code, ErrorCode::LockedOut);
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(guard.begin(other_ip, Some("alice")).is_err());
+        assert!(guard.begin(ip(), Some("bob")).is_err());
+
+        first.failed();
+        second.failed();
+        // One attempt at a time once the free attempts are used up
+        let third = guard.begin(ip(), Some("alice")).unwrap();
+        assert!(guard.begin(ip(), Some("alice")).is_err());
+        third.failed();
+        assert_eq!(retry_after(&guard), Some(1));
+    }
+
+    #[test]
+    fn finished_attempts_are_no_longer_pending() {
+        let guard = guard(1, 60, 10);
+        let attempt = guard.begin(ip(), Some("alice")).unwrap();
+        assert!(guard.begin(ip(), Some("alice")).is_err());
+        attempt.succeeded();
+        assert!(guard.begin(ip(), Some("alice")).is_ok());
+        // An attempt dropped without an answer, e.g. by a closed socket
+        drop(guard.begin(ip(), Some("alice")).unwrap());
+        assert!(guard.begin(ip(), Some("alice")).is_ok());
+    }
+
+    #[test]
+    fn clear_lifts_every_block_and_keeps_events() {
+        let guard = guard(10, 60, 3);
+        fail(&guard, 3);
+        guard.clear();
+        assert!(guard.begin(ip(), Some("alice")).is_ok());
+        assert_eq!(guard.events().len(), 2);
+    }
+}
@@ -0,0 +1,205 @@
+pub mod lockout;
+pub mod permissions;
+pub mod session;
+pub mod user_store;
+
+use crate::auth::permissions::{Action, Role};
+use crate::auth::session::Session;
+use crate::protocol::ErrorCode;
+use crate::r#trait::{AppState, ErrorMessage};
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::{header, request::Parts, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use base64::engine::general_purpose;
+use base64::Engine;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub username: String,
+    pub role: Role,
+}
+
+impl AuthUser {
+    pub fn check(&self, action: Action) -> Result<(), ErrorMessage> {
+        if self.role.allows(action) {
+            Ok(())
+        } else {
+            Err(ErrorMessage::forbidden(action, self.role))
+        }
+    }
+}
+
+/// Splits a `Basic <base64 username:password>` header value.
+pub fn parse_basic(auth_str: &str) -> Option<(String, String)> {
+    let encoded = auth_str.strip_prefix("Basic ")?;
+    let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded_str = String::from_utf8(decoded).ok()?;
+    // Only the first ':' separates, passwords may contain more
+    let (username, password) = decoded_str.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Checks a username and password coming from `ip`. Failures count
+/// towards the backoff and lockout of both the IP and the username, and
+/// blocked attempts are refused before the password is looked at.
+pub async fn verify_credentials(
+    state: &AppState,
+    ip: IpAddr,
+    username: String,
+    password: String,
+) -> Result<AuthUser, ErrorMessage> {
+    let attempt = state.login_guard.begin(ip, Some(&username))?;
+
+    // argon2 is slow on purpose, keep it off the async workers
+    let users = state.user_store.clone();
+    let name = username.clone();
+    let role = tokio::task::spawn_blocking(move || users.authenticate(&name, &password))
+        .await
+        .ok()
+        .flatten();
+
+    match role {
+        Some(role) => {
+            attempt.succeeded();
+            Ok(AuthUser { username, role })
+        }
+        None => {
+            attempt.failed();
+            Err(ErrorMessage::new(ErrorCode::Unauthorized, "Invalid username or password"))
+        }
+    }
+}
+
+/// Checks a `Basic <base64 username:password>` header value sent from `ip`.
+pub async fn authenticate(auth_str: &str, state: &AppState, ip: IpAddr) -> Result<AuthUser, ErrorMessage> {
+    match parse_basic(auth_str) {
+        Some((username, password)) => verify_credentials(state, ip, username, password).await,
+        None => {
+            state.login_guard.begin(ip, None)?.failed();
+            Err(ErrorMessage::new(ErrorCode::Unauthorized, "Valid credentials are required"))
+        }
+    }
+}
+
+/// Authenticates requests that cannot always set headers, like sockets
+/// and `<img>` sources: a session token as `?token=` or `Authorization:
+/// Bearer`, otherwise Basic credentials. The session is returned so the
+/// connection can end with it.
+pub async fn authenticate_request(
+    headers: &HeaderMap,
+    query_token: Option<&str>,
+    state: &AppState,
+    ip: IpAddr,
+) -> Result<(Option<Session>, AuthUser), ErrorMessage> {
+    if let Some(session) = state.sessions.from_request(headers, query_token, &state.user_store)? {
+        let user = session.user.clone();
+        return Ok((Some(session), user));
+    }
+    let authorization = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    authenticate(authorization, state, ip).await.map(|user| (None, user))
+}
+
+/// Reply to a failed first-frame login on a socket. Existing clients look
+/// for the plain `Unauthorized` text, other errors like a lockout go out
+/// as JSON.
+pub fn login_failure_text(error: &ErrorMessage) -> String {
+    if error.code == ErrorCode::Unauthorized {
+        "Unauthorized".to_string()
+    } else {
+        error.to_json()
+    }
+}
+
+/// Error response for REST handlers, status picked from the error code.
+impl IntoResponse for ErrorMessage {
+    fn into_response(self) -> Response {
+        let status = match self.code {
+            ErrorCode::Unauthorized | ErrorCode::TokenExpired | ErrorCode::TokenRevoked => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::LockedOut => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        if let Some(retry_after) = self.retry_after_secs {
+            let retry = [(header::RETRY_AFTER, retry_after.to_string())];
+            return (status, retry, Json(self)).into_response();
+        }
+        if status == StatusCode::UNAUTHORIZED {
+            let challenge = [(header::WWW_AUTHENTICATE, "Basic realm=\"monitor-system\"")];
+            return (status, challenge, Json(self)).into_response();
+        }
+        (status, Json(self)).into_response()
+    }
+}
+
+/// Authenticates REST requests from their `Authorization` header, either
+/// `Bearer <session token>` or `Basic <credentials>`.
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = ErrorMessage;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let header = parts.headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        if let Some(token) = header.strip_prefix("Bearer ") {
+            return state.sessions.validate(token.trim(), &state.user_store).map(|session| session.user);
+        }
+        authenticate(&header, state, client_ip(parts)).await
+    }
+}
+
+/// Address of the peer, from the connect info added by `main`.
+pub fn client_ip(parts: &Parts) -> IpAddr {
+    parts.extensions.get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", general_purpose::STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn parses_basic_credentials() {
+        assert_eq!(parse_basic(&basic("alice:secret")), Some(("alice".to_string(), "secret".to_string())));
+    }
+
+    #[test]
+    fn passwords_may_contain_colons() {
+        assert_eq!(parse_basic(&basic("alice:a:b:c")), Some(("alice".to_string(), "a:b:c".to_string())));
+        assert_eq!(parse_basic(&basic("alice::")), Some(("alice".to_string(), ":".to_string())));
+    }
+
+    #[test]
+    fn empty_password_is_kept() {
+        assert_eq!(parse_basic(&basic("alice:")), Some(("alice".to_string(), String::new())));
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        let header = format!("{}  ", basic("alice:secret"));
+        assert_eq!(parse_basic(&header), Some(("alice".to_string(), "secret".to_string())));
+    }
+
+    #[test]
+    fn rejects_malformed_credentials() {
+        assert_eq!(parse_basic(&basic("alice")), None);
+        assert_eq!(parse_basic("Basic not-base64!"), None);
+        assert_eq!(parse_basic(&format!("Bearer {}", general_purpose::STANDARD.encode("alice:secret"))), None);
+        assert_eq!(parse_basic(&format!("Basic {}", general_purpose::STANDARD.encode([0xff, b':', 0xfe]))), None);
+    }
+}
@@ -0,0 +1,60 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Roles are ordered, each one can do everything the previous one can.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Receive frames of a camera that is already running, or start the default one
+    Watch,
+    /// Receive the microphone stream
+    Listen,
+    /// Relay WebRTC signaling and chat between peers
+    Signal,
+    /// Start a camera nobody is watching, other than the default one
+    StartCamera,
+    /// Change capture settings or image controls of a camera
+    ConfigureCamera,
+    /// Save a clip of a camera on demand
+    TriggerClip,
+    /// Delete recorded segments and clips
+    DeleteRecordings,
+    ManageUsers,
+    ManageConfig,
+}
+
+impl Action {
+    pub fn required_role(self) -> Role {
+        match self {
+            Action::Watch | Action::Listen | Action::Signal => Role::Viewer,
+            Action::StartCamera | Action::ConfigureCamera | Action::TriggerClip => Role::Operator,
+            Action::DeleteRecordings | Action::ManageUsers | Action::ManageConfig => Role::Admin,
+        }
+    }
+}
+
+impl Role {
+    pub fn allows(self, action: Action) -> bool {
+        self >= action.required_role()
+    }
+}
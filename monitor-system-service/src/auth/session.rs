@@ -0,0 +1,225 @@
+use crate::auth::permissions::Role;
+use crate::auth::user_store::UserStore;
+use crate::auth::AuthUser;
+use crate::protocol::ErrorCode;
+use crate::r#trait::ErrorMessage;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use axum::extract::ws::{CloseFrame, Message};
+use axum::http::{header, HeaderMap};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+
+/// How often live sockets re-check that their user still exists, so
+/// removals made with `monitor-system user remove` are noticed too.
+const USER_RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Close code sent when a session ends under a live socket (policy violation).
+const SESSION_CLOSE_CODE: u16 = 1008;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub role: Role,
+    pub jti: String,
+    pub iat: u64,
+    pub exp: u64,
+    /// Issue time in Unix milliseconds, `iat` is too coarse to order a
+    /// login against a revocation in the same second
+    pub iat_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user: AuthUser,
+    pub claims: Claims,
+}
+
+#[derive(Default)]
+struct Revoked {
+    /// Token id to expiry, dropped once the token would have expired anyway
+    tokens: HashMap<String, u64>,
+    /// Tokens of a user issued at or before this Unix millisecond are no
+    /// longer valid
+    users: HashMap<String, u64>,
+}
+
+/// Issues and checks HMAC-signed JWT session tokens. Revocations live in
+/// memory, restarting the server with a new secret ends every session.
+pub struct SessionManager {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    ttl: Duration,
+    revoked: Mutex<Revoked>,
+    changes: watch::Sender<u64>,
+}
+
+fn now_secs() -> u64 {
+    now_ms() / 1000
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default()
+}
+
+impl SessionManager {
+    /// Without a configured secret a random one is generated, tokens then
+    /// stop working when the server restarts.
+    pub fn new(secret: Option<&str>, ttl: Duration) -> Self {
+        let secret = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                println!("No auth.session_secret set, session tokens end when the server restarts");
+                let mut secret = vec![0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+                secret
+            }
+        };
+        let (changes, _) = watch::channel(0);
+        Self {
+            encoding: EncodingKey::from_secret(&secret),
+            decoding: DecodingKey::from_secret(&secret),
+            ttl,
+            revoked: Mutex::new(Revoked::default()),
+            changes,
+        }
+    }
+
+    pub fn issue(&self, user: &AuthUser) -> Result<(String, Claims), String> {
+        let iat_ms = now_ms();
+        let iat = iat_ms / 1000;
+        let claims = Claims {
+            sub: user.username.clone(),
+            role: user.role,
+            jti: uuid::Uuid::new_v4().to_string(),
+            iat,
+            exp: iat + self.ttl.as_secs(),
+            iat_ms,
+        };
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+            .map_err(|e| format!("Failed to sign session token: {}", e))?;
+        Ok((token, claims))
+    }
+
+    /// Checks signature, expiry and revocation. The role comes from the
+    /// user store so role changes apply to tokens already handed out.
+    pub fn validate(&self, token: &str, users: &UserStore) -> Result<Session, ErrorMessage> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.decoding, &validation)
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+                    ErrorMessage::new(ErrorCode::TokenExpired, "Session token has expired")
+                }
+                _ => ErrorMessage::new(ErrorCode::Unauthorized, "Invalid session token"),
+            })?
+            .claims;
+        self.check(claims, users)
+    }
+
+    fn check(&self, claims: Claims, users: &UserStore) -> Result<Session, ErrorMessage> {
+        if claims.exp <= now_secs() {
+            return Err(ErrorMessage::new(ErrorCode::TokenExpired, "Session token has expired"));
+        }
+        {
+            let revoked = self.revoked.lock().unwrap();
+            let user_revoked = revoked.users.get(&claims.sub).is_some_and(|&cutoff| claims.iat_ms <= cutoff);
+            if user_revoked || revoked.tokens.contains_key(&claims.jti) {
+                return Err(ErrorMessage::new(ErrorCode::TokenRevoked, "Session token has been revoked"));
+            }
+        }
+        let role = users.role(&claims.sub)
+            .ok_or_else(|| ErrorMessage::new(ErrorCode::TokenRevoked, "User no longer exists"))?;
+
+        Ok(Session {
+            user: AuthUser {
+                username: claims.sub.clone(),
+                role,
+            },
+            claims,
+        })
+    }
+
+    /// Picks the token from the `token` query parameter or an
+    /// `Authorization: Bearer` header. `Ok(None)` means no token was sent.
+    pub fn from_request(
+        &self,
+        headers: &HeaderMap,
+        query_token: Option<&str>,
+        users: &UserStore,
+    ) -> Result<Option<Session>, ErrorMessage> {
+        match query_token.map(str::to_string).or_else(|| bearer_token(headers)) {
+            Some(token) => self.validate(&token, users).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn revoke(&self, claims: &Claims) {
+        let now = now_secs();
+        let mut revoked = self.revoked.lock().unwrap();
+        revoked.tokens.retain(|_, exp| *exp > now);
+        revoked.tokens.insert(claims.jti.clone(), claims.exp);
+        drop(revoked);
+        self.changes.send_modify(|generation| *generation += 1);
+    }
+
+    /// Ends every session of `username` issued up to now.
+    pub fn revoke_user(&self, username: &str) {
+        let now = now_ms();
+        let max_age = self.ttl.as_millis() as u64;
+        let mut revoked = self.revoked.lock().unwrap();
+        revoked.users.retain(|_, cutoff| *cutoff + max_age > now);
+        revoked.users.insert(username.to_string(), now);
+        drop(revoked);
+        self.changes.send_modify(|generation| *generation += 1);
+    }
+
+    /// Resolves with the reason once `session` expires, is revoked or its
+    /// user is removed. Never resolves for sockets without a session.
+    pub async fn ended(self: Arc<Self>, users: Arc<UserStore>, session: Option<Session>) -> ErrorMessage {
+        let claims = match session {
+            Some(session) => session.claims,
+            None => return std::future::pending().await,
+        };
+        let mut changes = self.changes.subscribe();
+        let expires_in = Duration::from_secs(claims.exp.saturating_sub(now_secs()));
+        let expiry = tokio::time::sleep(expires_in);
+        tokio::pin!(expiry);
+        let mut recheck = tokio::time::interval(USER_RECHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = &mut expiry => {
+                    return ErrorMessage::new(ErrorCode::TokenExpired, "Session token has expired");
+                }
+                changed = changes.changed() => {
+                    if changed.is_err() {
+                        return std::future::pending().await;
+                    }
+                }
+                _ = recheck.tick() => {}
+            }
+            if let Err(e) = self.check(claims.clone(), &users) {
+                return e;
+            }
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+/// Close frame for a socket whose session ended, the reason is the error code.
+pub fn close_message(error: &ErrorMessage) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: SESSION_CLOSE_CODE,
+        reason: error.code.as_str().into(),
+    }))
+}
@@ -0,0 +1,307 @@
+use crate::auth::permissions::Role;
+use crate::config::UserAction;
+use crate::storage::{modified_time, read_toml, write_atomic};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub username: String,
+    pub password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UsersFile {
+    #[serde(default)]
+    users: Vec<UserRecord>,
+}
+
+struct Loaded {
+    users: BTreeMap<String, UserRecord>,
+    modified: Option<SystemTime>,
+}
+
+/// Users and argon2 password hashes kept in a TOML file. The file is
+/// re-read when it changes on disk, so `monitor-system user ...` takes
+/// effect on a running server.
+pub struct UserStore {
+    path: PathBuf,
+    loaded: RwLock<Loaded>,
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Failed to hash password: {}", e))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Hash checked for unknown usernames so a miss costs as much as a hit.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("dummy-password").unwrap_or_default())
+}
+
+impl UserStore {
+    /// Opens the store at `path`. A missing file is an empty store.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let loaded = Self::read(&path)?;
+        Ok(Self {
+            path,
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    fn read(path: &Path) -> Result<Loaded, String> {
+        let modified = modified_time(path);
+        let file: UsersFile = read_toml(path)?;
+
+        let users = file.users.into_iter()
+            .map(|user| (user.username.clone(), user))
+            .collect();
+        Ok(Loaded { users, modified })
+    }
+
+    fn refresh(&self) {
+        let modified = modified_time(&self.path);
+        if self.loaded.read().unwrap().modified == modified {
+            return;
+        }
+
+        match Self::read(&self.path) {
+            Ok(loaded) => {
+                println!("Reloaded users from {}", self.path.display());
+                *self.loaded.write().unwrap() = loaded;
+            }
+            Err(e) => eprintln!("Keeping previous users: {}", e),
+        }
+    }
+
+    fn save(&self, loaded: &mut Loaded) -> Result<(), String> {
+        let file = UsersFile {
+            users: loaded.users.values().cloned().collect(),
+        };
+        let content = toml::to_string_pretty(&file)
+            .map_err(|e| format!("Failed to serialize users: {}", e))?;
+
+        // Password hashes are for the owner only
+        write_atomic(&self.path, &content, Some(0o600))?;
+        loaded.modified = modified_time(&self.path);
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.refresh();
+        self.loaded.read().unwrap().users.is_empty()
+    }
+
+    pub fn users(&self) -> Vec<(String, Role)> {
+        self.refresh();
+        self.loaded.read().unwrap().users.values()
+            .map(|user| (user.username.clone(), user.role))
+            .collect()
+    }
+
+    pub fn role(&self, username: &str) -> Option<Role> {
+        self.refresh();
+        self.loaded.read().unwrap().users.get(username).map(|user| user.role)
+    }
+
+    /// Returns the role of `username` when `password` matches its hash.
+    pub fn authenticate(&self, username: &str, password: &str) -> Option<Role> {
+        self.refresh();
+        let user = self.loaded.read().unwrap().users.get(username).cloned();
+        match user {
+            Some(user) if verify_password(password, &user.password_hash) => Some(user.role),
+            Some(_) => None,
+            None => {
+                verify_password(password, dummy_hash());
+                None
+            }
+        }
+    }
+
+    pub fn add_user(&self, username: &str, password: &str, role: Role) -> Result<(), String> {
+        validate_username(username)?;
+        validate_password(password)?;
+        self.refresh();
+
+        let mut loaded = self.loaded.write().unwrap();
+        if loaded.users.contains_key(username) {
+            return Err(format!("User '{}' already exists", username));
+        }
+        loaded.users.insert(username.to_string(), UserRecord {
+            username: username.to_string(),
+            password_hash: hash_password(password)?,
+            role,
+        });
+        self.save(&mut loaded)
+    }
+
+    pub fn remove_user(&self, username: &str) -> Result<(), String> {
+        self.refresh();
+
+        let mut loaded = self.loaded.write().unwrap();
+        if loaded.users.remove(username).is_none() {
+            return Err(format!("User '{}' does not exist", username));
+        }
+        self.save(&mut loaded)
+    }
+
+    pub fn set_password(&self, username: &str, password: &str) -> Result<(), String> {
+        validate_password(password)?;
+        self.refresh();
+
+        let mut loaded = self.loaded.write().unwrap();
+        match loaded.users.get_mut(username) {
+            Some(user) => user.password_hash = hash_password(password)?,
+            None => return Err(format!("User '{}' does not exist", username)),
+        }
+        self.save(&mut loaded)
+    }
+
+    pub fn set_role(&self, username: &str, role: Role) -> Result<(), String> {
+        self.refresh();
+
+        let mut loaded = self.loaded.write().unwrap();
+        match loaded.users.get_mut(username) {
+            Some(user) => user.role = role,
+            None => return Err(format!("User '{}' does not exist", username)),
+        }
+        self.save(&mut loaded)
+    }
+}
+
+fn validate_username(username: &str) -> Result<(), String> {
+    if username.is_empty() {
+        return Err("Username must not be empty".to_string());
+    }
+    // Basic credentials are `username:password`, so only the password may contain ':'
+    if username.contains(':') || username.chars().any(char::is_control) {
+        return Err("Username must not contain ':' or control characters".to_string());
+    }
+    Ok(())
+}
+
+fn validate_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < 8 {
+        return Err("Password must be at least 8 characters".to_string());
+    }
+    Ok(())
+}
+
+fn read_new_password(username: &str) -> Result<String, String> {
+    use std::io::IsTerminal;
+
+    if !std::io::stdin().is_terminal() {
+        // Scripted use: take the first line of stdin
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)
+            .map_err(|e| format!("Failed to read password: {}", e))?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let password = rpassword::prompt_password(format!("New password for {}: ", username))
+        .map_err(|e| format!("Failed to read password: {}", e))?;
+    let confirm = rpassword::prompt_password("Repeat password: ")
+        .map_err(|e| format!("Failed to read password: {}", e))?;
+    if password != confirm {
+        return Err("Passwords do not match".to_string());
+    }
+    Ok(password)
+}
+
+/// Runs a `monitor-system user ...` subcommand against the store.
+pub fn run_user_command(store: &UserStore, action: &UserAction) -> Result<(), String> {
+    match action {
+        UserAction::Add { username, role } => {
+            let password = read_new_password(username)?;
+            store.add_user(username, &password, *role)?;
+            println!("Added {} '{}' to {}", role, username, store.path().display());
+        }
+        UserAction::Remove { username } => {
+            store.remove_user(username)?;
+            println!("Removed user '{}' from {}", username, store.path().display());
+        }
+        UserAction::Passwd { username } => {
+            let password = read_new_password(username)?;
+            store.set_password(username, &password)?;
+            println!("Changed password of '{}'", username);
+        }
+        UserAction::Role { username, role } => {
+            store.set_role(username, *role)?;
+            println!("'{}' is now {}", username, role);
+        }
+        UserAction::List => {
+            for (username, role) in store.users() {
+                println!("{}\t{}", username, role);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_plain_usernames() {
+        assert!(validate_username("alice").is_ok());
+        assert!(validate_username("alice.smith@example.com").is_ok());
+        assert!(validate_username("zoë").is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_usernames() {
+        assert!(validate_username("").is_err());
+    }
+
+    #[test]
+    fn rejects_colons_and_control_characters_in_usernames() {
+        assert!(validate_username("alice:admin").is_err());
+        assert!(validate_username("alice\n").is_err());
+        assert!(validate_username("al\tice").is_err());
+    }
+
+    #[test]
+    fn passwords_need_eight_characters() {
+        assert!(validate_password("").is_err());
+        assert!(validate_password("1234567").is_err());
+        assert!(validate_password("12345678").is_ok());
+        assert!(validate_password("correct horse battery staple").is_ok());
+    }
+
+    #[test]
+    fn password_length_counts_characters_not_bytes() {
+        // Seven characters, fourteen bytes
+        assert!(validate_password("äöüäöüä").is_err());
+        assert!(validate_password("äöüäöüäö").is_ok());
+    }
+
+    #[test]
+    fn passwords_may_contain_colons() {
+        assert!(validate_password("pass:word").is_ok());
+    }
+}
@@ -0,0 +1,799 @@
+use crate::auth::permissions::Role;
+use crate::processor::audio_source::{AudioFormat, AudioSourceConfig};
+use crate::processor::capture_settings;
+use crate::processor::frame_source::SourceConfig;
+use axum::http::HeaderValue;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Parser)]
+#[command(name = "monitor-system", version, about = "Camera and microphone monitoring service")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to a TOML configuration file
+    #[arg(short, long, env = "MONITOR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Port to listen on
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// File holding users and their password hashes
+    #[arg(long, env = "MONITOR_USERS_FILE")]
+    pub users_file: Option<PathBuf>,
+
+    /// Secret used to sign session tokens
+    #[arg(long, env = "MONITOR_SESSION_SECRET", hide_env_values = true)]
+    pub session_secret: Option<String>,
+
+    /// Frame source for a camera, e.g. `0=v4l2`, `1=file:/srv/lobby.mp4`, `2=test:640x480@15`
+    #[arg(long = "camera-source", env = "CAMERA_SOURCES", value_delimiter = ';')]
+    pub camera_sources: Vec<String>,
+
+    /// Audio input: `cpal`, `wav:<path>`, `tone[:<hz>]` or `noise`
+    #[arg(long, env = "AUDIO_SOURCE")]
+    pub audio_source: Option<String>,
+
+    /// Allowed CORS origin, may be repeated. `*` allows any origin
+    #[arg(long = "cors-origin")]
+    pub cors_origins: Vec<String>,
+
+    /// Serve HTTPS and WSS
+    #[arg(long)]
+    pub tls: bool,
+
+    /// PEM certificate chain, implies --tls
+    #[arg(long)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key, implies --tls
+    #[arg(long)]
+    pub tls_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage the users allowed to log in
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+    },
+    /// Print the JSON Schema of the /ws messages
+    Schema,
+}
+
+/// Passwords are prompted for on a terminal, or read from the first line
+/// of stdin when it is not one.
+#[derive(Debug, Subcommand)]
+pub enum UserAction {
+    /// Add a user
+    Add {
+        username: String,
+        #[arg(long, value_enum, default_value_t = Role::Viewer)]
+        role: Role,
+    },
+    /// Remove a user
+    Remove { username: String },
+    /// Change the password of a user
+    Passwd { username: String },
+    /// Change the role of a user
+    Role {
+        username: String,
+        #[arg(value_enum)]
+        role: Role,
+    },
+    /// List users and their roles
+    List,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub auth: AuthConfig,
+    pub cameras: Vec<CameraConfig>,
+    pub audio: AudioConfig,
+    pub cors: CorsConfig,
+    pub tls: TlsConfig,
+    pub storage: StorageConfig,
+    pub recording: RecordingConfig,
+    pub clips: ClipConfig,
+    pub h264: H264Config,
+    pub hls: HlsConfig,
+    pub webrtc: WebRtcConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8081,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub users_file: PathBuf,
+    /// Random per start when unset. Never sent back by `/admin/config`
+    #[serde(skip_serializing)]
+    pub session_secret: Option<String>,
+    pub session_ttl_secs: u64,
+    pub lockout: LockoutConfig,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            users_file: PathBuf::from("users.toml"),
+            session_secret: None,
+            session_ttl_secs: 3600,
+            lockout: LockoutConfig::default(),
+        }
+    }
+}
+
+/// Failed login handling, counted separately per client IP and per username.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    /// Failures allowed before backoff starts
+    pub free_attempts: u32,
+    /// Longest delay between attempts while backing off
+    pub max_backoff_secs: u64,
+    /// Failures that lock the IP or username out
+    pub threshold: u32,
+    pub duration_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            max_backoff_secs: 60,
+            threshold: 10,
+            duration_secs: 900,
+        }
+    }
+}
+
+/// Capture settings for one camera index. Unset values keep the
+/// defaults of the pipeline that opens the camera.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraConfig {
+    pub index: i32,
+    #[serde(default = "default_camera_source")]
+    pub source: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub fps: Option<f64>,
+    pub quality: Option<i32>,
+    /// Pixel format requested from the device, e.g. MJPG or YUYV
+    pub fourcc: Option<String>,
+    pub motion: Option<MotionConfig>,
+}
+
+/// Whether one directory is the other or inside it. Relative paths are
+/// taken from the working directory, symlinks are not followed.
+fn dirs_overlap(a: &Path, b: &Path) -> bool {
+    match (std::path::absolute(a), std::path::absolute(b)) {
+        (Ok(a), Ok(b)) => a.starts_with(&b) || b.starts_with(&a),
+        _ => false,
+    }
+}
+
+fn default_camera_source() -> String {
+    "v4l2".to_string()
+}
+
+impl CameraConfig {
+    pub fn new(index: i32) -> Self {
+        Self {
+            index,
+            source: default_camera_source(),
+            width: None,
+            height: None,
+            fps: None,
+            quality: None,
+            fourcc: None,
+            motion: None,
+        }
+    }
+
+    pub fn source_config(&self) -> SourceConfig {
+        self.source.parse().unwrap_or_default()
+    }
+}
+
+/// Motion detection of one camera. While it is enabled the camera keeps
+/// capturing without viewers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotionConfig {
+    pub enabled: bool,
+    /// 1 to 100, higher reacts to smaller changes in brightness
+    pub sensitivity: u32,
+    /// Smallest moving region that counts, in percent of the frame
+    pub min_area: f64,
+    /// Stillness before motion is reported as ended
+    pub end_delay_secs: u64,
+    /// Polygons with corners as `[x, y]` fractions of the frame, e.g.
+    /// `[[0.0, 0.5], [1.0, 0.5], [1.0, 1.0], [0.0, 1.0]]` for the lower
+    /// half. Empty watches the whole frame.
+    pub zones: Vec<Vec<[f64; 2]>>,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sensitivity: 50,
+            min_area: 0.5,
+            end_delay_secs: 5,
+            zones: Vec::new(),
+        }
+    }
+}
+
+impl MotionConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if !(1..=100).contains(&self.sensitivity) {
+            errors.push("motion.sensitivity must be between 1 and 100".to_string());
+        }
+        if !(self.min_area > 0.0 && self.min_area <= 100.0) {
+            errors.push("motion.min_area must be above 0 and at most 100 percent".to_string());
+        }
+        if !(1..=600).contains(&self.end_delay_secs) {
+            errors.push("motion.end_delay_secs must be between 1 and 600".to_string());
+        }
+        for (i, zone) in self.zones.iter().enumerate() {
+            if zone.len() < 3 {
+                errors.push(format!("motion.zones[{}] needs at least 3 corners", i));
+            }
+            if zone.iter().flatten().any(|c| !(0.0..=1.0).contains(c)) {
+                errors.push(format!("motion.zones[{}] corners must be fractions between 0 and 1", i));
+            }
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub source: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub buffer_size: usize,
+    pub latency_ms: u64,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        let format = AudioFormat::default();
+        Self {
+            source: "cpal".to_string(),
+            sample_rate: format.sample_rate,
+            channels: format.channels,
+            buffer_size: format.buffer_size,
+            latency_ms: format.latency_ms,
+        }
+    }
+}
+
+impl AudioConfig {
+    pub fn format(&self) -> AudioFormat {
+        AudioFormat {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            buffer_size: self.buffer_size,
+            latency_ms: self.latency_ms,
+        }
+    }
+
+    pub fn source_config(&self) -> AudioSourceConfig {
+        self.source.parse().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            origins: vec!["*".to_string()],
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn allows_any(&self) -> bool {
+        self.origins.iter().any(|origin| origin == "*")
+    }
+}
+
+/// HTTPS for the whole server. A self-signed pair is written to the
+/// configured paths when both files are missing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub self_signed: bool,
+    /// How often the files are checked for changes
+    pub reload_interval_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: PathBuf::from("tls/cert.pem"),
+            key_path: PathBuf::from("tls/key.pem"),
+            self_signed: true,
+            reload_interval_secs: 30,
+        }
+    }
+}
+
+/// Files the service writes at runtime.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Image controls changed through the API, reapplied on every start
+    pub controls_file: PathBuf,
+    /// Motion and other events, one JSON object per line
+    pub events_file: PathBuf,
+    /// SQLite catalog of recorded segments and clips
+    pub catalog_file: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            controls_file: PathBuf::from("camera_controls.toml"),
+            events_file: PathBuf::from("events.jsonl"),
+            catalog_file: PathBuf::from("catalog.db"),
+        }
+    }
+}
+
+/// Continuous recording, independent of viewers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    pub enabled: bool,
+    /// Camera indexes to record, every `[[cameras]]` entry when empty
+    pub cameras: Vec<i32>,
+    pub dir: PathBuf,
+    pub segment_secs: u64,
+    /// Oldest segments are deleted above this total, 0 for no limit
+    pub max_total_mb: u64,
+    /// Segments older than this are deleted, 0 to keep them
+    pub max_age_hours: u64,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cameras: Vec::new(),
+            dir: PathBuf::from("recordings"),
+            segment_secs: 300,
+            max_total_mb: 10 * 1024,
+            max_age_hours: 7 * 24,
+        }
+    }
+}
+
+/// Clips saved around motion, loud sounds and manual triggers. They are
+/// never deleted by the service, unlike recording segments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClipConfig {
+    pub enabled: bool,
+    /// Camera indexes to buffer, every `[[cameras]]` entry when empty
+    pub cameras: Vec<i32>,
+    pub dir: PathBuf,
+    /// Seconds before the trigger kept in memory and saved with the clip
+    pub pre_roll_secs: u64,
+    /// Seconds saved after the last trigger or the end of motion
+    pub post_roll_secs: u64,
+    pub max_clip_secs: u64,
+    /// Microphone level in dBFS that triggers a clip on every camera, off when unset
+    pub audio_threshold_db: Option<f64>,
+}
+
+impl Default for ClipConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cameras: Vec::new(),
+            dir: PathBuf::from("clips"),
+            pre_roll_secs: 5,
+            post_roll_secs: 10,
+            max_clip_secs: 60,
+            audio_threshold_db: None,
+        }
+    }
+}
+
+/// Software H.264 for `/ws` viewers that ask for it, one encoder per
+/// camera shared by its viewers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct H264Config {
+    pub enabled: bool,
+    pub bitrate_kbps: u32,
+    /// Frames between keyframes; new viewers get one right away
+    pub gop_frames: u32,
+}
+
+impl Default for H264Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bitrate_kbps: 500,
+            gop_frames: 60,
+        }
+    }
+}
+
+/// HLS playlists of live cameras and recordings. Uses the `[h264]`
+/// encoder settings whether or not `/ws` viewers may ask for H.264.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HlsConfig {
+    pub enabled: bool,
+    /// Segments and playlists, emptied of a camera once nobody fetches it
+    pub dir: PathBuf,
+    /// Segments are cut at the first keyframe after this long
+    pub segment_secs: u64,
+    /// Segments listed in a live playlist
+    pub playlist_segments: usize,
+    /// A camera's packager stops when nothing was fetched for this long
+    pub idle_timeout_secs: u64,
+    /// Transcoded recordings kept, least recently transcoded go first
+    pub vod_cache_recordings: usize,
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: PathBuf::from("hls"),
+            segment_secs: 2,
+            playlist_segments: 5,
+            idle_timeout_secs: 30,
+            vod_cache_recordings: 10,
+        }
+    }
+}
+
+/// The server as a WebRTC peer for `/ws` clients, with host candidates
+/// only. Video uses the `[h264]` encoder settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebRtcConfig {
+    pub enabled: bool,
+    /// UDP ports for media, e.g. to open in a firewall; 0 for any
+    pub udp_port_min: u16,
+    pub udp_port_max: u16,
+}
+
+impl Config {
+    /// Reads the file named on the command line, applies the flag
+    /// overrides and validates the result. On failure every problem
+    /// found is returned, not just the first one.
+    pub fn load(cli: &Cli) -> Result<Config, Vec<String>> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .map_err(|e| vec![format!("Failed to read {}: {}", path.display(), e)])?;
+                toml::from_str::<Config>(&content)
+                    .map_err(|e| vec![format!("Failed to parse {}: {}", path.display(), e)])?
+            }
+            None => Config::default(),
+        };
+
+        let mut errors = config.apply_cli(cli);
+        errors.extend(config.validate());
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Some(host) = &cli.host {
+            self.server.host = host.clone();
+        }
+        if let Some(port) = cli.port {
+            self.server.port = port;
+        }
+        if let Some(users_file) = &cli.users_file {
+            self.auth.users_file = users_file.clone();
+        }
+        if let Some(secret) = &cli.session_secret {
+            self.auth.session_secret = Some(secret.clone());
+        }
+        if let Some(source) = &cli.audio_source {
+            self.audio.source = source.clone();
+        }
+        if !cli.cors_origins.is_empty() {
+            self.cors.origins = cli.cors_origins.clone();
+        }
+        if cli.tls {
+            self.tls.enabled = true;
+        }
+        if let Some(cert) = &cli.tls_cert {
+            self.tls.enabled = true;
+            self.tls.cert_path = cert.clone();
+        }
+        if let Some(key) = &cli.tls_key {
+            self.tls.enabled = true;
+            self.tls.key_path = key.clone();
+        }
+
+        for entry in cli.camera_sources.iter().map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let parsed = entry.split_once('=')
+                .and_then(|(index, source)| Some((index.trim().parse::<i32>().ok()?, source.trim())));
+            match parsed {
+                Some((index, source)) => match self.cameras.iter_mut().find(|c| c.index == index) {
+                    Some(camera) => camera.source = source.to_string(),
+                    None => {
+                        let mut camera = CameraConfig::new(index);
+                        camera.source = source.to_string();
+                        self.cameras.push(camera);
+                    }
+                },
+                None => errors.push(format!("camera source '{}': expected <index>=<source>", entry)),
+            }
+        }
+
+        errors
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if self.server.host.trim().is_empty() {
+            errors.push("server.host must not be empty".to_string());
+        }
+        if self.server.port == 0 {
+            errors.push("server.port must be between 1 and 65535".to_string());
+        }
+
+        if self.auth.users_file.as_os_str().is_empty() {
+            errors.push("auth.users_file must not be empty".to_string());
+        }
+        if self.auth.session_secret.as_ref().is_some_and(|secret| secret.len() < 32) {
+            errors.push("auth.session_secret must be at least 32 characters".to_string());
+        }
+        if !(60..=7 * 24 * 3600).contains(&self.auth.session_ttl_secs) {
+            errors.push("auth.session_ttl_secs must be between 60 and 604800".to_string());
+        }
+        let lockout = &self.auth.lockout;
+        if lockout.threshold == 0 || lockout.free_attempts >= lockout.threshold {
+            errors.push("auth.lockout.threshold must be above auth.lockout.free_attempts".to_string());
+        }
+        if lockout.max_backoff_secs == 0 || lockout.duration_secs == 0 {
+            errors.push("auth.lockout.max_backoff_secs and duration_secs must be positive".to_string());
+        }
+
+        let mut seen = HashSet::new();
+        for camera in &self.cameras {
+            let name = format!("cameras[index={}]", camera.index);
+            if camera.index < 0 {
+                errors.push(format!("{}: index must not be negative", name));
+            }
+            if !seen.insert(camera.index) {
+                errors.push(format!("{}: index is configured more than once", name));
+            }
+            if let Err(e) = camera.source.parse::<SourceConfig>() {
+                errors.push(format!("{}.source: {}", name, e));
+            }
+            if camera.width.is_some() != camera.height.is_some() {
+                errors.push(format!("{}: width and height must be set together", name));
+            }
+            let capture_errors = capture_settings::validate(
+                camera.width,
+                camera.height,
+                camera.fps,
+                camera.quality,
+                camera.fourcc.as_deref(),
+            );
+            for error in capture_errors {
+                errors.push(format!("{}: {}", name, error));
+            }
+            for error in camera.motion.iter().flat_map(|motion| motion.validate()) {
+                errors.push(format!("{}.{}", name, error));
+            }
+        }
+
+        match self.audio.source.parse::<AudioSourceConfig>() {
+            Ok(AudioSourceConfig::Tone { frequency }) if frequency >= self.audio.sample_rate as f32 / 2.0 => {
+                errors.push(format!(
+                    "audio.source: tone frequency must be below {} Hz at sample rate {}",
+                    self.audio.sample_rate / 2,
+                    self.audio.sample_rate
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(format!("audio.source: {}", e)),
+        }
+        if !(8000..=192000).contains(&self.audio.sample_rate) {
+            errors.push("audio.sample_rate must be between 8000 and 192000".to_string());
+        }
+        if !(1..=2).contains(&self.audio.channels) {
+            errors.push("audio.channels must be 1 or 2".to_string());
+        }
+        let frame_bytes = self.audio.channels.max(1) as usize * 2;
+        if self.audio.buffer_size == 0 || self.audio.buffer_size % frame_bytes != 0 {
+            errors.push(format!("audio.buffer_size must be a positive multiple of {} bytes", frame_bytes));
+        }
+
+        if self.storage.controls_file.as_os_str().is_empty()
+            || self.storage.events_file.as_os_str().is_empty()
+            || self.storage.catalog_file.as_os_str().is_empty()
+        {
+            errors.push("storage.controls_file, storage.events_file and storage.catalog_file must not be empty".to_string());
+        }
+
+        if self.recording.enabled {
+            if self.recording.dir.as_os_str().is_empty() {
+                errors.push("recording.dir must not be empty".to_string());
+            }
+            if !(10..=24 * 3600).contains(&self.recording.segment_secs) {
+                errors.push("recording.segment_secs must be between 10 and 86400".to_string());
+            }
+            if self.recording_cameras().is_empty() {
+                errors.push("recording.cameras: no cameras to record, list them or add [[cameras]] entries".to_string());
+            }
+            if self.recording.cameras.iter().any(|&index| index < 0) {
+                errors.push("recording.cameras: indexes must not be negative".to_string());
+            }
+        }
+
+        if self.clips.enabled {
+            if self.clips.dir.as_os_str().is_empty() {
+                errors.push("clips.dir must not be empty".to_string());
+            }
+            if !(1..=60).contains(&self.clips.pre_roll_secs) || !(1..=600).contains(&self.clips.post_roll_secs) {
+                errors.push("clips.pre_roll_secs must be between 1 and 60, clips.post_roll_secs between 1 and 600".to_string());
+            }
+            if self.clips.max_clip_secs < self.clips.pre_roll_secs + self.clips.post_roll_secs || self.clips.max_clip_secs > 3600 {
+                errors.push("clips.max_clip_secs must cover pre-roll and post-roll and be at most 3600".to_string());
+            }
+            if self.clips.audio_threshold_db.is_some_and(|db| !(-90.0..=0.0).contains(&db)) {
+                errors.push("clips.audio_threshold_db must be between -90 and 0".to_string());
+            }
+            if self.clip_cameras().is_empty() {
+                errors.push("clips.cameras: no cameras to buffer, list them or add [[cameras]] entries".to_string());
+            }
+            if self.clips.cameras.iter().any(|&index| index < 0) {
+                errors.push("clips.cameras: indexes must not be negative".to_string());
+            }
+            // Retention would take clips for segments and delete them
+            if self.recording.enabled && dirs_overlap(&self.recording.dir, &self.clips.dir) {
+                errors.push("clips.dir and recording.dir must be separate, neither inside the other".to_string());
+            }
+        }
+
+        if self.h264.enabled || self.hls.enabled || self.webrtc.enabled {
+            if !(50..=20_000).contains(&self.h264.bitrate_kbps) {
+                errors.push("h264.bitrate_kbps must be between 50 and 20000".to_string());
+            }
+            if !(1..=600).contains(&self.h264.gop_frames) {
+                errors.push("h264.gop_frames must be between 1 and 600".to_string());
+            }
+        }
+
+        if self.hls.enabled {
+            if self.hls.dir.as_os_str().is_empty() {
+                errors.push("hls.dir must not be empty".to_string());
+            }
+            if !(1..=30).contains(&self.hls.segment_secs) {
+                errors.push("hls.segment_secs must be between 1 and 30".to_string());
+            }
+            if !(3..=100).contains(&self.hls.playlist_segments) {
+                errors.push("hls.playlist_segments must be between 3 and 100".to_string());
+            }
+            if !(5..=3600).contains(&self.hls.idle_timeout_secs) {
+                errors.push("hls.idle_timeout_secs must be between 5 and 3600".to_string());
+            }
+            if !(1..=1000).contains(&self.hls.vod_cache_recordings) {
+                errors.push("hls.vod_cache_recordings must be between 1 and 1000".to_string());
+            }
+        }
+
+        if self.webrtc.enabled {
+            let (min, max) = (self.webrtc.udp_port_min, self.webrtc.udp_port_max);
+            if (min == 0) != (max == 0) || min > max {
+                errors.push("webrtc.udp_port_min and webrtc.udp_port_max must both be 0 or form a range".to_string());
+            }
+        }
+
+        if self.tls.enabled {
+            if self.tls.cert_path.as_os_str().is_empty() || self.tls.key_path.as_os_str().is_empty() {
+                errors.push("tls.cert_path and tls.key_path must not be empty".to_string());
+            }
+            if self.tls.reload_interval_secs == 0 {
+                errors.push("tls.reload_interval_secs must be positive".to_string());
+            }
+        }
+
+        for origin in &self.cors.origins {
+            if origin == "*" {
+                continue;
+            }
+            if !(origin.starts_with("http://") || origin.starts_with("https://"))
+                || HeaderValue::from_str(origin).is_err()
+            {
+                errors.push(format!("cors.origins: '{}' is not a valid origin", origin));
+            }
+        }
+
+        errors
+    }
+
+    /// Indexes with motion detection turned on.
+    pub fn motion_cameras(&self) -> Vec<i32> {
+        self.cameras.iter()
+            .filter(|camera| camera.motion.as_ref().is_some_and(|motion| motion.enabled))
+            .map(|camera| camera.index)
+            .collect()
+    }
+
+    /// Indexes recorded when recording is enabled.
+    pub fn recording_cameras(&self) -> Vec<i32> {
+        if self.recording.cameras.is_empty() {
+            self.cameras.iter().map(|camera| camera.index).collect()
+        } else {
+            self.recording.cameras.clone()
+        }
+    }
+
+    /// Indexes clips are saved for when clips are enabled.
+    pub fn clip_cameras(&self) -> Vec<i32> {
+        if self.clips.cameras.is_empty() {
+            self.cameras.iter().map(|camera| camera.index).collect()
+        } else {
+            self.clips.cameras.clone()
+        }
+    }
+
+    pub fn camera(&self, index: i32) -> CameraConfig {
+        self.cameras.iter()
+            .find(|camera| camera.index == index)
+            .cloned()
+            .unwrap_or_else(|| CameraConfig::new(index))
+    }
+}
@@ -0,0 +1,159 @@
+use crate::auth::permissions::{Action, Role};
+use crate::auth::lockout::{BlockedKey, LockoutEvent};
+use crate::auth::user_store::UserStore;
+use crate::auth::AuthUser;
+use crate::config::Config;
+use crate::protocol::ErrorCode;
+use crate::r#trait::{AppState, ErrorMessage, UserInfo};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUser {
+    username: String,
+    password: String,
+    #[serde(default)]
+    role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateUser {
+    password: Option<String>,
+    role: Option<Role>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Lockouts {
+    blocked: Vec<BlockedKey>,
+    events: Vec<LockoutEvent>,
+}
+
+fn user_exists(state: &AppState, username: &str) -> bool {
+    state.user_store.users().iter().any(|(name, _)| name == username)
+}
+
+/// Runs a store write off the async workers, hashing a password is slow.
+async fn write_store<F>(state: &AppState, write: F) -> Result<(), ErrorMessage>
+where
+    F: FnOnce(&UserStore) -> Result<(), String> + Send + 'static,
+{
+    let store = state.user_store.clone();
+    tokio::task::spawn_blocking(move || write(&store))
+        .await
+        .unwrap_or_else(|e| Err(format!("User store task failed: {}", e)))
+        .map_err(|e| ErrorMessage::new(ErrorCode::BadRequest, e))
+}
+
+pub async fn list_users(
+    user: AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<UserInfo>>, ErrorMessage> {
+    user.check(Action::ManageUsers)?;
+    let users = state.user_store.users().into_iter()
+        .map(|(username, role)| UserInfo { username, role })
+        .collect();
+    Ok(Json(users))
+}
+
+pub async fn create_user(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Json(body): Json<CreateUser>,
+) -> Result<(StatusCode, Json<UserInfo>), ErrorMessage> {
+    user.check(Action::ManageUsers)?;
+    if user_exists(&state, &body.username) {
+        return Err(ErrorMessage::new(ErrorCode::Conflict, format!("User '{}' already exists", body.username)));
+    }
+
+    let info = UserInfo { username: body.username.clone(), role: body.role };
+    write_store(&state, move |store| store.add_user(&body.username, &body.password, body.role)).await?;
+    println!("{} added {} '{}'", user.username, info.role, info.username);
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+pub async fn update_user(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Json(body): Json<UpdateUser>,
+) -> Result<StatusCode, ErrorMessage> {
+    user.check(Action::ManageUsers)?;
+    if !user_exists(&state, &username) {
+        return Err(ErrorMessage::new(ErrorCode::NotFound, format!("User '{}' does not exist", username)));
+    }
+    // Keep at least the caller able to manage users
+    if username == user.username && body.role.is_some_and(|role| !role.allows(Action::ManageUsers)) {
+        return Err(ErrorMessage::new(ErrorCode::Conflict, "Admins cannot lower their own role"));
+    }
+
+    let ends_sessions = body.password.is_some() || body.role.is_some();
+    let target = username.clone();
+    write_store(&state, move |store| {
+        if let Some(password) = &body.password {
+            store.set_password(&target, password)?;
+        }
+        if let Some(role) = body.role {
+            store.set_role(&target, role)?;
+        }
+        Ok(())
+    }).await?;
+    // Live sockets keep the role they were opened with, so make the user log in again
+    if ends_sessions {
+        state.sessions.revoke_user(&username);
+    }
+    println!("{} updated user '{}'", user.username, username);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_user(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<StatusCode, ErrorMessage> {
+    user.check(Action::ManageUsers)?;
+    if !user_exists(&state, &username) {
+        return Err(ErrorMessage::new(ErrorCode::NotFound, format!("User '{}' does not exist", username)));
+    }
+    if username == user.username {
+        return Err(ErrorMessage::new(ErrorCode::Conflict, "Admins cannot remove themselves"));
+    }
+
+    let target = username.clone();
+    write_store(&state, move |store| store.remove_user(&target)).await?;
+    state.sessions.revoke_user(&username);
+    println!("{} removed user '{}'", user.username, username);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_config(
+    user: AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<Config>, ErrorMessage> {
+    user.check(Action::ManageConfig)?;
+    Ok(Json(state.config.as_ref().clone()))
+}
+
+/// IPs and usernames currently backing off or locked out, and the recent
+/// lockout events.
+pub async fn list_lockouts(
+    user: AuthUser,
+    State(state): State<AppState>,
+) -> Result<Json<Lockouts>, ErrorMessage> {
+    user.check(Action::ManageUsers)?;
+    Ok(Json(Lockouts {
+        blocked: state.login_guard.blocked(),
+        events: state.login_guard.events(),
+    }))
+}
+
+pub async fn clear_lockouts(
+    user: AuthUser,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ErrorMessage> {
+    user.check(Action::ManageUsers)?;
+    state.login_guard.clear();
+    println!("{} cleared all login lockouts", user.username);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,61 @@
+use crate::auth::permissions::Role;
+use crate::auth::verify_credentials;
+use crate::protocol::ErrorCode;
+use crate::r#trait::{AppState, ErrorMessage};
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginResponse {
+    token: String,
+    token_type: &'static str,
+    /// Unix time in seconds
+    expires_at: u64,
+    username: String,
+    role: Role,
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(body): Json<LoginRequest>,
+) -> Result<Json<LoginResponse>, ErrorMessage> {
+    let user = verify_credentials(&state, addr.ip(), body.username, body.password).await?;
+    let (token, claims) = state.sessions.issue(&user)
+        .map_err(|e| ErrorMessage::new(ErrorCode::Internal, e))?;
+    println!("Issued session token for {} ({})", user.username, user.role);
+
+    Ok(Json(LoginResponse {
+        token,
+        token_type: "Bearer",
+        expires_at: claims.exp,
+        username: user.username,
+        role: user.role,
+    }))
+}
+
+/// Revokes the session token sent as `Authorization: Bearer`. Sockets
+/// opened with it are closed.
+pub async fn logout(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ErrorMessage> {
+    let token = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| ErrorMessage::new(ErrorCode::Unauthorized, "A Bearer session token is required"))?;
+
+    let session = state.sessions.validate(token.trim(), &state.user_store)?;
+    state.sessions.revoke(&session.claims);
+    println!("Session of {} revoked by logout", session.user.username);
+    Ok(StatusCode::NO_CONTENT)
+}
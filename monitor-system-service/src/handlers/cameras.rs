@@ -0,0 +1,168 @@
+use crate::auth::permissions::Action;
+use crate::auth::{authenticate_request, AuthUser};
+use crate::processor::camera_registry::{CameraControls, CameraSettings, Subscription};
+use crate::processor::capabilities::{self, CameraCapabilities};
+use crate::processor::capture_settings::CaptureSettingsUpdate;
+use crate::processor::image_controls::ImageControls;
+use crate::processor::snapshot::{self, SnapshotOptions};
+use crate::protocol::ErrorCode;
+use crate::r#trait::{AppState, ErrorMessage};
+use crate::websocket::watch_action;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+/// Separates the JPEG parts of `/cameras/<index>/mjpeg`.
+const MJPEG_BOUNDARY: &str = "frame";
+
+/// Formats, frame sizes, frame rates and controls of the device behind
+/// `index`, read from the device on every call.
+pub async fn get_camera(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(index): Path<i32>,
+) -> Result<Json<CameraCapabilities>, ErrorMessage> {
+    user.check(Action::Watch)?;
+    if index < 0 {
+        return Err(ErrorMessage::new(ErrorCode::InvalidCamera, format!("{} is not a camera index", index)));
+    }
+    let source = state.config.camera(index).source_config();
+    tokio::task::spawn_blocking(move || capabilities::discover(index, &source))
+        .await
+        .unwrap_or_else(|e| Err(format!("Camera query failed: {}", e)))
+        .map(Json)
+        .map_err(|e| ErrorMessage::new(ErrorCode::NotFound, e))
+}
+
+pub async fn get_camera_settings(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(index): Path<i32>,
+) -> Result<Json<CameraSettings>, ErrorMessage> {
+    user.check(Action::Watch)?;
+    Ok(Json(state.cameras.settings(index)))
+}
+
+/// Answers once a running camera has switched, with what the device
+/// accepted under `applied`.
+pub async fn update_camera_settings(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(index): Path<i32>,
+    Json(body): Json<CaptureSettingsUpdate>,
+) -> Result<Json<CameraSettings>, ErrorMessage> {
+    user.check(Action::ConfigureCamera)?;
+    let settings = state.cameras.update_settings(index, &body).await?;
+    println!("{} changed camera {} settings to {:?}", user.username, index, settings.requested);
+    Ok(Json(settings))
+}
+
+pub async fn get_camera_controls(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(index): Path<i32>,
+) -> Result<Json<CameraControls>, ErrorMessage> {
+    user.check(Action::Watch)?;
+    Ok(Json(state.cameras.controls(index)))
+}
+
+/// Changes brightness, exposure, focus and the other image controls.
+/// Fields left out keep their value.
+pub async fn update_camera_controls(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(index): Path<i32>,
+    Json(body): Json<ImageControls>,
+) -> Result<Json<CameraControls>, ErrorMessage> {
+    user.check(Action::ConfigureCamera)?;
+    let controls = state.cameras.update_controls(index, &body).await?;
+    println!("{} changed camera {} controls to {:?}", user.username, index, controls.requested);
+    Ok(Json(controls))
+}
+
+/// One JPEG from the camera. A running capture is reused, an idle camera
+/// is opened for the frame and closed again, which takes the same role
+/// as starting it from a socket.
+pub async fn get_camera_snapshot(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(index): Path<i32>,
+    Query(options): Query<SnapshotOptions>,
+) -> Result<Response, ErrorMessage> {
+    if index < 0 {
+        return Err(ErrorMessage::new(ErrorCode::InvalidCamera, format!("{} is not a camera index", index)));
+    }
+    user.check(watch_action(&state.cameras, index))?;
+    options.validate().map_err(|e| ErrorMessage::new(ErrorCode::BadRequest, e))?;
+
+    let frame = state.cameras.snapshot(index).await?;
+    let jpeg = tokio::task::spawn_blocking(move || snapshot::render(&frame.jpeg, &options).map_err(|e| e.to_string()))
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()))
+        .map_err(|e| ErrorMessage::new(ErrorCode::Internal, format!("Cannot encode snapshot: {}", e)))?;
+    Ok(([(header::CONTENT_TYPE, "image/jpeg"), (header::CACHE_CONTROL, "no-store")], jpeg).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MjpegQuery {
+    /// Session token for clients that cannot set headers, e.g. `<img>`
+    token: Option<String>,
+}
+
+/// The camera as `multipart/x-mixed-replace` JPEG parts for `<img>` tags,
+/// `ffmpeg` and NVRs. The response is a viewer of the camera's capture
+/// like a socket, and ends when the camera stops or the session ends.
+pub async fn get_camera_mjpeg(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(index): Path<i32>,
+    Query(query): Query<MjpegQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ErrorMessage> {
+    let (session, user) = authenticate_request(&headers, query.token.as_deref(), &state, addr.ip()).await?;
+    if index < 0 {
+        return Err(ErrorMessage::new(ErrorCode::InvalidCamera, format!("{} is not a camera index", index)));
+    }
+    user.check(watch_action(&state.cameras, index))?;
+
+    let viewer = format!("mjpeg-{}", uuid::Uuid::new_v4());
+    println!("{} started MJPEG stream of camera {} as {}", user.username, index, viewer);
+    let subscription = state.cameras.subscription(index, viewer);
+    let session_end = state.sessions.clone().ended(state.user_store.clone(), session);
+    let parts = futures::stream::unfold(subscription, next_part).take_until(session_end);
+
+    let content_type = format!("multipart/x-mixed-replace; boundary={}", MJPEG_BOUNDARY);
+    Ok((
+        [(header::CONTENT_TYPE, content_type), (header::CACHE_CONTROL, "no-store".to_string())],
+        Body::from_stream(parts),
+    )
+        .into_response())
+}
+
+/// The next frame as one multipart part. Slow clients skip frames.
+async fn next_part(mut subscription: Subscription) -> Option<(Result<Vec<u8>, Infallible>, Subscription)> {
+    loop {
+        match subscription.frames.recv().await {
+            Ok(frame) => {
+                let mut part = format!(
+                    "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                    MJPEG_BOUNDARY,
+                    frame.jpeg.len()
+                )
+                .into_bytes();
+                part.extend_from_slice(&frame.jpeg);
+                part.extend_from_slice(b"\r\n");
+                return Some((Ok(part), subscription));
+            }
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
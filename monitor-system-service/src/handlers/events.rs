@@ -0,0 +1,29 @@
+use crate::auth::permissions::Action;
+use crate::auth::AuthUser;
+use crate::processor::event_log::Event;
+use crate::r#trait::{AppState, ErrorMessage};
+use axum::extract::{Query, State};
+use axum::Json;
+use serde::Deserialize;
+
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct EventQuery {
+    camera: Option<i32>,
+    /// Unix milliseconds
+    since_ms: Option<u64>,
+    limit: Option<usize>,
+}
+
+/// Recent motion and other camera events, newest first. Older ones are
+/// in the events file.
+pub async fn list_events(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Query(query): Query<EventQuery>,
+) -> Result<Json<Vec<Event>>, ErrorMessage> {
+    user.check(Action::Watch)?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    Ok(Json(state.events.recent(query.camera, query.since_ms, limit)))
+}
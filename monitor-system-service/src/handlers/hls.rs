@@ -0,0 +1,103 @@
+use crate::auth::permissions::Action;
+use crate::auth::authenticate_request;
+use crate::handlers::recordings::find_recording;
+use crate::processor::hls::{LIVE_PLAYLIST, VOD_PLAYLIST};
+use crate::protocol::ErrorCode;
+use crate::r#trait::{AppState, ErrorMessage};
+use crate::websocket::watch_action;
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+const PLAYLIST_TYPE: &str = "application/vnd.apple.mpegurl";
+const SEGMENT_TYPE: &str = "video/mp2t";
+
+/// Players that cannot send headers pass the session token as `?token=`;
+/// playlists then carry it on every segment URI.
+#[derive(Debug, Default, Deserialize)]
+pub struct HlsQuery {
+    pub token: Option<String>,
+}
+
+/// `live.m3u8` or one of its segments. The first playlist request starts
+/// packaging the camera, opening it if needed.
+pub async fn get_live_hls(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((index, file)): Path<(i32, String)>,
+    Query(query): Query<HlsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ErrorMessage> {
+    let (_, user) = authenticate_request(&headers, query.token.as_deref(), &state, addr.ip()).await?;
+    if !state.hls.enabled() {
+        return Err(ErrorMessage::new(ErrorCode::NotFound, "HLS is disabled"));
+    }
+    if index < 0 {
+        return Err(ErrorMessage::new(ErrorCode::InvalidCamera, format!("{} is not a camera index", index)));
+    }
+
+    if file == LIVE_PLAYLIST {
+        user.check(watch_action(&state.cameras, index))?;
+        let playlist = state.hls.live_playlist(index).await?;
+        Ok(playlist_response(playlist, query.token.as_deref()))
+    } else {
+        user.check(Action::Watch)?;
+        let segment = state.hls.live_segment(index, &file).await?;
+        Ok(segment_response(segment, "no-store"))
+    }
+}
+
+/// `index.m3u8` of a recording or one of its segments. The recording is
+/// transcoded on the first playlist request, which can take a while for
+/// long segments.
+pub async fn get_recording_hls(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((id, file)): Path<(i64, String)>,
+    Query(query): Query<HlsQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ErrorMessage> {
+    let (_, user) = authenticate_request(&headers, query.token.as_deref(), &state, addr.ip()).await?;
+    if !state.hls.enabled() {
+        return Err(ErrorMessage::new(ErrorCode::NotFound, "HLS is disabled"));
+    }
+    user.check(Action::Watch)?;
+    let recording = find_recording(&state, id)?;
+
+    if file == VOD_PLAYLIST {
+        let playlist = state.hls.vod_playlist(&recording).await?;
+        Ok(playlist_response(playlist, query.token.as_deref()))
+    } else {
+        let segment = state.hls.vod_segment(recording.id, &file).await?;
+        Ok(segment_response(segment, "private, max-age=3600"))
+    }
+}
+
+fn playlist_response(playlist: String, token: Option<&str>) -> Response {
+    let playlist = match token {
+        Some(token) => with_token(&playlist, token),
+        None => playlist,
+    };
+    ([(header::CONTENT_TYPE, PLAYLIST_TYPE), (header::CACHE_CONTROL, "no-store")], playlist).into_response()
+}
+
+fn segment_response(segment: Vec<u8>, cache_control: &'static str) -> Response {
+    ([(header::CONTENT_TYPE, SEGMENT_TYPE), (header::CACHE_CONTROL, cache_control)], segment).into_response()
+}
+
+/// Appends the token to every segment URI, the lines that are not tags.
+/// Session tokens are URL-safe as they are.
+fn with_token(playlist: &str, token: &str) -> String {
+    playlist
+        .lines()
+        .map(|line| {
+            if line.is_empty() || line.starts_with('#') {
+                format!("{}\n", line)
+            } else {
+                format!("{}?token={}\n", line, token)
+            }
+        })
+        .collect()
+}
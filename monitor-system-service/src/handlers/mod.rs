@@ -1,3 +1,7 @@
+pub mod admin;
+pub mod auth;
+pub mod cameras;
+pub mod events;
+pub mod hls;
+pub mod recordings;
 pub mod system_info;
-
-pub mod camera;
\ No newline at end of file
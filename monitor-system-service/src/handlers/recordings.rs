@@ -0,0 +1,114 @@
+use crate::auth::permissions::Action;
+use crate::auth::AuthUser;
+use crate::processor::catalog::{Recording, RecordingFilter, RecordingKind};
+use crate::protocol::ErrorCode;
+use crate::r#trait::{AppState, ErrorMessage};
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use tokio_util::io::ReaderStream;
+
+/// Looks up a catalog entry for the REST handlers and the playback socket.
+pub fn find_recording(state: &AppState, id: i64) -> Result<Recording, ErrorMessage> {
+    state.catalog.get(id)
+        .map_err(|e| ErrorMessage::new(ErrorCode::Internal, e))?
+        .ok_or_else(|| ErrorMessage::new(ErrorCode::NotFound, format!("Recording {} does not exist", id)))
+}
+
+/// Segments and clips, newest first. `from_ms` and `to_ms` keep the ones
+/// that overlap the range.
+pub async fn list_recordings(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Query(filter): Query<RecordingFilter>,
+) -> Result<Json<Vec<Recording>>, ErrorMessage> {
+    user.check(Action::Watch)?;
+    if let (Some(from_ms), Some(to_ms)) = (filter.from_ms, filter.to_ms) {
+        if from_ms > to_ms {
+            return Err(ErrorMessage::new(ErrorCode::BadRequest, "from_ms must not be after to_ms"));
+        }
+    }
+    state.catalog.list(&filter)
+        .map(Json)
+        .map_err(|e| ErrorMessage::new(ErrorCode::Internal, e))
+}
+
+pub async fn get_recording(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Recording>, ErrorMessage> {
+    user.check(Action::Watch)?;
+    find_recording(&state, id).map(Json)
+}
+
+/// Streams the AVI file as a download.
+pub async fn download_recording(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Response, ErrorMessage> {
+    user.check(Action::Watch)?;
+    let recording = find_recording(&state, id)?;
+    let file = tokio::fs::File::open(&recording.path).await
+        .map_err(|e| ErrorMessage::new(ErrorCode::NotFound, format!("Cannot read recording {}: {}", id, e)))?;
+    let length = file.metadata().await.map(|metadata| metadata.len()).ok();
+
+    let kind = match recording.kind {
+        RecordingKind::Segment => "segment",
+        RecordingKind::Clip => "clip",
+    };
+    let name = std::path::Path::new(&recording.path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| format!("{}.avi", id));
+    let disposition = format!("attachment; filename=\"camera-{}-{}-{}\"", recording.camera, kind, name);
+
+    let mut response = (
+        [(header::CONTENT_TYPE, "video/x-msvideo".to_string()), (header::CONTENT_DISPOSITION, disposition)],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response();
+    if let Some(length) = length {
+        response.headers_mut().insert(header::CONTENT_LENGTH, length.into());
+    }
+    Ok(response)
+}
+
+pub async fn get_recording_thumbnail(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Response, ErrorMessage> {
+    user.check(Action::Watch)?;
+    let recording = find_recording(&state, id)?;
+    let not_found = || ErrorMessage::new(ErrorCode::NotFound, format!("Recording {} has no thumbnail", id));
+    let path = recording.thumbnail.ok_or_else(not_found)?;
+    let jpeg = tokio::fs::read(&path).await.map_err(|_| not_found())?;
+    Ok(([(header::CONTENT_TYPE, "image/jpeg")], jpeg).into_response())
+}
+
+/// Deletes the file, its thumbnail, its HLS copy and the catalog entry.
+pub async fn delete_recording(
+    user: AuthUser,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ErrorMessage> {
+    user.check(Action::DeleteRecordings)?;
+    let recording = find_recording(&state, id)?;
+    for path in std::iter::once(&recording.path).chain(recording.thumbnail.iter()) {
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(ErrorMessage::new(ErrorCode::Internal, format!("Cannot delete {}: {}", path, e)));
+            }
+        }
+    }
+    state.catalog.remove(id).map_err(|e| ErrorMessage::new(ErrorCode::Internal, e))?;
+    state.hls.forget_recording(id).await;
+    println!("{} deleted recording {} ({})", user.username, id, recording.path);
+    Ok(StatusCode::NO_CONTENT)
+}
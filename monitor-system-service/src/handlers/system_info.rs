@@ -1,57 +1,33 @@
 use crate::r#trait::{AppState, CameraStatus, EyeInfo, SystemInfo};
 use axum::extract::State;
 use axum::Json;
-use opencv::videoio;
-use opencv::videoio::{VideoCaptureTrait, VideoCaptureTraitConst};
 use std::collections::HashSet;
 
+/// Lists cameras from the inventory, devices are never opened here.
 pub async fn get_system_info(
     State(state): State<AppState>,
 ) -> Json<SystemInfo> {
-    let io = match state.os_type.as_str() {
-        "Linux" => videoio::CAP_V4L2,
-        "Windows" => videoio::CAP_WINRT,
-        "Darwin" => videoio::CAP_AVFOUNDATION,
-        _ => videoio::CAP_ANY,
-    };
+    let active: HashSet<i32> = state.cameras.active().iter().map(|camera| camera.index).collect();
 
-    let mut cameras = vec![];
-    let current_camera = state.current_camera_index.lock().await.clone();
-    let mut checked_ports = HashSet::new();
-
-    // First, add the currently used camera if any
-    if let Some(index) = current_camera {
-        cameras.push(EyeInfo {
-            index,
-            name: format!("Camera {} (in use)", index),
-            status: CameraStatus::InUse,
-        });
-        checked_ports.insert(index);
-    }
-
-    // Then check other available cameras
-    for i in 0..10 {
-        if checked_ports.contains(&i) {
-            continue;
-        }
-
-        // Create a temporary capture to check availability
-        match videoio::VideoCapture::new(i, io) {
-            Ok(mut cap) => {
-                if cap.is_opened().unwrap_or(false) {
-                    cameras.push(EyeInfo {
-                        index: i,
-                        name: format!("Camera {}", i),
-                        status: CameraStatus::Available,
-                    });
-                    // Make sure to release the capture immediately
-                    let _ = cap.release();
-                }
-            },
-            Err(_) => {
-                // Skip unavailable cameras
-                continue;
+    let mut cameras: Vec<EyeInfo> = state.inventory.list().into_iter()
+        .map(|device| {
+            let in_use = active.contains(&device.index);
+            EyeInfo {
+                index: device.index,
+                name: if in_use { format!("{} (in use)", device.name) } else { device.name },
+                status: if in_use { CameraStatus::InUse } else { CameraStatus::Available },
             }
+        })
+        .collect();
+
+    // File and test pattern sources have no device
+    for index in active {
+        if !cameras.iter().any(|camera| camera.index == index) {
+            cameras.push(EyeInfo {
+                index,
+                name: format!("Camera {} (in use)", index),
+                status: CameraStatus::InUse,
+            });
         }
     }
 
@@ -1,42 +1,133 @@
-use crate::handlers::camera::{handle_video_socket, Users};
+use crate::handlers::admin::{clear_lockouts, create_user, delete_user, get_config, list_lockouts, list_users, update_user};
+use crate::handlers::auth::{login, logout};
+use crate::handlers::cameras::{
+    get_camera, get_camera_controls, get_camera_mjpeg, get_camera_settings, get_camera_snapshot, update_camera_controls,
+    update_camera_settings,
+};
+use crate::handlers::events::list_events;
+use crate::handlers::hls::{get_live_hls, get_recording_hls};
+use crate::handlers::recordings::{
+    delete_recording, download_recording, find_recording, get_recording, get_recording_thumbnail, list_recordings,
+};
 use crate::handlers::system_info::get_system_info;
 use axum::{
-    extract::{State, WebSocketUpgrade},
-    response::IntoResponse,
-    routing::get
-    , Router,
+    extract::{ConnectInfo, Path, Query, State, WebSocketUpgrade},
+    response::{IntoResponse, Response},
+    routing::{get, post, put}
+    , Json, Router,
 };
+use axum::http::{HeaderMap, HeaderValue};
+use schemars::schema::RootSchema;
+use serde::Deserialize;
+use clap::Parser;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::{Mutex as TokioMutex, RwLock};
-use tower_http::cors::{Any, CorsLayer};
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod auth;
+mod config;
 mod r#trait;
 mod websocket;
 mod handlers;
 mod processor;
+mod protocol;
+mod storage;
+mod tls;
 
-use crate::r#trait::{AppState, EyesState, VideoState};
-use crate::websocket::handle_audio_socket;
+use crate::auth::authenticate_request;
+use crate::auth::lockout::LoginGuard;
+use crate::auth::permissions::Action;
+use crate::auth::session::SessionManager;
+use crate::auth::user_store::{run_user_command, UserStore};
+use crate::config::{Cli, Command, Config};
+use crate::processor::audio_source::{create_audio_source, AudioSource};
+use crate::processor::camera_inventory::CameraInventory;
+use crate::processor::catalog::Catalog;
+use crate::processor::clips::ClipRecorder;
+use crate::processor::loudness;
+use crate::processor::microphone::Microphone;
+use crate::processor::event_log::EventLog;
+use crate::processor::h264::H264Encoders;
+use crate::processor::hls::HlsPackager;
+use crate::processor::image_controls::ControlsStore;
+use crate::processor::recorder::Recorder;
+use crate::processor::webrtc_peer::WebRtcPeers;
+use crate::processor::camera_registry::CameraRegistry;
+use crate::r#trait::{AppState, Users};
+use crate::websocket::{handle_audio_socket, handle_playback_socket, handle_video_socket};
 
 
 async fn healthcheck() -> &'static str {
     "Up"
 }
 
+/// JSON Schema of the `/ws` messages, public so clients can generate bindings.
+async fn protocol_schema() -> Json<RootSchema> {
+    Json(protocol::schema())
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Sockets opened with a session token (`?token=` or `Authorization: Bearer`)
+/// are authenticated before the upgrade. Without one the first text frame
+/// must carry Basic credentials.
 async fn video_websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_video_socket(socket, state))
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let session = match state.sessions.from_request(&headers, query.token.as_deref(), &state.user_store) {
+        Ok(session) => session,
+        Err(e) => return e.into_response(),
+    };
+    ws.on_upgrade(move |socket| handle_video_socket(socket, state, session, addr.ip()))
 }
 
 async fn audio_websocket_handler(
     ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    ws.on_upgrade(handle_audio_socket)
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let session = match state.sessions.from_request(&headers, query.token.as_deref(), &state.user_store) {
+        Ok(session) => session,
+        Err(e) => return e.into_response(),
+    };
+    ws.on_upgrade(move |socket| handle_audio_socket(socket, state, session, addr.ip()))
+}
+
+/// Playback of a catalog entry. Browsers pass a session token as
+/// `?token=`, other clients may also send `Authorization: Basic`.
+async fn playback_websocket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<i64>,
+    Query(query): Query<TokenQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (session, user) = match authenticate_request(&headers, query.token.as_deref(), &state, addr.ip()).await {
+        Ok(authenticated) => authenticated,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = user.check(Action::Watch) {
+        return e.into_response();
+    }
+    let recording = match find_recording(&state, id) {
+        Ok(recording) => recording,
+        Err(e) => return e.into_response(),
+    };
+    ws.on_upgrade(move |socket| handle_playback_socket(socket, state, session, user, recording))
 }
 
 #[tokio::main]
@@ -48,37 +139,181 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let cli = Cli::parse();
+    if let Some(Command::Schema) = &cli.command {
+        println!("{}", serde_json::to_string_pretty(&protocol::schema()).unwrap());
+        return;
+    }
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("Invalid configuration:");
+            for error in errors {
+                eprintln!("  - {}", error);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    let user_store = match UserStore::open(&config.auth.users_file) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(Command::User { action }) = &cli.command {
+        if let Err(e) = run_user_command(&user_store, action) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if user_store.is_empty() {
+        println!(
+            "No users in {}, nobody can log in. Add one with `monitor-system user add <username>`",
+            config.auth.users_file.display()
+        );
+    }
+
     let users: Users = Arc::new(RwLock::new(HashMap::new()));
 
     let os_type = sys_info::os_type().unwrap();
-    let eyes = EyesState {
-        eyes_io: Arc::new(TokioMutex::new(None)),
-        status: Arc::new(TokioMutex::new(false)),
+    let audio_source: Arc<dyn AudioSource> = Arc::from(create_audio_source(&config.audio.source_config(), config.audio.format()));
+    println!("Audio source: {}", audio_source.name());
+    let microphone = Arc::new(Microphone::new(audio_source, config.audio.format()));
+    let config = Arc::new(config);
+    let controls = match ControlsStore::open(&config.storage.controls_file) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Camera controls are stored in {}", controls.path().display());
+    let events = Arc::new(EventLog::new(&config.storage.events_file));
+    println!("Events are logged to {}", events.path().display());
+    let catalog = match Catalog::open(&config.storage.catalog_file) {
+        Ok(catalog) => Arc::new(catalog),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("Recordings are cataloged in {}", catalog.path().display());
+    let cameras = Arc::new(CameraRegistry::new(config.clone(), os_type.clone(), controls, events.clone()));
+    let inventory = Arc::new(CameraInventory::new());
+    inventory.clone().watch(cameras.clone());
+    for index in config.motion_cameras() {
+        println!("Watching camera {} for motion", index);
+        cameras.hold(index, "motion");
+    }
+    if config.clips.enabled {
+        Arc::new(ClipRecorder::new(config.clips.clone(), cameras.clone(), events.clone(), catalog.clone())).start(config.clip_cameras());
+        if let Some(threshold_db) = config.clips.audio_threshold_db {
+            loudness::watch(microphone.clone(), threshold_db, events.clone());
+        }
+    }
+    if config.recording.enabled {
+        Arc::new(Recorder::new(config.recording.clone(), cameras.clone(), catalog.clone())).start(config.recording_cameras());
+    }
+    let h264 = Arc::new(H264Encoders::new(config.h264.clone(), cameras.clone()));
+    let hls = Arc::new(HlsPackager::new(config.hls.clone(), config.h264.clone(), h264.clone()));
+    let webrtc = match WebRtcPeers::new(config.webrtc.clone(), h264.clone(), microphone.clone()) {
+        Ok(webrtc) => Arc::new(webrtc),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     };
-
     let state = AppState {
-        eyes: Arc::new(eyes),
-        current_camera_index: Arc::new(TokioMutex::new(None)),
+        cameras,
+        inventory,
+        events,
+        catalog,
+        h264,
+        hls,
+        webrtc,
         os_type,
-        video_state: Arc::new(VideoState::new()),
-        user_sate: users.clone()
+        signal_peers: users.clone(),
+        config: config.clone(),
+        user_store: Arc::new(user_store),
+        sessions: Arc::new(SessionManager::new(
+            config.auth.session_secret.as_deref(),
+            Duration::from_secs(config.auth.session_ttl_secs),
+        )),
+        login_guard: Arc::new(LoginGuard::new(config.auth.lockout.clone())),
+        microphone,
     };
 
+    let allow_origin = if config.cors.allows_any() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(config.cors.origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))
+    };
     let cors = CorsLayer::new()
-        .allow_origin(Any)
+        .allow_origin(allow_origin)
         .allow_methods(Any)
         .allow_headers(Any);
 
     let app = Router::new()
         .route("/healthz", get(healthcheck))
+        .route("/auth/login", post(login))
+        .route("/auth/logout", post(logout))
         .route("/ws", get(video_websocket_handler))
+        .route("/ws/schema", get(protocol_schema))
         .route("/sensors/ears/ws", get(audio_websocket_handler))
         .route("/system", get(get_system_info))
+        .route("/cameras/:index", get(get_camera))
+        .route("/cameras/:index/settings", get(get_camera_settings).put(update_camera_settings))
+        .route("/cameras/:index/controls", get(get_camera_controls).put(update_camera_controls))
+        .route("/cameras/:index/snapshot", get(get_camera_snapshot))
+        .route("/cameras/:index/mjpeg", get(get_camera_mjpeg))
+        .route("/cameras/:index/hls/:file", get(get_live_hls))
+        .route("/events", get(list_events))
+        .route("/recordings", get(list_recordings))
+        .route("/recordings/:id", get(get_recording).delete(delete_recording))
+        .route("/recordings/:id/file", get(download_recording))
+        .route("/recordings/:id/thumbnail", get(get_recording_thumbnail))
+        .route("/recordings/:id/play", get(playback_websocket_handler))
+        .route("/recordings/:id/hls/:file", get(get_recording_hls))
+        .route("/admin/users", get(list_users).post(create_user))
+        .route("/admin/users/:username", put(update_user).delete(delete_user))
+        .route("/admin/config", get(get_config))
+        .route("/admin/lockouts", get(list_lockouts).delete(clear_lockouts))
         .layer(cors)
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8081").await.unwrap();
-    println!("Server running at http://0.0.0.0:8081");
-    axum::serve(listener, app).await.unwrap();
+    let listener = match tokio::net::TcpListener::bind((config.server.host.as_str(), config.server.port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind {}:{}: {}", config.server.host, config.server.port, e);
+            std::process::exit(1);
+        }
+    };
+
+    if !config.tls.enabled {
+        println!("Server running at http://{}:{}", config.server.host, config.server.port);
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+        return;
+    }
+
+    let rustls_config = match tls::load(&config.tls, &config.server.host).await {
+        Ok(rustls_config) => rustls_config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    tls::watch(rustls_config.clone(), config.tls.clone());
+
+    let listener = listener.into_std().unwrap();
+    println!("Server running at https://{}:{}", config.server.host, config.server.port);
+    axum_server::from_tcp_rustls(listener, rustls_config)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
 }
 
@@ -0,0 +1,365 @@
+use crate::r#trait::AudioStreamHandle;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// PCM layout delivered to ears clients, always 16-bit little-endian.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub buffer_size: usize,
+    pub latency_ms: u64,
+}
+
+impl Default for AudioFormat {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            channels: 2,
+            buffer_size: 2048, // Smaller chunks for lower latency
+            latency_ms: 30,
+        }
+    }
+}
+
+/// Which input feeds the ears pipeline.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum AudioSourceConfig {
+    #[default]
+    Cpal,
+    WavFile { path: String },
+    Tone { frequency: f32 },
+    Noise,
+}
+
+impl FromStr for AudioSourceConfig {
+    type Err = String;
+
+    /// Accepts `cpal`, `wav:<path>`, `tone[:<hz>]` and `noise`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind.trim(), Some(arg.trim())),
+            None => (s.trim(), None),
+        };
+
+        match (kind, arg) {
+            ("cpal", None) => Ok(AudioSourceConfig::Cpal),
+            ("wav", Some(path)) if !path.is_empty() => Ok(AudioSourceConfig::WavFile { path: path.to_string() }),
+            ("tone", None) => Ok(AudioSourceConfig::Tone { frequency: 440.0 }),
+            ("tone", Some(frequency)) => match frequency.parse::<f32>() {
+                Ok(frequency) if frequency > 0.0 => {
+                    Ok(AudioSourceConfig::Tone { frequency })
+                }
+                _ => Err(format!("Invalid tone frequency in '{}'", s)),
+            },
+            ("noise", None) => Ok(AudioSourceConfig::Noise),
+            _ => Err(format!("Unknown audio source '{}', expected cpal, wav:<path>, tone[:<hz>] or noise", s)),
+        }
+    }
+}
+
+/// Produces interleaved PCM in the configured `AudioFormat`, in chunks of
+/// about `buffer_size` bytes.
+pub trait AudioSource: Send + Sync {
+    fn name(&self) -> String;
+    fn start(&self, audio_sender: crossbeam_channel::Sender<Vec<u8>>) -> Result<AudioStreamHandle, String>;
+}
+
+pub fn create_audio_source(config: &AudioSourceConfig, format: AudioFormat) -> Box<dyn AudioSource> {
+    match config {
+        AudioSourceConfig::Cpal => Box::new(CpalSource { format }),
+        AudioSourceConfig::WavFile { path } => Box::new(WavFileSource { path: path.clone(), format }),
+        AudioSourceConfig::Tone { frequency } => Box::new(GeneratorSource { waveform: Waveform::Sine(*frequency), format }),
+        AudioSourceConfig::Noise => Box::new(GeneratorSource { waveform: Waveform::Noise, format }),
+    }
+}
+
+/// Default input device of the default cpal host.
+pub struct CpalSource {
+    format: AudioFormat,
+}
+
+impl AudioSource for CpalSource {
+    fn name(&self) -> String {
+        "cpal".to_string()
+    }
+
+    fn start(&self, audio_sender: crossbeam_channel::Sender<Vec<u8>>) -> Result<AudioStreamHandle, String> {
+        let host = cpal::default_host();
+
+        // Get default input device
+        let device = host.default_input_device()
+            .ok_or_else(|| "No input device available".to_string())?;
+
+        println!("[AUDIO] Using device: {}", device.name().unwrap_or_default());
+
+        // Use explicit config
+        let config = cpal::StreamConfig {
+            channels: self.format.channels,
+            sample_rate: cpal::SampleRate(self.format.sample_rate),
+            buffer_size: cpal::BufferSize::Fixed(self.format.buffer_size as u32),
+        };
+        let buffer_size = self.format.buffer_size;
+
+        println!("[AUDIO] Stream config: {:?}", config);
+
+        let stop_signal = Arc::new(Mutex::new(false));
+        let stop_signal_clone = stop_signal.clone();
+
+        // Ring buffer for audio processing
+        let ring_buffer = Arc::new(Mutex::new(Vec::with_capacity(buffer_size * 2)));
+        let ring_buffer_clone = ring_buffer.clone();
+
+        let stream = device.build_input_stream(
+            &config,
+            move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                if *stop_signal_clone.lock().unwrap() {
+                    return;
+                }
+
+                let mut buffer = ring_buffer_clone.lock().unwrap();
+
+                // Convert samples with noise gate and normalization
+                let mut max_amplitude = 0.0f32;
+                let mut has_audio = false;
+
+                let audio_data: Vec<u8> = data.iter()
+                    .map(|&sample| {
+                        // Update max amplitude
+                        max_amplitude = max_amplitude.max(sample.abs());
+
+                        // Apply noise gate
+                        let gated = if sample.abs() < 0.01 { 0.0 } else { sample };
+                        if gated != 0.0 {
+                            has_audio = true;
+                        }
+
+                        // Convert to i16
+                        let normalized = if max_amplitude > 1.0 {
+                            gated / max_amplitude
+                        } else {
+                            gated
+                        };
+
+                        let scaled = (normalized * 32767.0) as i16;
+                        scaled.to_le_bytes()
+                    })
+                    .flatten()
+                    .collect();
+
+                // Only send if we have actual audio
+                if has_audio {
+                    buffer.extend(audio_data);
+
+                    // Send when we have enough data
+                    if buffer.len() >= buffer_size {
+                        let data_to_send = buffer.split_off(0);
+                        if let Err(e) = audio_sender.try_send(data_to_send) {
+                            eprintln!("[AUDIO] Send error: {:?}", e);
+                        }
+                    }
+                }
+            },
+            move |err| eprintln!("[AUDIO] Stream error: {:?}", err),
+            Some(Duration::from_millis(self.format.latency_ms)),
+        ).map_err(|e| format!("Failed to build input stream: {:?}", e))?;
+
+        stream.play().map_err(|e| format!("Failed to start stream: {:?}", e))?;
+        println!("[AUDIO] Stream started successfully");
+
+        Ok(AudioStreamHandle {
+            stream: Some(Arc::new(stream)),
+            stop_signal,
+        })
+    }
+}
+
+/// Plays a WAV file in a loop at real-time speed.
+pub struct WavFileSource {
+    path: String,
+    format: AudioFormat,
+}
+
+impl WavFileSource {
+    fn load(&self) -> Result<Vec<i16>, String> {
+        let mut reader = hound::WavReader::open(&self.path)
+            .map_err(|e| format!("Failed to open WAV file {}: {}", self.path, e))?;
+        let spec = reader.spec();
+
+        let samples: Vec<i16> = match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Int, 16) => reader.samples::<i16>().collect::<Result<_, _>>(),
+            (hound::SampleFormat::Int, bits) => reader.samples::<i32>()
+                .map(|s| s.map(|s| if bits > 16 { (s >> (bits - 16)) as i16 } else { (s << (16 - bits)) as i16 }))
+                .collect::<Result<_, _>>(),
+            (hound::SampleFormat::Float, _) => reader.samples::<f32>()
+                .map(|s| s.map(|s| (s.clamp(-1.0, 1.0) * 32767.0) as i16))
+                .collect::<Result<_, _>>(),
+        }.map_err(|e| format!("Failed to read WAV file {}: {}", self.path, e))?;
+
+        if spec.channels == 0 || samples.is_empty() {
+            return Err(format!("WAV file {} contains no audio", self.path));
+        }
+
+        println!(
+            "[AUDIO] Loaded {} ({} Hz, {} channels, {} bits)",
+            self.path, spec.sample_rate, spec.channels, spec.bits_per_sample
+        );
+
+        Ok(convert_format(&samples, spec.channels, spec.sample_rate, &self.format))
+    }
+}
+
+/// Remaps channels and linearly resamples to the output format.
+fn convert_format(samples: &[i16], channels: u16, sample_rate: u32, format: &AudioFormat) -> Vec<i16> {
+    let channels = channels as usize;
+    let out_channels = format.channels as usize;
+    let frames: Vec<Vec<f32>> = samples.chunks_exact(channels)
+        .map(|frame| {
+            (0..out_channels)
+                .map(|c| {
+                    if channels == 1 {
+                        frame[0] as f32
+                    } else if out_channels == 1 {
+                        frame.iter().map(|&s| s as f32).sum::<f32>() / channels as f32
+                    } else {
+                        frame[c.min(channels - 1)] as f32
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    if frames.is_empty() {
+        return Vec::new();
+    }
+
+    let ratio = sample_rate as f64 / format.sample_rate as f64;
+    let out_frames = ((frames.len() as f64) / ratio) as usize;
+    let mut out = Vec::with_capacity(out_frames * out_channels);
+    for i in 0..out_frames {
+        let pos = i as f64 * ratio;
+        let idx = pos as usize;
+        let frac = (pos - idx as f64) as f32;
+        let next = (idx + 1).min(frames.len() - 1);
+        for c in 0..out_channels {
+            let value = frames[idx][c] + (frames[next][c] - frames[idx][c]) * frac;
+            out.push(value as i16);
+        }
+    }
+    out
+}
+
+impl AudioSource for WavFileSource {
+    fn name(&self) -> String {
+        format!("wav:{}", self.path)
+    }
+
+    fn start(&self, audio_sender: crossbeam_channel::Sender<Vec<u8>>) -> Result<AudioStreamHandle, String> {
+        let samples = self.load()?;
+        let mut position = 0;
+
+        Ok(spawn_generator(self.format, audio_sender, move |chunk| {
+            for sample in chunk.iter_mut() {
+                *sample = samples[position];
+                position = (position + 1) % samples.len();
+            }
+        }))
+    }
+}
+
+enum Waveform {
+    Sine(f32),
+    Noise,
+}
+
+/// Synthetic tone or white noise, for machines without a sound card.
+pub struct GeneratorSource {
+    waveform: Waveform,
+    format: AudioFormat,
+}
+
+impl AudioSource for GeneratorSource {
+    fn name(&self) -> String {
+        match self.waveform {
+            Waveform::Sine(frequency) => format!("tone:{}", frequency),
+            Waveform::Noise => "noise".to_string(),
+        }
+    }
+
+    fn start(&self, audio_sender: crossbeam_channel::Sender<Vec<u8>>) -> Result<AudioStreamHandle, String> {
+        let channels = self.format.channels as usize;
+        let handle = match self.waveform {
+            Waveform::Sine(frequency) => {
+                let step = 2.0 * std::f32::consts::PI * frequency / self.format.sample_rate as f32;
+                let mut phase = 0.0f32;
+                spawn_generator(self.format, audio_sender, move |chunk| {
+                    for frame in chunk.chunks_mut(channels) {
+                        let value = (phase.sin() * 0.3 * 32767.0) as i16;
+                        frame.fill(value);
+                        phase = (phase + step) % (2.0 * std::f32::consts::PI);
+                    }
+                })
+            }
+            Waveform::Noise => {
+                // xorshift32, good enough for test noise
+                let mut state = 0x2545_f491u32;
+                spawn_generator(self.format, audio_sender, move |chunk| {
+                    for sample in chunk.iter_mut() {
+                        state ^= state << 13;
+                        state ^= state >> 17;
+                        state ^= state << 5;
+                        *sample = ((state >> 16) as i16) / 4;
+                    }
+                })
+            }
+        };
+        Ok(handle)
+    }
+}
+
+/// Runs `fill` on a worker thread, sending one chunk every
+/// `buffer_size` bytes worth of playback time until stopped.
+fn spawn_generator<F>(
+    format: AudioFormat,
+    audio_sender: crossbeam_channel::Sender<Vec<u8>>,
+    mut fill: F,
+) -> AudioStreamHandle
+where
+    F: FnMut(&mut [i16]) + Send + 'static,
+{
+    let stop_signal = Arc::new(Mutex::new(false));
+    let stop_signal_clone = stop_signal.clone();
+
+    std::thread::spawn(move || {
+        let samples_per_chunk = format.buffer_size / 2;
+        let chunk_duration = Duration::from_secs_f64(
+            samples_per_chunk as f64 / (format.sample_rate as f64 * format.channels as f64),
+        );
+        let mut chunk = vec![0i16; samples_per_chunk];
+        let mut next_chunk_at = Instant::now();
+
+        while !*stop_signal_clone.lock().unwrap() {
+            fill(&mut chunk);
+            let data: Vec<u8> = chunk.iter().flat_map(|s| s.to_le_bytes()).collect();
+            match audio_sender.try_send(data) {
+                Ok(()) | Err(crossbeam_channel::TrySendError::Full(_)) => {}
+                Err(crossbeam_channel::TrySendError::Disconnected(_)) => break,
+            }
+
+            next_chunk_at += chunk_duration;
+            let now = Instant::now();
+            if next_chunk_at > now {
+                std::thread::sleep(next_chunk_at - now);
+            }
+        }
+        println!("[AUDIO] Generator stopped");
+    });
+
+    println!("[AUDIO] Generator started");
+    AudioStreamHandle {
+        stream: None,
+        stop_signal,
+    }
+}
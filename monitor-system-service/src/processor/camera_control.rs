@@ -1,63 +1,141 @@
+use crate::config::CameraConfig;
+use crate::processor::camera_registry::{monotonic_us, VideoFrame};
+use crate::processor::capture_settings::CaptureSettings;
+use crate::processor::event_log::EventLog;
+use crate::processor::frame_source::{create_source, FrameSource};
+use crate::processor::image_controls::ImageControls;
+use crate::processor::motion::MotionDetector;
 use opencv::{
     prelude::*,
     videoio,
     core,
     Result,
 };
-use tokio::sync::{mpsc, watch};
-use std::time::Duration;
-use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
-
-#[derive(Debug)]
-pub enum CameraControl {
-    Start,
-    Stop,
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+
+/// Asks a running capture to switch settings. The reply carries what the
+/// source delivers afterwards, or why the previous settings were kept.
+pub struct SettingsRequest {
+    pub settings: CaptureSettings,
+    pub reply: oneshot::Sender<std::result::Result<CaptureSettings, String>>,
+}
+
+/// Asks a running capture to change image controls. The reply carries
+/// the values the device reports afterwards.
+pub struct ControlsRequest {
+    pub controls: ImageControls,
+    pub reply: oneshot::Sender<std::result::Result<ImageControls, String>>,
+}
+
+/// Channels the registry uses to steer a capture.
+pub struct CaptureControl {
+    pub stop_rx: watch::Receiver<bool>,
+    pub requests: mpsc::UnboundedReceiver<SettingsRequest>,
+    pub control_requests: mpsc::UnboundedReceiver<ControlsRequest>,
+    /// What the source delivers, updated on every (re)open
+    pub applied: watch::Sender<Option<CaptureSettings>>,
+    /// Image controls as the device reports them, updated like `applied`
+    pub applied_controls: watch::Sender<Option<ImageControls>>,
 }
 
+/// Capture loop of one camera. Frames are JPEG encoded once and shared
+/// with every subscriber through `frames`.
 pub struct CameraServer {
-    frame_sender: mpsc::UnboundedSender<String>,
-    stop_rx: watch::Receiver<bool>,
-    running: watch::Receiver<bool>,
+    frames: broadcast::Sender<Arc<VideoFrame>>,
+    control: CaptureControl,
+    camera: CameraConfig,
+    settings: CaptureSettings,
+    /// Applied on every open, so they survive reinitialization
+    controls: ImageControls,
+    events: Arc<EventLog>,
+    os_type: String,
+}
+
+/// `imencode` parameters for a JPEG of `quality`, 1 to 100.
+pub fn jpeg_params(quality: i32) -> core::Vector<i32> {
+    let mut params = core::Vector::new();
+    params.push(opencv::imgcodecs::IMWRITE_JPEG_QUALITY);
+    params.push(quality);
+    params
 }
 
 impl CameraServer {
     pub fn new(
-        frame_sender: mpsc::UnboundedSender<String>,
-        stop_rx: watch::Receiver<bool>,
-        running: watch::Receiver<bool>,
+        frames: broadcast::Sender<Arc<VideoFrame>>,
+        control: CaptureControl,
+        camera: CameraConfig,
+        settings: CaptureSettings,
+        controls: ImageControls,
+        events: Arc<EventLog>,
+        os_type: String,
     ) -> Self {
         Self {
-            frame_sender,
-            stop_rx,
-            running,
+            frames,
+            control,
+            camera,
+            settings,
+            controls,
+            events,
+            os_type,
         }
     }
 
-    fn configure_camera(cap: &mut videoio::VideoCapture) -> Result<()> {
-        let _ = cap.set(videoio::CAP_PROP_FOURCC,
-                        videoio::VideoWriter::fourcc('M', 'J', 'P', 'G')? as f64);
-
-        cap.set(videoio::CAP_PROP_FRAME_WIDTH, 320.0)?;
-        cap.set(videoio::CAP_PROP_FRAME_HEIGHT, 240.0)?;
-        cap.set(videoio::CAP_PROP_FPS, 15.0)?;
-        cap.set(videoio::CAP_PROP_BUFFERSIZE, 1.0)?;
-        let _ = cap.set(videoio::CAP_PROP_AUTO_EXPOSURE, 0.0);
-        let _ = cap.set(videoio::CAP_PROP_AUTOFOCUS, 0.0);
-
-        Ok(())
+    fn motion_detector(&self) -> Option<MotionDetector> {
+        let config = self.camera.motion.clone().filter(|motion| motion.enabled)?;
+        match MotionDetector::new(config) {
+            Ok(detector) => Some(detector),
+            Err(e) => {
+                eprintln!("Motion detection of camera {} is off: {}", self.camera.index, e);
+                None
+            }
+        }
     }
 
-    fn try_open_camera() -> Result<videoio::VideoCapture> {
-        let mut cap = videoio::VideoCapture::new(0, videoio::CAP_V4L2)?;
+    fn camera_properties(settings: &CaptureSettings, controls: &ImageControls) -> Result<Vec<(i32, f64)>> {
+        let mut properties = Vec::new();
+        if let Some([a, b, c, d]) = settings.fourcc_code() {
+            properties.push((videoio::CAP_PROP_FOURCC, videoio::VideoWriter::fourcc(a, b, c, d)? as f64));
+        }
+        properties.extend([
+            (videoio::CAP_PROP_FRAME_WIDTH, settings.width as f64),
+            (videoio::CAP_PROP_FRAME_HEIGHT, settings.height as f64),
+            (videoio::CAP_PROP_FPS, settings.fps),
+            (videoio::CAP_PROP_BUFFERSIZE, 1.0),
+        ]);
+        properties.extend(controls.properties());
+        Ok(properties)
+    }
 
-        if !cap.is_opened()? {
-            return Err(opencv::Error::new(
-                core::StsError,
-                "Failed to open camera".to_string(),
-            ));
+    /// What `cam` delivers with `settings` requested. Devices round to
+    /// the nearest mode they support, other sources keep their own size.
+    fn applied(cam: &dyn FrameSource, settings: &CaptureSettings) -> CaptureSettings {
+        let caps = cam.capabilities();
+        CaptureSettings {
+            width: caps.width,
+            height: caps.height,
+            fps: if caps.fps > 0.0 { caps.fps } else { settings.fps },
+            quality: settings.quality,
+            fourcc: caps.fourcc,
         }
+    }
 
-        Self::configure_camera(&mut cap)?;
+    /// Publishes what an (re)opened source delivers.
+    fn report_applied(&self, cam: &dyn FrameSource, settings: &CaptureSettings) {
+        let _ = self.control.applied.send(Some(Self::applied(cam, settings)));
+        let _ = self.control.applied_controls.send(Some(self.controls.read_back(|prop| cam.property(prop))));
+    }
+
+    fn try_open_camera(&self, settings: &CaptureSettings) -> Result<Box<dyn FrameSource>> {
+        let mut cap = create_source(
+            self.camera.index,
+            &self.camera.source_config(),
+            &self.os_type,
+            Self::camera_properties(settings, &self.controls)?,
+        );
+        cap.open()?;
+        println!("Camera opened: {:?}", cap.capabilities());
 
         let mut frame = core::Mat::default();
         for _ in 0..5 {
@@ -68,16 +146,83 @@ impl CameraServer {
         Ok(cap)
     }
 
-    pub async fn start_capture(&self) -> Result<()> {
-        let mut cam = Self::try_open_camera()?;
+    /// Switches to `request.settings`. Only the encoder changes for a new
+    /// quality, anything else reopens the source. When the source refuses
+    /// to open with the new settings the old ones are restored.
+    fn change_settings(
+        &self,
+        request: SettingsRequest,
+        cam: &mut Box<dyn FrameSource>,
+        settings: &mut CaptureSettings,
+    ) -> Result<()> {
+        if !request.settings.needs_reopen(settings) {
+            *settings = request.settings;
+            let applied = Self::applied(cam.as_ref(), settings);
+            let _ = self.control.applied.send(Some(applied.clone()));
+            let _ = request.reply.send(Ok(applied));
+            return Ok(());
+        }
+
+        println!("Reopening camera {} with {:?}", self.camera.index, request.settings);
+        cam.close();
+        match self.try_open_camera(&request.settings) {
+            Ok(new_cam) => {
+                *cam = new_cam;
+                *settings = request.settings;
+                self.report_applied(cam.as_ref(), settings);
+                let _ = request.reply.send(Ok(Self::applied(cam.as_ref(), settings)));
+                Ok(())
+            }
+            Err(e) => {
+                println!("Camera {} refused {:?}: {}, restoring previous settings", self.camera.index, request.settings, e);
+                let _ = request.reply.send(Err(format!("Camera {} refused the settings: {}", self.camera.index, e)));
+                *cam = self.try_open_camera(settings)?;
+                self.report_applied(cam.as_ref(), settings);
+                Ok(())
+            }
+        }
+    }
+
+    /// Sets the requested controls on the open device. They are kept for
+    /// later opens only when the device took all of them.
+    fn change_controls(&mut self, request: ControlsRequest, cam: &mut Box<dyn FrameSource>) {
+        let refused: Vec<String> = request.controls.values().into_iter()
+            .filter_map(|(control, value)| {
+                cam.set_property(control.prop, value).err().map(|e| format!("{}: {}", control.name, e))
+            })
+            .collect();
+
+        if refused.is_empty() {
+            self.controls = self.controls.merged(&request.controls);
+            let applied = self.controls.read_back(|prop| cam.property(prop));
+            let _ = self.control.applied_controls.send(Some(applied.clone()));
+            let _ = request.reply.send(Ok(applied));
+        } else {
+            println!("Camera {} refused controls: {}", self.camera.index, refused.join(", "));
+            let _ = request.reply.send(Err(format!("Camera {} refused {}", self.camera.index, refused.join(", "))));
+        }
+    }
+
+    /// Blocking, run it on its own thread. Returns when the stop signal is
+    /// set or the camera cannot be reopened.
+    pub fn run_capture(&mut self) -> Result<()> {
+        let mut settings = self.settings.clone();
+        let mut cam = self.try_open_camera(&settings)?;
+        self.report_applied(cam.as_ref(), &settings);
         let mut frame = core::Mat::default();
         let mut consecutive_failures = 0;
+        let mut seq = 0u64;
+        let mut motion = self.motion_detector();
         const MAX_FAILURES: i32 = 3;
 
-        while *self.running.borrow() {
-            if *self.stop_rx.borrow() {
-                println!("Camera capture stopping due to stop signal");
-                break;
+        while !*self.control.stop_rx.borrow() {
+            let started = Instant::now();
+
+            while let Ok(request) = self.control.requests.try_recv() {
+                self.change_settings(request, &mut cam, &mut settings)?;
+            }
+            while let Ok(request) = self.control.control_requests.try_recv() {
+                self.change_controls(request, &mut cam);
             }
 
             let read_result = cam.read(&mut frame);
@@ -89,36 +234,52 @@ impl CameraServer {
                     } else {
                         consecutive_failures = 0;
 
-                        let mut buffer = core::Vector::new();
-                        let mut params = core::Vector::new();
-                        params.push(opencv::imgcodecs::IMWRITE_JPEG_QUALITY);
-                        params.push(60);
-
-                        if let Ok(_) = opencv::imgcodecs::imencode(".jpg", &frame, &mut buffer, &params) {
-                            let frame_data = BASE64.encode(&buffer);
-                            if self.frame_sender.send(frame_data).is_err() {
-                                println!("Frame receiver disconnected");
-                                break;
+                        if let Some(detector) = motion.as_mut() {
+                            match detector.process(&frame) {
+                                Ok(Some(event)) => {
+                                    self.events.record(Some(self.camera.index), event);
+                                }
+                                Ok(None) => {}
+                                Err(e) => println!("Motion detection failed on camera {}: {}", self.camera.index, e),
                             }
                         }
+
+                        let mut buffer = core::Vector::new();
+                        if let Ok(true) = opencv::imgcodecs::imencode(".jpg", &frame, &mut buffer, &jpeg_params(settings.quality)) {
+                            seq += 1;
+                            let monotonic_us = monotonic_us();
+                            let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH)
+                                .map(|d| d.as_millis() as u64)
+                                .unwrap_or_default();
+                            // No subscribers is fine, the registry decides when to stop
+                            let _ = self.frames.send(Arc::new(VideoFrame {
+                                index: self.camera.index,
+                                seq,
+                                timestamp_ms,
+                                monotonic_us,
+                                jpeg: buffer.to_vec(),
+                            }));
+                        }
                     }
                 }
                 Ok(false) | Err(_) => {
-                    println!("Failed to read frame");
+                    println!("Failed to read frame from camera {}", self.camera.index);
                     consecutive_failures += 1;
                 }
             }
 
             if consecutive_failures >= MAX_FAILURES {
-                if *self.stop_rx.borrow() {
+                if *self.control.stop_rx.borrow() {
                     break;
                 }
 
-                println!("Too many consecutive failures, reinitializing camera...");
-                match Self::try_open_camera() {
+                println!("Too many consecutive failures, reinitializing camera {}...", self.camera.index);
+                match self.try_open_camera(&settings) {
                     Ok(new_cam) => {
+                        cam.close();
                         cam = new_cam;
                         consecutive_failures = 0;
+                        self.report_applied(cam.as_ref(), &settings);
                         println!("Camera reinitialized successfully");
                     }
                     Err(e) => {
@@ -128,10 +289,17 @@ impl CameraServer {
                 }
             }
 
-            tokio::time::sleep(Duration::from_millis(66)).await;
+            let frame_interval = Duration::from_secs_f64(1.0 / settings.fps);
+            if let Some(remaining) = frame_interval.checked_sub(started.elapsed()) {
+                std::thread::sleep(remaining);
+            }
         }
 
-        println!("Camera capture ended");
+        cam.close();
+        if let Some(event) = motion.as_mut().and_then(|detector| detector.finish()) {
+            self.events.record(Some(self.camera.index), event);
+        }
+        println!("Camera {} capture ended", self.camera.index);
         Ok(())
     }
-}
\ No newline at end of file
+}
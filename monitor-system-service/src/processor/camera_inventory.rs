@@ -0,0 +1,202 @@
+use crate::processor::camera_registry::CameraRegistry;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Inventory changes kept for clients that are slow to read them.
+const EVENT_BUFFER: usize = 16;
+
+/// A video device found without opening it.
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct CameraDevice {
+    pub index: i32,
+    pub path: String,
+    /// Card name from sysfs, e.g. "HD Pro Webcam C920"
+    pub name: String,
+    /// Where the device hangs off, e.g. the USB port "1-1:1.0"
+    pub bus: Option<String>,
+    pub driver: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum InventoryEvent {
+    Added(CameraDevice),
+    Removed(CameraDevice),
+}
+
+/// Cached list of the cameras attached to the machine, kept current by a
+/// watcher on `/dev` so requests never probe devices.
+pub struct CameraInventory {
+    devices: Mutex<BTreeMap<i32, CameraDevice>>,
+    events: broadcast::Sender<InventoryEvent>,
+}
+
+impl CameraInventory {
+    pub fn new() -> Self {
+        let devices = platform::scan().into_iter()
+            .map(|device| (device.index, device))
+            .collect::<BTreeMap<_, _>>();
+        println!("Found {} camera(s): {:?}", devices.len(), devices.keys().collect::<Vec<_>>());
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            devices: Mutex::new(devices),
+            events,
+        }
+    }
+
+    pub fn list(&self) -> Vec<CameraDevice> {
+        self.devices.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<InventoryEvent> {
+        self.events.subscribe()
+    }
+
+    /// Rescans and reports what changed since the last scan. Cameras that
+    /// went away are stopped so their viewers are told right away.
+    fn refresh(&self, cameras: &CameraRegistry) {
+        let current: BTreeMap<i32, CameraDevice> = platform::scan().into_iter()
+            .map(|device| (device.index, device))
+            .collect();
+        let previous = std::mem::replace(&mut *self.devices.lock().unwrap(), current.clone());
+
+        for (index, device) in &previous {
+            if current.get(index) != Some(device) {
+                println!("Camera {} ({}) was removed", index, device.name);
+                cameras.device_removed(*index);
+                let _ = self.events.send(InventoryEvent::Removed(device.clone()));
+            }
+        }
+        for (index, device) in &current {
+            if previous.get(index) != Some(device) {
+                println!("Camera {} ({}) was added", index, device.name);
+                let _ = self.events.send(InventoryEvent::Added(device.clone()));
+            }
+        }
+    }
+
+    /// Follows plug and unplug of video devices until the process exits.
+    pub fn watch(self: Arc<Self>, cameras: Arc<CameraRegistry>) {
+        platform::watch(self, cameras);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+    use futures::StreamExt;
+    use inotify::{Inotify, WatchMask};
+    use std::path::Path;
+    use std::time::Duration;
+
+    /// udev creates the node first and fixes its permissions and sysfs
+    /// links a moment later.
+    const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+    fn sysfs(index: i32, attribute: &str) -> Option<String> {
+        let value = std::fs::read_to_string(format!("/sys/class/video4linux/video{}/{}", index, attribute)).ok()?;
+        Some(value.trim().to_string())
+    }
+
+    /// Last component of a sysfs symlink, e.g. the bus id or driver name.
+    fn link_name(index: i32, link: &str) -> Option<String> {
+        let target = std::fs::read_link(format!("/sys/class/video4linux/video{}/{}", index, link)).ok()?;
+        Some(target.file_name()?.to_string_lossy().into_owned())
+    }
+
+    fn video_index(name: &str) -> Option<i32> {
+        name.strip_prefix("video")?.parse().ok()
+    }
+
+    /// Lists `/dev/video*` with their sysfs metadata. UVC cameras add a
+    /// second node for metadata; only the first node of each device, the
+    /// one that delivers frames, is kept.
+    pub fn scan() -> Vec<CameraDevice> {
+        let entries = match std::fs::read_dir("/dev") {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Cannot list /dev: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut devices: Vec<CameraDevice> = entries
+            .filter_map(|entry| video_index(&entry.ok()?.file_name().to_string_lossy()))
+            .filter(|&index| sysfs(index, "index").is_none_or(|node| node == "0"))
+            .map(|index| CameraDevice {
+                index,
+                path: format!("/dev/video{}", index),
+                name: sysfs(index, "name").unwrap_or_else(|| format!("Camera {}", index)),
+                bus: link_name(index, "device"),
+                driver: link_name(index, "device/driver"),
+            })
+            .collect();
+        devices.sort_by_key(|device| device.index);
+        devices
+    }
+
+    pub fn watch(inventory: Arc<CameraInventory>, cameras: Arc<CameraRegistry>) {
+        let inotify = Inotify::init().and_then(|inotify| {
+            inotify.watches().add(Path::new("/dev"), WatchMask::CREATE | WatchMask::DELETE | WatchMask::ATTRIB)?;
+            Ok(inotify)
+        });
+        let mut events = match inotify.and_then(|inotify| inotify.into_event_stream([0u8; 4096])) {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Cannot watch /dev for cameras, hot-plug is not detected: {}", e);
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let is_video = match &event {
+                    Ok(event) => event.name.as_ref()
+                        .is_some_and(|name| video_index(&name.to_string_lossy()).is_some()),
+                    Err(e) => {
+                        eprintln!("Camera watcher error: {}", e);
+                        false
+                    }
+                };
+                if is_video {
+                    tokio::time::sleep(SETTLE_DELAY).await;
+                    inventory.refresh(&cameras);
+                }
+            }
+        });
+    }
+}
+
+/// Without sysfs and inotify the cameras are probed once at startup.
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use super::*;
+    use crate::processor::frame_source::capture_api;
+    use opencv::videoio::{self, VideoCaptureTrait, VideoCaptureTraitConst};
+
+    pub fn scan() -> Vec<CameraDevice> {
+        let api = capture_api(&sys_info::os_type().unwrap_or_default());
+        (0..10)
+            .filter(|&index| {
+                videoio::VideoCapture::new(index, api).is_ok_and(|mut cap| {
+                    let opened = cap.is_opened().unwrap_or(false);
+                    let _ = cap.release();
+                    opened
+                })
+            })
+            .map(|index| CameraDevice {
+                index,
+                path: index.to_string(),
+                name: format!("Camera {}", index),
+                bus: None,
+                driver: None,
+            })
+            .collect()
+    }
+
+    pub fn watch(_inventory: Arc<CameraInventory>, _cameras: Arc<CameraRegistry>) {
+        println!("Camera hot-plug detection needs Linux, the camera list is fixed at startup");
+    }
+}
@@ -0,0 +1,463 @@
+use crate::config::Config;
+use crate::processor::camera_control::{CameraServer, CaptureControl, ControlsRequest, SettingsRequest};
+use crate::processor::capabilities;
+use crate::processor::capture_settings::{CaptureSettings, CaptureSettingsUpdate};
+use crate::processor::event_log::EventLog;
+use crate::processor::frame_source::SourceConfig;
+use crate::processor::image_controls::{ControlsStore, ImageControls};
+use crate::protocol::ErrorCode;
+use crate::r#trait::ErrorMessage;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, TryLockError};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+
+/// Frames buffered per camera before slow subscribers start to lag.
+const FRAME_BUFFER: usize = 8;
+
+/// Reopening a device with new settings includes a few warm-up frames.
+const SETTINGS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest wait for the frame of a snapshot, including opening the device.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait before a held camera whose capture ended is started again.
+const HOLD_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct VideoFrame {
+    pub index: i32,
+    /// Counts up from 1 for every capture run
+    pub seq: u64,
+    /// Capture time, Unix milliseconds
+    pub timestamp_ms: u64,
+    /// Capture time on `monotonic_us`, unaffected by clock changes
+    pub monotonic_us: u64,
+    pub jpeg: Vec<u8>,
+}
+
+/// Microseconds on a clock that never goes back, counted from the first
+/// frame the service captured. Shared by every camera, so their frames
+/// can be lined up.
+pub fn monotonic_us() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// Numbers runs from 1, so a capture, encoder or packager that ends can
+/// tell whether a newer run took its place.
+#[derive(Default)]
+pub struct RunIds(AtomicU64);
+
+impl RunIds {
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+pub type FrameReceiver = broadcast::Receiver<Arc<VideoFrame>>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CameraActivity {
+    pub index: i32,
+    pub viewers: usize,
+}
+
+/// A viewer that leaves its camera when dropped, for consumers that can
+/// go away at any point, like HTTP response bodies.
+pub struct Subscription {
+    pub frames: FrameReceiver,
+    registry: Arc<CameraRegistry>,
+    index: i32,
+    viewer: String,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.registry.unsubscribe(self.index, &self.viewer);
+    }
+}
+
+/// Reports the end of a capture run when dropped, so a capture thread
+/// that panics cannot leave its camera registered with no frames coming.
+struct CaptureEnded {
+    registry: Arc<CameraRegistry>,
+    index: i32,
+    run: u64,
+}
+
+impl Drop for CaptureEnded {
+    fn drop(&mut self) {
+        self.registry.capture_ended(self.index, self.run);
+    }
+}
+
+/// Settings of one camera: what was asked for and, while it captures,
+/// what the device actually delivers.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CameraSettings {
+    pub index: i32,
+    pub active: bool,
+    pub requested: CaptureSettings,
+    pub applied: Option<CaptureSettings>,
+}
+
+/// Image controls of one camera: the stored values and, while it
+/// captures, what the device reports for them.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CameraControls {
+    pub index: i32,
+    pub active: bool,
+    pub requested: ImageControls,
+    pub applied: Option<ImageControls>,
+}
+
+struct ActiveCamera {
+    /// Tells a finished capture apart from a newer one on the same index
+    run: u64,
+    frames: broadcast::Sender<Arc<VideoFrame>>,
+    viewers: HashSet<String>,
+    stop_tx: watch::Sender<bool>,
+    settings_tx: mpsc::UnboundedSender<SettingsRequest>,
+    controls_tx: mpsc::UnboundedSender<ControlsRequest>,
+    applied: watch::Receiver<Option<CaptureSettings>>,
+    applied_controls: watch::Receiver<Option<ImageControls>>,
+}
+
+/// Runs one capture thread per camera index that has viewers. Each index
+/// has its own frame channel and viewer set, and its capture stops when
+/// the last viewer leaves.
+pub struct CameraRegistry {
+    config: Arc<Config>,
+    os_type: String,
+    cameras: Mutex<HashMap<i32, ActiveCamera>>,
+    runs: RunIds,
+    /// Held by the capture thread of each index while it has the device
+    /// open, so a new run waits for a stopping one to let go of it
+    devices: Mutex<HashMap<i32, Arc<Mutex<()>>>>,
+    /// Settings changed at runtime, they outlive the capture until restart
+    requested: Mutex<HashMap<i32, CaptureSettings>>,
+    /// Image controls, they outlive restarts
+    controls: ControlsStore,
+    events: Arc<EventLog>,
+}
+
+impl CameraRegistry {
+    pub fn new(config: Arc<Config>, os_type: String, controls: ControlsStore, events: Arc<EventLog>) -> Self {
+        Self {
+            config,
+            os_type,
+            cameras: Mutex::new(HashMap::new()),
+            runs: RunIds::default(),
+            devices: Mutex::new(HashMap::new()),
+            requested: Mutex::new(HashMap::new()),
+            controls,
+            events,
+        }
+    }
+
+    /// Adds `viewer` to the camera, starting its capture when it is the
+    /// first one. The receiver closes when the capture ends on its own,
+    /// e.g. because the camera could not be opened.
+    pub fn subscribe(self: &Arc<Self>, index: i32, viewer: &str) -> FrameReceiver {
+        let mut cameras = self.cameras.lock().unwrap();
+        let camera = cameras.entry(index).or_insert_with(|| self.start(index));
+        camera.viewers.insert(viewer.to_string());
+        println!("Viewer {} joined camera {}, {} watching", viewer, index, camera.viewers.len());
+        camera.frames.subscribe()
+    }
+
+    /// Like `subscribe`, leaving the camera once the subscription is dropped.
+    pub fn subscription(self: &Arc<Self>, index: i32, viewer: String) -> Subscription {
+        Subscription {
+            frames: self.subscribe(index, &viewer),
+            registry: self.clone(),
+            index,
+            viewer,
+        }
+    }
+
+    /// Keeps a camera capturing without clients as `viewer`, e.g. for
+    /// motion detection. A capture that ends is started again after a
+    /// delay, so an unplugged camera resumes once it is back.
+    pub fn hold(self: &Arc<Self>, index: i32, viewer: &'static str) {
+        let registry = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut frames = registry.subscribe(index, viewer);
+                while !matches!(frames.recv().await, Err(broadcast::error::RecvError::Closed)) {}
+                println!("Capture of camera {} ended, {} starts it again in {} s", index, viewer, HOLD_RETRY_DELAY.as_secs());
+                tokio::time::sleep(HOLD_RETRY_DELAY).await;
+            }
+        });
+    }
+
+    /// The next frame of a camera. An idle camera is started for it and
+    /// stops again afterwards. Runs as a task of its own, so a client
+    /// that gives up does not leave the snapshot viewer behind.
+    pub async fn snapshot(self: &Arc<Self>, index: i32) -> Result<Arc<VideoFrame>, ErrorMessage> {
+        let registry = self.clone();
+        let task = tokio::spawn(async move {
+            let viewer = format!("snapshot-{}", uuid::Uuid::new_v4());
+            let mut frames = registry.subscribe(index, &viewer);
+            let frame = tokio::time::timeout(SNAPSHOT_TIMEOUT, async {
+                loop {
+                    match frames.recv().await {
+                        Ok(frame) => return Some(frame),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            })
+            .await;
+            registry.unsubscribe(index, &viewer);
+            match frame {
+                Ok(Some(frame)) => Ok(frame),
+                Ok(None) => Err(ErrorMessage::new(ErrorCode::CameraStopped, format!("Camera {} is not capturing", index))),
+                Err(_) => Err(ErrorMessage::new(ErrorCode::Internal, format!("Camera {} sent no frame in time", index))),
+            }
+        });
+        task.await
+            .unwrap_or_else(|e| Err(ErrorMessage::new(ErrorCode::Internal, format!("Snapshot failed: {}", e))))
+    }
+
+    pub fn unsubscribe(&self, index: i32, viewer: &str) {
+        let mut cameras = self.cameras.lock().unwrap();
+        let empty = match cameras.get_mut(&index) {
+            Some(camera) => {
+                camera.viewers.remove(viewer);
+                camera.viewers.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            if let Some(camera) = cameras.remove(&index) {
+                println!("No viewers left on camera {}, stopping capture", index);
+                let _ = camera.stop_tx.send(true);
+            }
+        }
+    }
+
+    pub fn unsubscribe_all(&self, viewer: &str) {
+        let indexes: Vec<i32> = self.cameras.lock().unwrap().keys().copied().collect();
+        for index in indexes {
+            self.unsubscribe(index, viewer);
+        }
+    }
+
+    /// Stops a device camera that was unplugged. Its viewers' receivers
+    /// close, which tells them the camera stopped.
+    pub fn device_removed(&self, index: i32) {
+        if self.config.camera(index).source_config() != SourceConfig::V4l2 {
+            return;
+        }
+        if let Some(camera) = self.cameras.lock().unwrap().remove(&index) {
+            println!("Camera {} was unplugged, stopping capture for {} viewer(s)", index, camera.viewers.len());
+            let _ = camera.stop_tx.send(true);
+        }
+    }
+
+    pub fn is_active(&self, index: i32) -> bool {
+        self.cameras.lock().unwrap().contains_key(&index)
+    }
+
+    pub fn active(&self) -> Vec<CameraActivity> {
+        let mut active: Vec<CameraActivity> = self.cameras.lock().unwrap().iter()
+            .map(|(&index, camera)| CameraActivity {
+                index,
+                viewers: camera.viewers.len(),
+            })
+            .collect();
+        active.sort_by_key(|camera| camera.index);
+        active
+    }
+
+    fn requested_settings(&self, index: i32) -> CaptureSettings {
+        self.requested.lock().unwrap().get(&index)
+            .cloned()
+            .unwrap_or_else(|| CaptureSettings::from_config(&self.config.camera(index)))
+    }
+
+    pub fn settings(&self, index: i32) -> CameraSettings {
+        let applied = self.cameras.lock().unwrap().get(&index)
+            .map(|camera| camera.applied.borrow().clone());
+        CameraSettings {
+            index,
+            active: applied.is_some(),
+            requested: self.requested_settings(index),
+            applied: applied.flatten(),
+        }
+    }
+
+    /// Changes the settings of a camera. A running capture switches right
+    /// away and the answer holds what the device accepted; a rejected
+    /// change keeps the previous settings. Idle cameras use the new
+    /// settings on their next start.
+    pub async fn update_settings(&self, index: i32, update: &CaptureSettingsUpdate) -> Result<CameraSettings, ErrorMessage> {
+        if index < 0 {
+            return Err(ErrorMessage::new(ErrorCode::InvalidCamera, format!("{} is not a camera index", index)));
+        }
+        let previous = self.requested_settings(index);
+        let settings = update.apply_to(&previous)
+            .map_err(|e| ErrorMessage::new(ErrorCode::BadRequest, e))?;
+        self.requested.lock().unwrap().insert(index, settings.clone());
+
+        let settings_tx = self.cameras.lock().unwrap().get(&index).map(|camera| camera.settings_tx.clone());
+        if let Some(settings_tx) = settings_tx {
+            let (reply, answer) = oneshot::channel();
+            if settings_tx.send(SettingsRequest { settings, reply }).is_ok() {
+                match tokio::time::timeout(SETTINGS_TIMEOUT, answer).await {
+                    Ok(Ok(Ok(_))) => {}
+                    Ok(Ok(Err(e))) => {
+                        self.requested.lock().unwrap().insert(index, previous);
+                        return Err(ErrorMessage::new(ErrorCode::SettingsRejected, e));
+                    }
+                    // The capture ended meanwhile, the settings apply on its next start
+                    Ok(Err(_)) => {}
+                    Err(_) => {
+                        return Err(ErrorMessage::new(
+                            ErrorCode::Internal,
+                            format!("Camera {} did not apply the settings in time", index),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(self.settings(index))
+    }
+
+    pub fn controls(&self, index: i32) -> CameraControls {
+        let applied = self.cameras.lock().unwrap().get(&index)
+            .map(|camera| camera.applied_controls.borrow().clone());
+        CameraControls {
+            index,
+            active: applied.is_some(),
+            requested: self.controls.get(index),
+            applied: applied.flatten(),
+        }
+    }
+
+    /// Changes image controls of a camera and stores them. Values are
+    /// checked against the ranges the device reports when it can be
+    /// queried. A running capture applies them right away, a change the
+    /// device refuses is not stored. An empty update only reports.
+    pub async fn update_controls(&self, index: i32, update: &ImageControls) -> Result<CameraControls, ErrorMessage> {
+        if index < 0 {
+            return Err(ErrorMessage::new(ErrorCode::InvalidCamera, format!("{} is not a camera index", index)));
+        }
+        if update.is_empty() {
+            return Ok(self.controls(index));
+        }
+        if let Some(error) = update.validate().into_iter().next() {
+            return Err(ErrorMessage::new(ErrorCode::BadRequest, error));
+        }
+
+        let source = self.config.camera(index).source_config();
+        if source == SourceConfig::V4l2 {
+            // An unplugged camera cannot be queried, its controls are checked when it is back
+            let discovered = tokio::task::spawn_blocking(move || capabilities::discover(index, &source)).await;
+            if let Ok(Ok(caps)) = discovered {
+                if let Some(error) = update.check_device(&caps.controls).into_iter().next() {
+                    return Err(ErrorMessage::new(ErrorCode::BadRequest, error));
+                }
+            }
+        }
+
+        let controls_tx = self.cameras.lock().unwrap().get(&index).map(|camera| camera.controls_tx.clone());
+        if let Some(controls_tx) = controls_tx {
+            let (reply, answer) = oneshot::channel();
+            if controls_tx.send(ControlsRequest { controls: update.clone(), reply }).is_ok() {
+                match tokio::time::timeout(SETTINGS_TIMEOUT, answer).await {
+                    Ok(Ok(Ok(_))) => {}
+                    Ok(Ok(Err(e))) => return Err(ErrorMessage::new(ErrorCode::SettingsRejected, e)),
+                    // The capture ended meanwhile, the controls apply on its next start
+                    Ok(Err(_)) => {}
+                    Err(_) => {
+                        return Err(ErrorMessage::new(
+                            ErrorCode::Internal,
+                            format!("Camera {} did not apply the controls in time", index),
+                        ));
+                    }
+                }
+            }
+        }
+
+        let controls = self.controls.get(index).merged(update);
+        self.controls.set(index, controls)
+            .map_err(|e| ErrorMessage::new(ErrorCode::Internal, e))?;
+        Ok(self.controls(index))
+    }
+
+    fn start(self: &Arc<Self>, index: i32) -> ActiveCamera {
+        let run = self.runs.next();
+        let (frames, _) = broadcast::channel(FRAME_BUFFER);
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let (settings_tx, requests) = mpsc::unbounded_channel();
+        let (controls_tx, control_requests) = mpsc::unbounded_channel();
+        let (applied_tx, applied) = watch::channel(None);
+        let (applied_controls_tx, applied_controls) = watch::channel(None);
+        let control = CaptureControl {
+            stop_rx,
+            requests,
+            control_requests,
+            applied: applied_tx,
+            applied_controls: applied_controls_tx,
+        };
+        let mut server = CameraServer::new(
+            frames.clone(),
+            control,
+            self.config.camera(index),
+            self.requested_settings(index),
+            self.controls.get(index),
+            self.events.clone(),
+            self.os_type.clone(),
+        );
+
+        println!("Starting capture of camera {}", index);
+        let ended = CaptureEnded {
+            registry: self.clone(),
+            index,
+            run,
+        };
+        let device = self.devices.lock().unwrap().entry(index).or_default().clone();
+        tokio::task::spawn_blocking(move || {
+            let _ended = ended;
+            // A capture thread that panicked still released the device
+            let _device = match device.try_lock() {
+                Ok(device) => device,
+                Err(TryLockError::Poisoned(e)) => e.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    println!("Waiting for the previous capture of camera {} to close the device", index);
+                    device.lock().unwrap_or_else(|e| e.into_inner())
+                }
+            };
+            if let Err(e) = server.run_capture() {
+                eprintln!("Camera {} error: {}", index, e);
+            }
+        });
+
+        ActiveCamera {
+            run,
+            frames,
+            viewers: HashSet::new(),
+            stop_tx,
+            settings_tx,
+            controls_tx,
+            applied,
+            applied_controls,
+        }
+    }
+
+    /// Drops a capture that ended by itself so its viewers' receivers
+    /// close and the next subscriber starts a fresh one.
+    fn capture_ended(&self, index: i32, run: u64) {
+        let mut cameras = self.cameras.lock().unwrap();
+        if cameras.get(&index).is_some_and(|camera| camera.run == run) {
+            cameras.remove(&index);
+            println!("Capture of camera {} ended with viewers still attached", index);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use crate::processor::frame_source::SourceConfig;
+use serde::Serialize;
+
+/// What a camera offers, so clients can pick valid capture settings.
+#[derive(Debug, Clone, Serialize)]
+pub struct CameraCapabilities {
+    pub index: i32,
+    /// `v4l2`, `file` or `test`
+    pub source: String,
+    pub path: Option<String>,
+    /// Card name reported by the driver, e.g. "HD Pro Webcam C920"
+    pub card: Option<String>,
+    pub driver: Option<String>,
+    pub bus: Option<String>,
+    pub formats: Vec<PixelFormat>,
+    pub controls: Vec<ControlInfo>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PixelFormat {
+    pub fourcc: String,
+    pub description: String,
+    pub compressed: bool,
+    /// Converted in user space by libv4l rather than delivered by the device
+    pub emulated: bool,
+    pub sizes: Vec<FrameSize>,
+    /// Set for devices that accept any size within a range
+    pub size_range: Option<SizeRange>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameSize {
+    pub width: u32,
+    pub height: u32,
+    /// Discrete frame rates, highest first
+    pub fps: Vec<f64>,
+    /// Set instead of `fps` when any rate in the range works
+    pub fps_range: Option<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SizeRange {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub step_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub step_height: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ControlInfo {
+    pub id: u32,
+    /// Name reported by the driver, e.g. "Brightness" or "Exposure, Auto"
+    pub name: String,
+    /// `integer`, `boolean`, `menu`, `button`, ...
+    pub kind: String,
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: u64,
+    pub default: i64,
+    pub value: Option<i64>,
+    pub read_only: bool,
+    /// Disabled or inactive, e.g. manual exposure while auto exposure is on
+    pub inactive: bool,
+    /// Entries of menu controls
+    pub menu: Vec<MenuEntry>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MenuEntry {
+    pub value: u32,
+    pub name: String,
+}
+
+/// Queries the device behind a camera index. Sources that are not
+/// devices only report their kind. Blocking, the device is opened.
+pub fn discover(index: i32, source: &SourceConfig) -> Result<CameraCapabilities, String> {
+    let kind = match source {
+        SourceConfig::V4l2 => return v4l2::query(index),
+        SourceConfig::File { .. } => "file",
+        SourceConfig::TestPattern { .. } => "test",
+    };
+    Ok(CameraCapabilities {
+        index,
+        source: kind.to_string(),
+        path: None,
+        card: None,
+        driver: None,
+        bus: None,
+        formats: Vec::new(),
+        controls: Vec::new(),
+    })
+}
+
+#[cfg(target_os = "linux")]
+mod v4l2 {
+    use super::*;
+    use v4l::control::{self, MenuItem, Type};
+    use v4l::format;
+    use v4l::frameinterval::FrameIntervalEnum;
+    use v4l::framesize::FrameSizeEnum;
+    use v4l::video::Capture;
+    use v4l::{Device, FourCC, Fraction};
+
+    fn fps(interval: &Fraction) -> f64 {
+        if interval.numerator == 0 {
+            return 0.0;
+        }
+        // Rounded to two decimals, 30000/1001 reads as 29.97
+        (interval.denominator as f64 / interval.numerator as f64 * 100.0).round() / 100.0
+    }
+
+    fn frame_size(device: &Device, fourcc: FourCC, width: u32, height: u32) -> FrameSize {
+        let mut size = FrameSize { width, height, fps: Vec::new(), fps_range: None };
+        for interval in device.enum_frameintervals(fourcc, width, height).unwrap_or_default() {
+            match interval.interval {
+                FrameIntervalEnum::Discrete(interval) => size.fps.push(fps(&interval)),
+                // The longest interval is the lowest rate
+                FrameIntervalEnum::Stepwise(range) => size.fps_range = Some((fps(&range.max), fps(&range.min))),
+            }
+        }
+        size.fps.sort_by(|a, b| b.total_cmp(a));
+        size.fps.dedup();
+        size
+    }
+
+    fn pixel_format(device: &Device, description: format::Description) -> PixelFormat {
+        let mut pixel_format = PixelFormat {
+            fourcc: description.fourcc.str().unwrap_or_default().to_string(),
+            description: description.description.clone(),
+            compressed: description.flags.contains(format::description::Flags::COMPRESSED),
+            emulated: description.flags.contains(format::description::Flags::EMULATED),
+            sizes: Vec::new(),
+            size_range: None,
+        };
+        for frame_size in device.enum_framesizes(description.fourcc).unwrap_or_default() {
+            match frame_size.size {
+                FrameSizeEnum::Discrete(size) => {
+                    pixel_format.sizes.push(self::frame_size(device, description.fourcc, size.width, size.height));
+                }
+                FrameSizeEnum::Stepwise(range) => {
+                    pixel_format.size_range = Some(SizeRange {
+                        min_width: range.min_width,
+                        max_width: range.max_width,
+                        step_width: range.step_width,
+                        min_height: range.min_height,
+                        max_height: range.max_height,
+                        step_height: range.step_height,
+                    });
+                }
+            }
+        }
+        pixel_format.sizes.sort_by_key(|size| std::cmp::Reverse(size.width * size.height));
+        pixel_format
+    }
+
+    fn control_info(device: &Device, description: control::Description) -> ControlInfo {
+        let readable = !description.flags.contains(control::Flags::WRITE_ONLY)
+            && matches!(description.typ, Type::Integer | Type::Boolean | Type::Menu | Type::IntegerMenu | Type::Integer64);
+        let value = if readable {
+            device.control(description.id).ok().and_then(|control| match control.value {
+                control::Value::Integer(value) => Some(value),
+                control::Value::Boolean(value) => Some(value as i64),
+                _ => None,
+            })
+        } else {
+            None
+        };
+        let menu = description.items.unwrap_or_default().into_iter()
+            .map(|(value, item)| MenuEntry {
+                value,
+                name: match item {
+                    MenuItem::Name(name) => name,
+                    MenuItem::Value(value) => value.to_string(),
+                },
+            })
+            .collect();
+
+        ControlInfo {
+            id: description.id,
+            name: description.name,
+            kind: format!("{:?}", description.typ).to_lowercase(),
+            minimum: description.minimum,
+            maximum: description.maximum,
+            step: description.step,
+            default: description.default,
+            value,
+            read_only: description.flags.contains(control::Flags::READ_ONLY),
+            inactive: description.flags.intersects(control::Flags::DISABLED | control::Flags::INACTIVE),
+            menu,
+        }
+    }
+
+    pub fn query(index: i32) -> Result<CameraCapabilities, String> {
+        let path = format!("/dev/video{}", index);
+        let device = Device::with_path(&path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        let caps = device.query_caps().map_err(|e| format!("Cannot query {}: {}", path, e))?;
+
+        let formats = device.enum_formats().unwrap_or_default().into_iter()
+            .map(|description| pixel_format(&device, description))
+            .collect();
+        let controls = device.query_controls().unwrap_or_default().into_iter()
+            .filter(|description| description.typ != Type::CtrlClass)
+            .map(|description| control_info(&device, description))
+            .collect();
+
+        Ok(CameraCapabilities {
+            index,
+            source: "v4l2".to_string(),
+            path: Some(path),
+            card: Some(caps.card),
+            driver: Some(caps.driver),
+            bus: Some(caps.bus),
+            formats,
+            controls,
+        })
+    }
+}
+
+/// Only V4L2 devices can be queried, other platforms get an error.
+#[cfg(not(target_os = "linux"))]
+mod v4l2 {
+    use super::*;
+
+    pub fn query(index: i32) -> Result<CameraCapabilities, String> {
+        Err(format!("Capability discovery of camera {} needs V4L2, which is Linux only", index))
+    }
+}
@@ -0,0 +1,181 @@
+use crate::config::CameraConfig;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_WIDTH: i32 = 320;
+pub const DEFAULT_HEIGHT: i32 = 240;
+pub const DEFAULT_FPS: f64 = 15.0;
+pub const DEFAULT_QUALITY: i32 = 60;
+pub const DEFAULT_FOURCC: &str = "MJPG";
+
+/// Resolution, frame rate, JPEG quality and pixel format of a capture.
+/// Used both for what was requested and for what the source delivers,
+/// where `fourcc` is unset for sources without one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CaptureSettings {
+    pub width: i32,
+    pub height: i32,
+    pub fps: f64,
+    /// JPEG encode quality, 1 to 100
+    pub quality: i32,
+    pub fourcc: Option<String>,
+}
+
+impl CaptureSettings {
+    /// Configured values with the pipeline defaults for the unset ones.
+    pub fn from_config(camera: &CameraConfig) -> Self {
+        Self {
+            width: camera.width.unwrap_or(DEFAULT_WIDTH),
+            height: camera.height.unwrap_or(DEFAULT_HEIGHT),
+            fps: camera.fps.unwrap_or(DEFAULT_FPS),
+            quality: camera.quality.unwrap_or(DEFAULT_QUALITY),
+            fourcc: Some(camera.fourcc.clone().unwrap_or_else(|| DEFAULT_FOURCC.to_string())),
+        }
+    }
+
+    /// Changing anything but the quality means reopening the device.
+    pub fn needs_reopen(&self, other: &CaptureSettings) -> bool {
+        self.width != other.width || self.height != other.height || self.fps != other.fps || self.fourcc != other.fourcc
+    }
+
+    pub fn fourcc_code(&self) -> Option<[char; 4]> {
+        let mut chars = self.fourcc.as_deref()?.chars();
+        Some([chars.next()?, chars.next()?, chars.next()?, chars.next()?])
+    }
+}
+
+/// Partial change of a camera's settings, unset fields stay as they are.
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureSettingsUpdate {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub fps: Option<f64>,
+    pub quality: Option<i32>,
+    pub fourcc: Option<String>,
+}
+
+impl CaptureSettingsUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.width.is_none() && self.height.is_none() && self.fps.is_none() && self.quality.is_none() && self.fourcc.is_none()
+    }
+
+    pub fn apply_to(&self, settings: &CaptureSettings) -> Result<CaptureSettings, String> {
+        if self.width.is_some() != self.height.is_some() {
+            return Err("width and height must be changed together".to_string());
+        }
+        let updated = CaptureSettings {
+            width: self.width.unwrap_or(settings.width),
+            height: self.height.unwrap_or(settings.height),
+            fps: self.fps.unwrap_or(settings.fps),
+            quality: self.quality.unwrap_or(settings.quality),
+            fourcc: self.fourcc.clone().or_else(|| settings.fourcc.clone()),
+        };
+        let errors = validate(Some(updated.width), Some(updated.height), Some(updated.fps), Some(updated.quality), updated.fourcc.as_deref());
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(updated),
+        }
+    }
+}
+
+/// Frame rates a capture loop can pace itself at. Below one frame per
+/// second the loop would sleep too long between stop checks.
+pub const MIN_FPS: f64 = 1.0;
+pub const MAX_FPS: f64 = 120.0;
+
+/// Whether `fps` is finite and within `MIN_FPS..=MAX_FPS`.
+pub fn valid_fps(fps: f64) -> bool {
+    fps.is_finite() && (MIN_FPS..=MAX_FPS).contains(&fps)
+}
+
+/// Range checks shared by the config file and runtime changes.
+pub fn validate(
+    width: Option<i32>,
+    height: Option<i32>,
+    fps: Option<f64>,
+    quality: Option<i32>,
+    fourcc: Option<&str>,
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    if width.is_some_and(|w| !(1..=7680).contains(&w)) || height.is_some_and(|h| !(1..=4320).contains(&h)) {
+        errors.push("width and height must be positive and at most 7680x4320".to_string());
+    }
+    if fps.is_some_and(|fps| !valid_fps(fps)) {
+        errors.push(format!("fps must be between {} and {}", MIN_FPS, MAX_FPS));
+    }
+    if quality.is_some_and(|q| !(1..=100).contains(&q)) {
+        errors.push("quality must be between 1 and 100".to_string());
+    }
+    if fourcc.is_some_and(|f| f.len() != 4 || !f.chars().all(|c| c.is_ascii_graphic() || c == ' ')) {
+        errors.push("fourcc must be four ASCII characters, e.g. MJPG or YUYV".to_string());
+    }
+    errors
+}
+
+/// Turns the number OpenCV reports for `CAP_PROP_FOURCC` back into text.
+pub fn fourcc_name(code: f64) -> Option<String> {
+    let code = code as u32;
+    if code == 0 {
+        return None;
+    }
+    let name: String = code.to_le_bytes().iter().map(|&b| b as char).collect();
+    Some(name.trim_end_matches('\0').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unset_values_are_valid() {
+        assert!(validate(None, None, None, None, None).is_empty());
+    }
+
+    #[test]
+    fn accepts_values_at_the_limits() {
+        assert!(validate(Some(1), Some(1), Some(1.0), Some(1), Some("MJPG")).is_empty());
+        assert!(validate(Some(7680), Some(4320), Some(120.0), Some(100), Some("YUYV")).is_empty());
+        assert!(validate(None, None, None, None, Some("Y16 ")).is_empty());
+    }
+
+    #[test]
+    fn rejects_sizes_out_of_range() {
+        assert_eq!(validate(Some(0), None, None, None, None).len(), 1);
+        assert_eq!(validate(Some(7681), None, None, None, None).len(), 1);
+        assert_eq!(validate(None, Some(4321), None, None, None).len(), 1);
+        assert_eq!(validate(None, Some(-1), None, None, None).len(), 1);
+        // Width and height share one message
+        assert_eq!(validate(Some(0), Some(0), None, None, None).len(), 1);
+    }
+
+    #[test]
+    fn rejects_fps_out_of_range() {
+        assert_eq!(validate(None, None, Some(0.0), None, None).len(), 1);
+        assert_eq!(validate(None, None, Some(-30.0), None, None).len(), 1);
+        assert_eq!(validate(None, None, Some(120.5), None, None).len(), 1);
+        assert_eq!(validate(None, None, Some(0.5), None, None).len(), 1);
+        assert_eq!(validate(None, None, Some(1e-20), None, None).len(), 1);
+        assert_eq!(validate(None, None, Some(f64::NAN), None, None).len(), 1);
+        assert_eq!(validate(None, None, Some(f64::INFINITY), None, None).len(), 1);
+    }
+
+    #[test]
+    fn rejects_quality_out_of_range() {
+        assert_eq!(validate(None, None, None, Some(0), None).len(), 1);
+        assert_eq!(validate(None, None, None, Some(101), None).len(), 1);
+    }
+
+    #[test]
+    fn rejects_bad_fourcc() {
+        assert_eq!(validate(None, None, None, None, Some("MJP")).len(), 1);
+        assert_eq!(validate(None, None, None, None, Some("MJPEG")).len(), 1);
+        assert_eq!(validate(None, None, None, None, Some("MJ\tG")).len(), 1);
+        assert_eq!(validate(None, None, None, None, Some("MJPÉ")).len(), 1);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        assert_eq!(validate(Some(0), None, Some(0.0), Some(0), Some("")).len(), 4);
+    }
+}
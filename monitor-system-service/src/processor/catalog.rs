@@ -0,0 +1,235 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS recordings (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL,
+    camera INTEGER NOT NULL,
+    path TEXT NOT NULL UNIQUE,
+    started_ms INTEGER NOT NULL,
+    ended_ms INTEGER NOT NULL,
+    size_bytes INTEGER NOT NULL,
+    frames INTEGER NOT NULL,
+    trigger TEXT,
+    trigger_id INTEGER,
+    thumbnail TEXT
+);
+CREATE INDEX IF NOT EXISTS recordings_camera_time ON recordings (camera, started_ms);
+";
+
+const COLUMNS: &str = "id, kind, camera, path, started_ms, ended_ms, size_bytes, frames, trigger, trigger_id, thumbnail";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingKind {
+    /// Part of the continuous recording
+    Segment,
+    /// Saved around a trigger
+    Clip,
+}
+
+impl RecordingKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            RecordingKind::Segment => "segment",
+            RecordingKind::Clip => "clip",
+        }
+    }
+}
+
+/// A stored recording, `id` is assigned by the catalog.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Recording {
+    pub id: i64,
+    pub kind: RecordingKind,
+    pub camera: i32,
+    pub path: String,
+    /// Unix milliseconds of the first and last frame
+    pub started_ms: u64,
+    pub ended_ms: u64,
+    pub size_bytes: u64,
+    pub frames: u64,
+    /// `motion`, `loud_sound` or `manual` for clips
+    pub trigger: Option<String>,
+    /// Event that triggered a clip
+    pub trigger_id: Option<u64>,
+    pub thumbnail: Option<String>,
+}
+
+impl Recording {
+    /// Rate the frames were captured at. Segments are written at the
+    /// requested rate, which the device may not have delivered.
+    pub fn captured_fps(&self) -> Option<f64> {
+        let duration_ms = self.ended_ms.saturating_sub(self.started_ms);
+        if self.frames > 1 && duration_ms > 0 {
+            Some((self.frames - 1) as f64 * 1000.0 / duration_ms as f64)
+        } else {
+            None
+        }
+    }
+}
+
+/// Query of `GET /recordings`, every field narrows the result.
+#[derive(Debug, Default, Deserialize)]
+pub struct RecordingFilter {
+    pub camera: Option<i32>,
+    pub kind: Option<RecordingKind>,
+    /// Recordings that end at or after this time, Unix milliseconds
+    pub from_ms: Option<u64>,
+    /// Recordings that start at or before this time, Unix milliseconds
+    pub to_ms: Option<u64>,
+    /// 100 by default, at most 1000
+    pub limit: Option<u32>,
+}
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// SQLite index of the segments and clips on disk. Writers add files
+/// once they are finished and remove them when they delete them.
+pub struct Catalog {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+fn recording(row: &Row) -> rusqlite::Result<Recording> {
+    let kind: String = row.get(1)?;
+    Ok(Recording {
+        id: row.get(0)?,
+        kind: if kind == "clip" { RecordingKind::Clip } else { RecordingKind::Segment },
+        camera: row.get(2)?,
+        path: row.get(3)?,
+        started_ms: row.get::<_, i64>(4)? as u64,
+        ended_ms: row.get::<_, i64>(5)? as u64,
+        size_bytes: row.get::<_, i64>(6)? as u64,
+        frames: row.get::<_, i64>(7)? as u64,
+        trigger: row.get(8)?,
+        trigger_id: row.get::<_, Option<i64>>(9)?.map(|id| id as u64),
+        thumbnail: row.get(10)?,
+    })
+}
+
+impl Catalog {
+    /// Opens or creates the database and drops entries whose file is gone,
+    /// e.g. deleted by hand while the service was down.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let connection = Connection::open(&path)
+            .and_then(|connection| connection.execute_batch(SCHEMA).map(|_| connection))
+            .map_err(|e| format!("Failed to open catalog {}: {}", path.display(), e))?;
+        let catalog = Self {
+            path,
+            connection: Mutex::new(connection),
+        };
+
+        let missing: Vec<String> = catalog.paths()?
+            .into_iter()
+            .filter(|path| !Path::new(path).exists())
+            .collect();
+        for path in &missing {
+            catalog.remove_path(path)?;
+        }
+        if !missing.is_empty() {
+            println!("Dropped {} recording(s) with missing files from the catalog", missing.len());
+        }
+        Ok(catalog)
+    }
+
+    /// Adds a finished file and returns it with its id.
+    pub fn add(&self, mut recording: Recording) -> Result<Recording, String> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO recordings (kind, camera, path, started_ms, ended_ms, size_bytes, frames, trigger, trigger_id, thumbnail)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                recording.kind.as_str(),
+                recording.camera,
+                recording.path,
+                recording.started_ms as i64,
+                recording.ended_ms as i64,
+                recording.size_bytes as i64,
+                recording.frames as i64,
+                recording.trigger,
+                recording.trigger_id.map(|id| id as i64),
+                recording.thumbnail,
+            ],
+        )
+        .map_err(|e| format!("Failed to add {} to the catalog: {}", recording.path, e))?;
+        recording.id = connection.last_insert_rowid();
+        Ok(recording)
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<Recording>, String> {
+        self.connection.lock().unwrap()
+            .query_row(&format!("SELECT {} FROM recordings WHERE id = ?1", COLUMNS), [id], recording)
+            .optional()
+            .map_err(|e| format!("Catalog query failed: {}", e))
+    }
+
+    /// Newest first.
+    pub fn list(&self, filter: &RecordingFilter) -> Result<Vec<Recording>, String> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT {} FROM recordings
+                 WHERE (?1 IS NULL OR camera = ?1)
+                   AND (?2 IS NULL OR kind = ?2)
+                   AND (?3 IS NULL OR ended_ms >= ?3)
+                   AND (?4 IS NULL OR started_ms <= ?4)
+                 ORDER BY started_ms DESC
+                 LIMIT ?5",
+                COLUMNS
+            ))
+            .map_err(|e| format!("Catalog query failed: {}", e))?;
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        let rows = statement
+            .query_map(
+                params![
+                    filter.camera,
+                    filter.kind.map(RecordingKind::as_str),
+                    filter.from_ms.map(|ms| ms as i64),
+                    filter.to_ms.map(|ms| ms as i64),
+                    limit as i64,
+                ],
+                recording,
+            )
+            .map_err(|e| format!("Catalog query failed: {}", e))?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(|e| format!("Catalog query failed: {}", e))
+    }
+
+    fn paths(&self) -> Result<Vec<String>, String> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT path FROM recordings")
+            .map_err(|e| format!("Catalog query failed: {}", e))?;
+        let rows = statement
+            .query_map([], |row| row.get(0))
+            .map_err(|e| format!("Catalog query failed: {}", e))?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(|e| format!("Catalog query failed: {}", e))
+    }
+
+    pub fn remove(&self, id: i64) -> Result<(), String> {
+        self.connection.lock().unwrap()
+            .execute("DELETE FROM recordings WHERE id = ?1", [id])
+            .map(|_| ())
+            .map_err(|e| format!("Failed to remove recording {} from the catalog: {}", id, e))
+    }
+
+    /// Forgets a file deleted by retention.
+    pub fn remove_path(&self, path: &str) -> Result<(), String> {
+        self.connection.lock().unwrap()
+            .execute("DELETE FROM recordings WHERE path = ?1", [path])
+            .map(|_| ())
+            .map_err(|e| format!("Failed to remove {} from the catalog: {}", path, e))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
@@ -0,0 +1,273 @@
+use crate::config::ClipConfig;
+use crate::processor::camera_registry::{CameraRegistry, VideoFrame};
+use crate::processor::catalog::{Catalog, Recording, RecordingKind};
+use crate::processor::event_log::{ClipInfo, Event, EventKind, EventLog};
+use crate::processor::recorder::utc_timestamp;
+use opencv::{
+    core,
+    imgcodecs,
+    prelude::*,
+    videoio,
+    Result,
+};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Viewer name the clip recorder holds cameras open with.
+const CLIPS_VIEWER: &str = "clips";
+
+/// Wait before a camera whose capture ended is opened again.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// A clip being collected: the pre-roll and everything since the trigger.
+struct Clip {
+    trigger: &'static str,
+    trigger_id: u64,
+    frames: Vec<Arc<VideoFrame>>,
+    /// First frame after the trigger, saved as the thumbnail
+    thumbnail: Option<Arc<VideoFrame>>,
+    started: Instant,
+    ends: Instant,
+    /// Motion that has not ended keeps the clip going
+    motion: bool,
+}
+
+/// Keeps the last `pre_roll_secs` of JPEG frames of each camera in memory
+/// and saves a clip from pre-roll through post-roll when motion, a loud
+/// sound or a user triggers one. Triggers during a clip extend it up to
+/// `max_clip_secs`.
+pub struct ClipRecorder {
+    config: ClipConfig,
+    cameras: Arc<CameraRegistry>,
+    events: Arc<EventLog>,
+    catalog: Arc<Catalog>,
+}
+
+impl ClipRecorder {
+    pub fn new(config: ClipConfig, cameras: Arc<CameraRegistry>, events: Arc<EventLog>, catalog: Arc<Catalog>) -> Self {
+        Self { config, cameras, events, catalog }
+    }
+
+    pub fn start(self: Arc<Self>, indexes: Vec<i32>) {
+        println!(
+            "Saving clips of camera(s) {:?} to {} with {} s pre-roll and {} s post-roll",
+            indexes,
+            self.config.dir.display(),
+            self.config.pre_roll_secs,
+            self.config.post_roll_secs
+        );
+        for index in indexes {
+            tokio::spawn(self.clone().run(index));
+        }
+    }
+
+    async fn run(self: Arc<Self>, index: i32) {
+        let mut events = self.events.subscribe();
+        loop {
+            let mut frames = self.cameras.subscribe(index, CLIPS_VIEWER);
+            let mut buffer: VecDeque<Arc<VideoFrame>> = VecDeque::new();
+            let mut clip: Option<Clip> = None;
+            loop {
+                tokio::select! {
+                    frame = frames.recv() => match frame {
+                        Ok(frame) => self.add_frame(index, frame, &mut buffer, &mut clip),
+                        Err(RecvError::Lagged(skipped)) => {
+                            println!("Clip buffer fell behind on camera {}, {} frame(s) missing", index, skipped);
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    event = events.recv() => {
+                        if let Ok(event) = event {
+                            self.handle_event(index, &event, &mut buffer, &mut clip);
+                        }
+                    }
+                }
+            }
+            if let Some(finished) = clip.take() {
+                self.save(index, finished);
+            }
+
+            println!("Capture of camera {} ended, buffering clips again in {} s", index, RETRY_DELAY.as_secs());
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    fn add_frame(&self, index: i32, frame: Arc<VideoFrame>, buffer: &mut VecDeque<Arc<VideoFrame>>, clip: &mut Option<Clip>) {
+        let finished = clip.as_mut().is_some_and(|current| {
+            if current.thumbnail.is_none() {
+                current.thumbnail = Some(frame.clone());
+            }
+            current.frames.push(frame.clone());
+            let now = Instant::now();
+            let too_long = now.duration_since(current.started).as_secs() >= self.config.max_clip_secs;
+            too_long || (!current.motion && now >= current.ends)
+        });
+        if finished {
+            if let Some(finished) = clip.take() {
+                self.save(index, finished);
+            }
+        }
+
+        // The pre-roll is kept while a clip runs, so the next one has it too
+        let pre_roll_ms = self.config.pre_roll_secs * 1000;
+        buffer.push_back(frame);
+        while buffer.front().is_some_and(|oldest| {
+            buffer.back().map_or(0, |newest| newest.timestamp_ms).saturating_sub(oldest.timestamp_ms) > pre_roll_ms
+        }) {
+            buffer.pop_front();
+        }
+    }
+
+    fn handle_event(&self, index: i32, event: &Event, buffer: &mut VecDeque<Arc<VideoFrame>>, clip: &mut Option<Clip>) {
+        // Microphone events have no camera and trigger every camera
+        if event.index.is_some_and(|camera| camera != index) {
+            return;
+        }
+        let post_roll = Duration::from_secs(self.config.post_roll_secs);
+        if let EventKind::MotionEnded { .. } = event.kind {
+            if let Some(current) = clip.as_mut() {
+                current.motion = false;
+                current.ends = Instant::now() + post_roll;
+            }
+            return;
+        }
+        let Some(trigger) = event.kind.trigger() else {
+            return;
+        };
+        let motion = matches!(event.kind, EventKind::MotionStarted { .. });
+
+        match clip.as_mut() {
+            Some(current) => {
+                current.ends = current.ends.max(Instant::now() + post_roll);
+                current.motion |= motion;
+            }
+            None => {
+                println!("Clip of camera {} triggered by {} (event {})", index, trigger, event.id);
+                *clip = Some(Clip {
+                    trigger,
+                    trigger_id: event.id,
+                    frames: buffer.iter().cloned().collect(),
+                    thumbnail: None,
+                    started: Instant::now(),
+                    ends: Instant::now() + post_roll,
+                    motion,
+                });
+            }
+        }
+    }
+
+    /// Writes the clip in the background, adds it to the catalog and
+    /// reports it as `clip_saved`.
+    fn save(&self, index: i32, clip: Clip) {
+        if clip.frames.is_empty() {
+            return;
+        }
+        let dir = self.config.dir.clone();
+        let events = self.events.clone();
+        let catalog = self.catalog.clone();
+        tokio::task::spawn_blocking(move || match write_clip(&dir, index, &clip) {
+            Ok(mut info) => {
+                match catalog.add(catalog_entry(index, &info)) {
+                    Ok(recording) => info.recording_id = Some(recording.id),
+                    Err(e) => eprintln!("{}", e),
+                }
+                events.record(Some(index), EventKind::ClipSaved(info));
+            }
+            Err(e) => eprintln!("Failed to save clip of camera {}: {}", index, e),
+        });
+    }
+}
+
+fn clip_error(message: String) -> opencv::Error {
+    opencv::Error::new(core::StsError, message)
+}
+
+/// Writes `<dir>/camera-<index>/<start>-<trigger>.avi` and the thumbnail
+/// next to it as `.jpg`.
+fn write_clip(dir: &Path, index: i32, clip: &Clip) -> Result<ClipInfo> {
+    let camera_dir = dir.join(format!("camera-{}", index));
+    std::fs::create_dir_all(&camera_dir)
+        .map_err(|e| clip_error(format!("Cannot create {}: {}", camera_dir.display(), e)))?;
+
+    let (first, last) = match (clip.frames.first(), clip.frames.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Err(clip_error("Clip has no frames".to_string())),
+    };
+    let started = UNIX_EPOCH + Duration::from_millis(first.timestamp_ms);
+    let id = format!("{}-{}", utc_timestamp(started), clip.trigger);
+    let path = camera_dir.join(format!("{}.avi", id));
+
+    // Played back at the rate the frames were captured
+    let duration_secs = (last.timestamp_ms.saturating_sub(first.timestamp_ms)) as f64 / 1000.0;
+    let fps = if duration_secs > 0.0 { (clip.frames.len() - 1) as f64 / duration_secs } else { 1.0 };
+
+    let mut writer: Option<videoio::VideoWriter> = None;
+    for frame in &clip.frames {
+        let image = imgcodecs::imdecode(&core::Vector::<u8>::from_slice(&frame.jpeg), imgcodecs::IMREAD_COLOR)?;
+        if image.empty() {
+            continue;
+        }
+        if writer.is_none() {
+            let opened = videoio::VideoWriter::new(
+                &path.to_string_lossy(),
+                videoio::VideoWriter::fourcc('M', 'J', 'P', 'G')?,
+                fps.clamp(1.0, 120.0),
+                image.size()?,
+                true,
+            )?;
+            if !opened.is_opened()? {
+                return Err(clip_error(format!("Cannot write {}", path.display())));
+            }
+            writer = Some(opened);
+        }
+        if let Some(writer) = writer.as_mut() {
+            writer.write(&image)?;
+        }
+    }
+    if let Some(mut writer) = writer {
+        writer.release()?;
+    }
+
+    let thumbnail = clip.thumbnail.as_ref().and_then(|frame| {
+        let thumbnail_path = camera_dir.join(format!("{}.jpg", id));
+        match std::fs::write(&thumbnail_path, &frame.jpeg) {
+            Ok(()) => Some(thumbnail_path.to_string_lossy().into_owned()),
+            Err(e) => {
+                eprintln!("Failed to write {}: {}", thumbnail_path.display(), e);
+                None
+            }
+        }
+    });
+
+    println!("Saved clip {} with {} frames", path.display(), clip.frames.len());
+    Ok(ClipInfo {
+        id,
+        trigger: clip.trigger.to_string(),
+        trigger_id: clip.trigger_id,
+        started_ms: first.timestamp_ms,
+        ended_ms: last.timestamp_ms,
+        frames: clip.frames.len(),
+        path: path.to_string_lossy().into_owned(),
+        thumbnail,
+        recording_id: None,
+    })
+}
+
+fn catalog_entry(index: i32, clip: &ClipInfo) -> Recording {
+    Recording {
+        id: 0,
+        kind: RecordingKind::Clip,
+        camera: index,
+        path: clip.path.clone(),
+        started_ms: clip.started_ms,
+        ended_ms: clip.ended_ms,
+        size_bytes: std::fs::metadata(&clip.path).map_or(0, |metadata| metadata.len()),
+        frames: clip.frames as u64,
+        trigger: Some(clip.trigger.clone()),
+        trigger_id: Some(clip.trigger_id),
+        thumbnail: clip.thumbnail.clone(),
+    }
+}
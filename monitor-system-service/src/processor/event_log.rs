@@ -0,0 +1,168 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Events kept in memory for `GET /events`, older ones are only in the file.
+const RECENT_EVENTS: usize = 1000;
+
+/// Events buffered for sockets that are slow to read them.
+const EVENT_BUFFER: usize = 64;
+
+/// Something that happened on a camera or the microphone, as logged and
+/// sent to clients.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Event {
+    /// Counts up from 1 for every start of the service
+    pub id: u64,
+    /// Unix milliseconds
+    pub timestamp_ms: u64,
+    /// Camera index, unset for microphone events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<i32>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// Movement inside the camera's zones, `boxes` in frame pixels
+    MotionStarted { boxes: Vec<MotionBox> },
+    /// No movement for the camera's `end_delay_secs`
+    MotionEnded { duration_ms: u64 },
+    /// The microphone got louder than `clips.audio_threshold_db`
+    LoudSound { level_db: f64 },
+    /// A user asked for a clip with `trigger_clip`
+    ManualTrigger {
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// A clip covering pre-roll to post-roll of `trigger_id` was written
+    ClipSaved(ClipInfo),
+}
+
+/// One saved clip, also listed by `GET /recordings`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClipInfo {
+    /// File name of the clip without extension
+    pub id: String,
+    /// `motion`, `loud_sound` or `manual`
+    pub trigger: String,
+    pub trigger_id: u64,
+    pub started_ms: u64,
+    pub ended_ms: u64,
+    pub frames: usize,
+    pub path: String,
+    pub thumbnail: Option<String>,
+    /// Id in the recording catalog, unset if it could not be added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording_id: Option<i64>,
+}
+
+impl EventKind {
+    /// Name of the trigger when this event starts a clip.
+    pub fn trigger(&self) -> Option<&'static str> {
+        match self {
+            EventKind::MotionStarted { .. } => Some("motion"),
+            EventKind::LoudSound { .. } => Some("loud_sound"),
+            EventKind::ManualTrigger { .. } => Some("manual"),
+            EventKind::MotionEnded { .. } | EventKind::ClipSaved(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MotionBox {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+/// Appends events to a JSON lines file, keeps the recent ones for queries
+/// and hands them to subscribed sockets.
+pub struct EventLog {
+    path: PathBuf,
+    recent: Mutex<VecDeque<Event>>,
+    next_id: Mutex<u64>,
+    events: broadcast::Sender<Event>,
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl EventLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            path: path.into(),
+            recent: Mutex::new(VecDeque::new()),
+            next_id: Mutex::new(0),
+            events,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn record(&self, index: Option<i32>, kind: EventKind) -> Event {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        let event = Event {
+            id,
+            timestamp_ms: now_ms(),
+            index,
+            kind,
+        };
+        match index {
+            Some(index) => println!("Event on camera {}: {:?}", index, event.kind),
+            None => println!("Event: {:?}", event.kind),
+        }
+
+        if let Err(e) = self.append(&event) {
+            eprintln!("Failed to log event to {}: {}", self.path.display(), e);
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        drop(recent);
+
+        let _ = self.events.send(event.clone());
+        event
+    }
+
+    fn append(&self, event: &Event) -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let line = serde_json::to_string(event)?;
+        writeln!(file, "{}", line)
+    }
+
+    /// Recent events, newest first.
+    pub fn recent(&self, index: Option<i32>, since_ms: Option<u64>, limit: usize) -> Vec<Event> {
+        self.recent.lock().unwrap().iter().rev()
+            .filter(|event| index.is_none_or(|index| event.index == Some(index)))
+            .filter(|event| since_ms.is_none_or(|since| event.timestamp_ms >= since))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
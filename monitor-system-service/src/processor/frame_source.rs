@@ -0,0 +1,445 @@
+use opencv::{
+    core,
+    imgproc,
+    prelude::*,
+    videoio,
+    Result,
+};
+use crate::processor::capture_settings::{fourcc_name, valid_fps, MAX_FPS, MIN_FPS};
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where the frames of a camera index come from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum SourceConfig {
+    #[default]
+    V4l2,
+    File { path: String },
+    TestPattern { width: i32, height: i32, fps: f64 },
+}
+
+impl FromStr for SourceConfig {
+    type Err = String;
+
+    /// Accepts `v4l2`, `file:<path>` and `test[:<width>x<height>[@<fps>]]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind.trim(), Some(arg.trim())),
+            None => (s.trim(), None),
+        };
+
+        match (kind, arg) {
+            ("v4l2", None) => Ok(SourceConfig::V4l2),
+            ("file", Some(path)) if !path.is_empty() => Ok(SourceConfig::File { path: path.to_string() }),
+            ("test", None) => Ok(SourceConfig::TestPattern { width: 640, height: 480, fps: 30.0 }),
+            ("test", Some(spec)) => {
+                let (size, fps) = match spec.split_once('@') {
+                    Some((size, fps)) => (size, fps.parse::<f64>().map_err(|_| format!("Invalid fps in '{}'", s))?),
+                    None => (spec, 30.0),
+                };
+                let (width, height) = size.split_once('x')
+                    .and_then(|(w, h)| Some((w.parse::<i32>().ok()?, h.parse::<i32>().ok()?)))
+                    .ok_or_else(|| format!("Invalid test pattern size in '{}'", s))?;
+                if width <= 0 || height <= 0 {
+                    return Err(format!("Test pattern size must be positive in '{}'", s));
+                }
+                if !valid_fps(fps) {
+                    return Err(format!("Test pattern fps must be between {} and {} in '{}'", MIN_FPS, MAX_FPS, s));
+                }
+                Ok(SourceConfig::TestPattern { width, height, fps })
+            }
+            _ => Err(format!("Unknown frame source '{}', expected v4l2, file:<path> or test[:WxH[@fps]]", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceCapabilities {
+    pub kind: String,
+    pub width: i32,
+    pub height: i32,
+    pub fps: f64,
+    /// Pixel format the device delivers, only known for real devices
+    pub fourcc: Option<String>,
+    pub live: bool,
+}
+
+pub trait FrameSource: Send {
+    fn open(&mut self) -> Result<()>;
+    fn read(&mut self, frame: &mut core::Mat) -> Result<bool>;
+    fn close(&mut self);
+    fn capabilities(&self) -> SourceCapabilities;
+
+    /// Changes a property of the open device and keeps it for the next
+    /// open. Sources that are not devices have none and ignore it.
+    fn set_property(&mut self, _prop: i32, _value: f64) -> Result<()> {
+        Ok(())
+    }
+
+    fn property(&self, _prop: i32) -> Option<f64> {
+        None
+    }
+}
+
+/// Capture backend preferred by OpenCV on the given OS.
+pub fn capture_api(os_type: &str) -> i32 {
+    match os_type {
+        "Linux" => videoio::CAP_V4L2,
+        "Windows" => videoio::CAP_WINRT,
+        "Darwin" => videoio::CAP_AVFOUNDATION,
+        _ => videoio::CAP_ANY,
+    }
+}
+
+/// Builds the source for a camera index. `properties` are applied to real
+/// devices only; file and test pattern sources ignore them.
+pub fn create_source(
+    index: i32,
+    config: &SourceConfig,
+    os_type: &str,
+    properties: Vec<(i32, f64)>,
+) -> Box<dyn FrameSource> {
+    match config {
+        SourceConfig::V4l2 => Box::new(V4l2Source::new(index, capture_api(os_type), properties)),
+        SourceConfig::File { path } => Box::new(VideoFileSource::new(path.clone())),
+        SourceConfig::TestPattern { width, height, fps } => {
+            Box::new(TestPatternSource::new(index, *width, *height, *fps))
+        }
+    }
+}
+
+fn open_error(message: String) -> opencv::Error {
+    opencv::Error::new(core::StsError, message)
+}
+
+pub struct V4l2Source {
+    index: i32,
+    api_preference: i32,
+    properties: Vec<(i32, f64)>,
+    capture: Option<videoio::VideoCapture>,
+}
+
+impl V4l2Source {
+    pub fn new(index: i32, api_preference: i32, properties: Vec<(i32, f64)>) -> Self {
+        Self {
+            index,
+            api_preference,
+            properties,
+            capture: None,
+        }
+    }
+}
+
+impl FrameSource for V4l2Source {
+    fn open(&mut self) -> Result<()> {
+        let mut cap = videoio::VideoCapture::new(self.index, self.api_preference)?;
+        if !cap.is_opened()? {
+            return Err(open_error(format!("Failed to open camera {}", self.index)));
+        }
+
+        for (prop, value) in &self.properties {
+            if let Err(e) = cap.set(*prop, *value) {
+                println!("Camera {} ignored property {}: {}", self.index, prop, e);
+            }
+        }
+
+        self.capture = Some(cap);
+        Ok(())
+    }
+
+    fn read(&mut self, frame: &mut core::Mat) -> Result<bool> {
+        match self.capture.as_mut() {
+            Some(cap) => cap.read(frame),
+            None => Err(open_error(format!("Camera {} is not open", self.index))),
+        }
+    }
+
+    fn close(&mut self) {
+        if let Some(mut cap) = self.capture.take() {
+            let _ = cap.release();
+        }
+    }
+
+    fn capabilities(&self) -> SourceCapabilities {
+        let get = |prop| self.capture.as_ref().and_then(|cap| cap.get(prop).ok()).unwrap_or(0.0);
+        SourceCapabilities {
+            kind: "v4l2".to_string(),
+            width: get(videoio::CAP_PROP_FRAME_WIDTH) as i32,
+            height: get(videoio::CAP_PROP_FRAME_HEIGHT) as i32,
+            fps: get(videoio::CAP_PROP_FPS),
+            fourcc: fourcc_name(get(videoio::CAP_PROP_FOURCC)),
+            live: true,
+        }
+    }
+
+    fn set_property(&mut self, prop: i32, value: f64) -> Result<()> {
+        match self.properties.iter_mut().find(|(existing, _)| *existing == prop) {
+            Some(property) => property.1 = value,
+            None => self.properties.push((prop, value)),
+        }
+        let cap = self.capture.as_mut()
+            .ok_or_else(|| open_error(format!("Camera {} is not open", self.index)))?;
+        if !cap.set(prop, value)? {
+            return Err(open_error(format!("Camera {} refused property {} = {}", self.index, prop, value)));
+        }
+        Ok(())
+    }
+
+    fn property(&self, prop: i32) -> Option<f64> {
+        self.capture.as_ref().and_then(|cap| cap.get(prop).ok())
+    }
+}
+
+/// Plays a video file in a loop, paced at the file's own frame rate.
+pub struct VideoFileSource {
+    path: String,
+    capture: Option<videoio::VideoCapture>,
+    frame_interval: Duration,
+    next_frame_at: Instant,
+}
+
+impl VideoFileSource {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            capture: None,
+            frame_interval: Duration::from_millis(33),
+            next_frame_at: Instant::now(),
+        }
+    }
+}
+
+impl FrameSource for VideoFileSource {
+    fn open(&mut self) -> Result<()> {
+        let cap = videoio::VideoCapture::from_file(&self.path, videoio::CAP_ANY)?;
+        if !cap.is_opened()? {
+            return Err(open_error(format!("Failed to open video file {}", self.path)));
+        }
+
+        // Broken files report any rate, those play at the default one
+        let fps = cap.get(videoio::CAP_PROP_FPS).unwrap_or(0.0);
+        if valid_fps(fps) {
+            self.frame_interval = Duration::from_secs_f64(1.0 / fps);
+        }
+        self.next_frame_at = Instant::now();
+        self.capture = Some(cap);
+        Ok(())
+    }
+
+    fn read(&mut self, frame: &mut core::Mat) -> Result<bool> {
+        let cap = match self.capture.as_mut() {
+            Some(cap) => cap,
+            None => return Err(open_error(format!("Video file {} is not open", self.path))),
+        };
+
+        let now = Instant::now();
+        if self.next_frame_at > now {
+            std::thread::sleep(self.next_frame_at - now);
+        }
+        self.next_frame_at = Instant::now() + self.frame_interval;
+
+        if cap.read(frame)? && !frame.empty() {
+            return Ok(true);
+        }
+
+        // End of file, rewind and start over
+        cap.set(videoio::CAP_PROP_POS_FRAMES, 0.0)?;
+        Ok(cap.read(frame)? && !frame.empty())
+    }
+
+    fn close(&mut self) {
+        if let Some(mut cap) = self.capture.take() {
+            let _ = cap.release();
+        }
+    }
+
+    fn capabilities(&self) -> SourceCapabilities {
+        let get = |prop| self.capture.as_ref().and_then(|cap| cap.get(prop).ok()).unwrap_or(0.0);
+        SourceCapabilities {
+            kind: "file".to_string(),
+            width: get(videoio::CAP_PROP_FRAME_WIDTH) as i32,
+            height: get(videoio::CAP_PROP_FRAME_HEIGHT) as i32,
+            fps: 1.0 / self.frame_interval.as_secs_f64(),
+            fourcc: None,
+            live: false,
+        }
+    }
+}
+
+/// Generated color bars with a sweeping box and a running timestamp.
+pub struct TestPatternSource {
+    index: i32,
+    width: i32,
+    height: i32,
+    fps: f64,
+    background: Option<core::Mat>,
+    frame_count: u64,
+    next_frame_at: Instant,
+}
+
+impl TestPatternSource {
+    pub fn new(index: i32, width: i32, height: i32, fps: f64) -> Self {
+        Self {
+            index,
+            width,
+            height,
+            fps,
+            background: None,
+            frame_count: 0,
+            next_frame_at: Instant::now(),
+        }
+    }
+
+    fn draw_bars(&self) -> Result<core::Mat> {
+        let colors = [
+            (192.0, 192.0, 192.0),
+            (0.0, 192.0, 192.0),
+            (192.0, 192.0, 0.0),
+            (0.0, 192.0, 0.0),
+            (192.0, 0.0, 192.0),
+            (0.0, 0.0, 192.0),
+            (192.0, 0.0, 0.0),
+        ];
+
+        let mut bars = core::Mat::new_rows_cols_with_default(
+            self.height,
+            self.width,
+            core::CV_8UC3,
+            core::Scalar::all(0.0),
+        )?;
+        let bar_width = self.width / colors.len() as i32 + 1;
+        for (i, (b, g, r)) in colors.iter().enumerate() {
+            imgproc::rectangle(
+                &mut bars,
+                core::Rect::new(i as i32 * bar_width, 0, bar_width, self.height),
+                core::Scalar::new(*b, *g, *r, 0.0),
+                imgproc::FILLED,
+                imgproc::LINE_8,
+                0,
+            )?;
+        }
+        Ok(bars)
+    }
+}
+
+fn format_wall_clock(now: SystemTime) -> String {
+    let elapsed = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}.{:03} UTC",
+        (secs / 3600) % 24,
+        (secs / 60) % 60,
+        secs % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+impl FrameSource for TestPatternSource {
+    fn open(&mut self) -> Result<()> {
+        self.background = Some(self.draw_bars()?);
+        self.frame_count = 0;
+        self.next_frame_at = Instant::now();
+        Ok(())
+    }
+
+    fn read(&mut self, frame: &mut core::Mat) -> Result<bool> {
+        let background = match self.background.as_ref() {
+            Some(background) => background,
+            None => return Err(open_error(format!("Test pattern {} is not open", self.index))),
+        };
+
+        let now = Instant::now();
+        if self.next_frame_at > now {
+            std::thread::sleep(self.next_frame_at - now);
+        }
+        self.next_frame_at = Instant::now() + Duration::from_secs_f64(1.0 / self.fps);
+
+        background.copy_to(frame)?;
+
+        // Box sweeping left to right so frozen streams are easy to spot
+        let box_size = self.height / 6;
+        let travel = (self.width - box_size).max(1) as u64;
+        let x = (self.frame_count * 4 % travel) as i32;
+        imgproc::rectangle(
+            frame,
+            core::Rect::new(x, self.height - box_size * 2, box_size, box_size),
+            core::Scalar::new(255.0, 255.0, 255.0, 0.0),
+            imgproc::FILLED,
+            imgproc::LINE_8,
+            0,
+        )?;
+
+        let label = format!("CAM {}  #{}  {}", self.index, self.frame_count, format_wall_clock(SystemTime::now()));
+        imgproc::put_text(
+            frame,
+            &label,
+            core::Point::new(10, 30),
+            imgproc::FONT_HERSHEY_SIMPLEX,
+            self.width as f64 / 800.0,
+            core::Scalar::new(255.0, 255.0, 255.0, 0.0),
+            2,
+            imgproc::LINE_AA,
+            false,
+        )?;
+
+        self.frame_count += 1;
+        Ok(true)
+    }
+
+    fn close(&mut self) {
+        self.background = None;
+    }
+
+    fn capabilities(&self) -> SourceCapabilities {
+        SourceCapabilities {
+            kind: "test".to_string(),
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            fourcc: None,
+            live: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sources() {
+        assert_eq!("v4l2".parse::<SourceConfig>(), Ok(SourceConfig::V4l2));
+        assert_eq!("file:/tmp/a.mp4".parse::<SourceConfig>(), Ok(SourceConfig::File { path: "/tmp/a.mp4".to_string() }));
+        assert_eq!("test".parse::<SourceConfig>(), Ok(SourceConfig::TestPattern { width: 640, height: 480, fps: 30.0 }));
+        assert_eq!("test:320x240@15".parse::<SourceConfig>(), Ok(SourceConfig::TestPattern { width: 320, height: 240, fps: 15.0 }));
+    }
+
+    #[test]
+    fn accepts_test_pattern_fps_at_the_limits() {
+        assert!("test:640x480@1".parse::<SourceConfig>().is_ok());
+        assert!("test:640x480@120".parse::<SourceConfig>().is_ok());
+    }
+
+    #[test]
+    fn rejects_test_pattern_fps_out_of_range() {
+        for spec in ["test:640x480@0", "test:640x480@-5", "test:640x480@0.5", "test:640x480@1e-300",
+                     "test:640x480@120.5", "test:640x480@nan", "test:640x480@inf"] {
+            assert!(spec.parse::<SourceConfig>().is_err(), "{} was accepted", spec);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_test_pattern_sizes() {
+        assert!("test:0x480".parse::<SourceConfig>().is_err());
+        assert!("test:640".parse::<SourceConfig>().is_err());
+        assert!("test:640x-1@30".parse::<SourceConfig>().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_sources() {
+        assert!("file:".parse::<SourceConfig>().is_err());
+        assert!("v4l2:0".parse::<SourceConfig>().is_err());
+        assert!("rtsp://camera".parse::<SourceConfig>().is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use crate::config::H264Config;
+use crate::processor::camera_registry::{CameraRegistry, FrameReceiver, RunIds, VideoFrame};
+use opencv::{
+    core,
+    imgcodecs,
+    imgproc,
+    prelude::*,
+};
+use openh264::encoder::{BitRate, Encoder, EncoderConfig, FrameRate, FrameType, IntraFramePeriod, RateControlMode, UsageType};
+use openh264::formats::YUVBuffer;
+use openh264::OpenH264API;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Viewer name of the encoders in the camera registry.
+const ENCODER_VIEWER: &str = "h264";
+
+/// Encoded frames buffered per camera before slow subscribers start to lag.
+const CHUNK_BUFFER: usize = 16;
+
+/// One encoded frame as Annex-B NAL units. Keyframes start with SPS and
+/// PPS, so a decoder can begin at any of them.
+#[derive(Debug)]
+pub struct H264Chunk {
+    pub index: i32,
+    /// Sequence number of the captured frame
+    pub seq: u64,
+    /// Capture time, Unix milliseconds
+    pub timestamp_ms: u64,
+    /// Capture time on `camera_registry::monotonic_us`
+    pub monotonic_us: u64,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+pub type ChunkReceiver = broadcast::Receiver<Arc<H264Chunk>>;
+
+struct ActiveEncoder {
+    /// Tells a finished encoder apart from a newer one on the same index
+    run: u64,
+    chunks: broadcast::Sender<Arc<H264Chunk>>,
+    viewers: HashSet<String>,
+    /// Set to make the next frame a keyframe
+    keyframe: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+/// Runs one software H.264 encoder per camera that has H.264 viewers.
+/// The encoder is a viewer of the camera's capture, so it shares the
+/// device with the JPEG viewers, and stops when its last viewer leaves.
+pub struct H264Encoders {
+    config: H264Config,
+    cameras: Arc<CameraRegistry>,
+    encoders: Mutex<HashMap<i32, ActiveEncoder>>,
+    runs: RunIds,
+}
+
+impl H264Encoders {
+    pub fn new(config: H264Config, cameras: Arc<CameraRegistry>) -> Self {
+        Self {
+            config,
+            cameras,
+            encoders: Mutex::new(HashMap::new()),
+            runs: RunIds::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Adds `viewer` to the camera's encoder, starting it when it is the
+    /// first one. Every new viewer gets a keyframe right away instead of
+    /// waiting for the end of the GOP. The receiver closes when the
+    /// capture ends.
+    pub fn subscribe(self: &Arc<Self>, index: i32, viewer: &str) -> ChunkReceiver {
+        let mut encoders = self.encoders.lock().unwrap();
+        let encoder = encoders.entry(index).or_insert_with(|| self.start(index));
+        encoder.viewers.insert(viewer.to_string());
+        encoder.keyframe.store(true, Ordering::Relaxed);
+        println!("Viewer {} joined H.264 stream of camera {}, {} watching", viewer, index, encoder.viewers.len());
+        encoder.chunks.subscribe()
+    }
+
+    pub fn unsubscribe(&self, index: i32, viewer: &str) {
+        let mut encoders = self.encoders.lock().unwrap();
+        let empty = match encoders.get_mut(&index) {
+            Some(encoder) => {
+                encoder.viewers.remove(viewer);
+                encoder.viewers.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            if let Some(encoder) = encoders.remove(&index) {
+                println!("No H.264 viewers left on camera {}, stopping encoder", index);
+                encoder.stopped.store(true, Ordering::Relaxed);
+                self.cameras.unsubscribe(index, ENCODER_VIEWER);
+            }
+        }
+    }
+
+    /// Asks for a keyframe, e.g. after a viewer skipped frames.
+    pub fn request_keyframe(&self, index: i32) {
+        if let Some(encoder) = self.encoders.lock().unwrap().get(&index) {
+            encoder.keyframe.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn start(self: &Arc<Self>, index: i32) -> ActiveEncoder {
+        let run = self.runs.next();
+        let (chunks, _) = broadcast::channel(CHUNK_BUFFER);
+        let keyframe = Arc::new(AtomicBool::new(true));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let frames = self.cameras.subscribe(index, ENCODER_VIEWER);
+        let fps = self.cameras.settings(index).requested.fps;
+
+        println!(
+            "Starting H.264 encoder of camera {} at {} kbit/s, keyframe every {} frames",
+            index, self.config.bitrate_kbps, self.config.gop_frames
+        );
+        let worker = EncoderWorker {
+            index,
+            run,
+            encoders: self.clone(),
+            config: self.config.clone(),
+            fps,
+            chunks: chunks.clone(),
+            keyframe: keyframe.clone(),
+            stopped: stopped.clone(),
+        };
+        tokio::task::spawn_blocking(move || worker.run(frames));
+
+        ActiveEncoder {
+            run,
+            chunks,
+            viewers: HashSet::new(),
+            keyframe,
+            stopped,
+        }
+    }
+
+    /// Drops an encoder whose capture ended by itself so its viewers'
+    /// receivers close and the next subscriber starts a fresh one.
+    fn encoder_ended(&self, index: i32, run: u64) {
+        let mut encoders = self.encoders.lock().unwrap();
+        if encoders.get(&index).is_some_and(|encoder| encoder.run == run) {
+            encoders.remove(&index);
+            println!("Capture of camera {} ended with H.264 viewers still attached", index);
+        }
+    }
+}
+
+struct EncoderWorker {
+    index: i32,
+    run: u64,
+    encoders: Arc<H264Encoders>,
+    config: H264Config,
+    fps: f64,
+    chunks: broadcast::Sender<Arc<H264Chunk>>,
+    keyframe: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl EncoderWorker {
+    /// Blocking. Encodes until the encoder is stopped or the capture
+    /// ends; dropping the sender then closes the viewers' receivers.
+    fn run(self, mut frames: FrameReceiver) {
+        let mut encoder = FrameEncoder::new(self.config.clone(), self.fps);
+        while !self.stopped.load(Ordering::Relaxed) {
+            let frame = match frames.blocking_recv() {
+                Ok(frame) => frame,
+                Err(RecvError::Lagged(skipped)) => {
+                    println!("H.264 encoder fell behind on camera {}, {} frame(s) skipped", self.index, skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => {
+                    self.encoders.encoder_ended(self.index, self.run);
+                    break;
+                }
+            };
+            if let Err(e) = self.encode(&frame, &mut encoder) {
+                eprintln!("H.264 encoding of camera {} failed: {}", self.index, e);
+            }
+        }
+        println!("H.264 encoder of camera {} stopped", self.index);
+    }
+
+    fn encode(&self, frame: &VideoFrame, encoder: &mut FrameEncoder) -> Result<(), String> {
+        let image = imgcodecs::imdecode(&core::Vector::<u8>::from_slice(&frame.jpeg), imgcodecs::IMREAD_COLOR)
+            .map_err(|e| e.to_string())?;
+        let keyframe = self.keyframe.swap(false, Ordering::Relaxed);
+        let (data, keyframe) = match encoder.encode(&image, keyframe)? {
+            Some(encoded) => encoded,
+            None => return Ok(()),
+        };
+        // No subscribers is fine, the encoder stops once the last one leaves
+        let _ = self.chunks.send(Arc::new(H264Chunk {
+            index: self.index,
+            seq: frame.seq,
+            timestamp_ms: frame.timestamp_ms,
+            monotonic_us: frame.monotonic_us,
+            keyframe,
+            data,
+        }));
+        Ok(())
+    }
+}
+
+/// An openh264 encoder for a stream of BGR images, recreated when the
+/// image size changes, e.g. after new capture settings.
+pub struct FrameEncoder {
+    config: H264Config,
+    fps: f64,
+    encoder: Option<(Encoder, i32, i32)>,
+}
+
+impl FrameEncoder {
+    pub fn new(config: H264Config, fps: f64) -> Self {
+        Self {
+            config,
+            fps,
+            encoder: None,
+        }
+    }
+
+    fn open(&self) -> Result<Encoder, String> {
+        let config = EncoderConfig::new()
+            .bitrate(BitRate::from_bps(self.config.bitrate_kbps * 1000))
+            .max_frame_rate(FrameRate::from_hz(self.fps as f32))
+            .rate_control_mode(RateControlMode::Bitrate)
+            .usage_type(UsageType::CameraVideoRealTime)
+            .intra_frame_period(IntraFramePeriod::from_num_frames(self.config.gop_frames));
+        Encoder::with_api_config(OpenH264API::from_source(), config).map_err(|e| e.to_string())
+    }
+
+    /// Encodes one image, as a keyframe when `keyframe` is set. Returns
+    /// the Annex-B data and whether it is a keyframe, nothing for frames
+    /// the rate control skipped. A failed encoder is opened again on the
+    /// next image.
+    pub fn encode(&mut self, image: &core::Mat, keyframe: bool) -> Result<Option<(Vec<u8>, bool)>, String> {
+        let (yuv, width, height) = i420(image).map_err(|e| e.to_string())?;
+        if self.encoder.as_ref().is_none_or(|(_, w, h)| (*w, *h) != (width, height)) {
+            self.encoder = Some((self.open()?, width, height));
+        }
+        let (encoder, _, _) = self.encoder.as_mut().unwrap();
+
+        if keyframe {
+            encoder.force_intra_frame();
+        }
+        let result = encoder
+            .encode(&YUVBuffer::from_vec(yuv, width as usize, height as usize))
+            .map(|bitstream| (bitstream.to_vec(), matches!(bitstream.frame_type(), FrameType::IDR | FrameType::I)));
+        match result {
+            Ok((data, _)) if data.is_empty() => Ok(None),
+            Ok(encoded) => Ok(Some(encoded)),
+            Err(e) => {
+                self.encoder = None;
+                Err(e.to_string())
+            }
+        }
+    }
+}
+
+/// Converts a BGR image to planar I420, cropped to even dimensions as
+/// H.264 requires.
+fn i420(image: &core::Mat) -> opencv::Result<(Vec<u8>, i32, i32)> {
+    let size = image.size()?;
+    let (width, height) = (size.width & !1, size.height & !1);
+    if width == 0 || height == 0 {
+        return Err(opencv::Error::new(core::StsError, format!("Frame of {}x{} is too small", size.width, size.height)));
+    }
+    let mut yuv = core::Mat::default();
+    if (width, height) == (size.width, size.height) {
+        imgproc::cvt_color_def(image, &mut yuv, imgproc::COLOR_BGR2YUV_I420)?;
+    } else {
+        let cropped = image.roi(core::Rect::new(0, 0, width, height))?.try_clone()?;
+        imgproc::cvt_color_def(&cropped, &mut yuv, imgproc::COLOR_BGR2YUV_I420)?;
+    }
+    Ok((yuv.data_bytes()?.to_vec(), width, height))
+}
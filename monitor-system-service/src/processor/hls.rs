@@ -0,0 +1,412 @@
+use crate::config::{H264Config, HlsConfig};
+use crate::processor::camera_registry::RunIds;
+use crate::processor::catalog::Recording;
+use crate::processor::h264::{ChunkReceiver, FrameEncoder, H264Encoders};
+use crate::processor::mpegts::TsMuxer;
+use crate::protocol::ErrorCode;
+use crate::r#trait::ErrorMessage;
+use opencv::{
+    core,
+    prelude::*,
+    videoio,
+};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+/// Viewer name of the live packagers on the H.264 encoders.
+const HLS_VIEWER: &str = "hls";
+
+pub const LIVE_PLAYLIST: &str = "live.m3u8";
+pub const VOD_PLAYLIST: &str = "index.m3u8";
+
+/// How long the first playlist request of a camera waits for its first
+/// segment, including opening the device.
+const FIRST_SEGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often live packagers check whether anybody still fetches them.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct LiveStream {
+    /// Tells a finished packager apart from a newer one on the same index
+    run: u64,
+    last_request: Arc<Mutex<Instant>>,
+    /// Set once the first segment is in the playlist
+    ready: watch::Receiver<bool>,
+}
+
+/// Packs cameras and recordings as HLS: MPEG-TS segments of H.264 and
+/// `.m3u8` playlists under `hls.dir`. A camera's live packager starts
+/// with the first playlist request, takes its frames from the camera's
+/// shared H.264 encoder, and stops once nobody has fetched anything for
+/// `idle_timeout_secs`. Recordings are transcoded once on request and
+/// kept in a small cache.
+pub struct HlsPackager {
+    config: HlsConfig,
+    h264_config: H264Config,
+    h264: Arc<H264Encoders>,
+    live: Mutex<HashMap<i32, LiveStream>>,
+    runs: RunIds,
+    /// Recordings are transcoded one at a time
+    vod: tokio::sync::Mutex<()>,
+}
+
+impl HlsPackager {
+    pub fn new(config: HlsConfig, h264_config: H264Config, h264: Arc<H264Encoders>) -> Self {
+        Self {
+            config,
+            h264_config,
+            h264,
+            live: Mutex::new(HashMap::new()),
+            runs: RunIds::default(),
+            vod: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    fn live_dir(&self, index: i32) -> PathBuf {
+        self.config.dir.join(format!("camera-{}", index))
+    }
+
+    fn vod_dir(&self, id: i64) -> PathBuf {
+        self.config.dir.join(format!("recording-{}", id))
+    }
+
+    /// The live playlist of a camera, starting its packager if needed.
+    pub async fn live_playlist(self: &Arc<Self>, index: i32) -> Result<String, ErrorMessage> {
+        let mut ready = {
+            let mut live = self.live.lock().unwrap();
+            let stream = live.entry(index).or_insert_with(|| self.start(index));
+            *stream.last_request.lock().unwrap() = Instant::now();
+            stream.ready.clone()
+        };
+        let first_segment = async {
+            loop {
+                if *ready.borrow_and_update() {
+                    return true;
+                }
+                if ready.changed().await.is_err() {
+                    return false;
+                }
+            }
+        };
+        match tokio::time::timeout(FIRST_SEGMENT_TIMEOUT, first_segment).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(ErrorMessage::new(ErrorCode::CameraStopped, format!("Camera {} stopped", index)));
+            }
+            Err(_) => {
+                return Err(ErrorMessage::new(ErrorCode::Internal, format!("Camera {} has no HLS segment yet", index)));
+            }
+        }
+        read_playlist(&self.live_dir(index).join(LIVE_PLAYLIST)).await
+    }
+
+    /// A segment of a running live packager, which counts as activity.
+    pub async fn live_segment(&self, index: i32, name: &str) -> Result<Vec<u8>, ErrorMessage> {
+        if let Some(stream) = self.live.lock().unwrap().get(&index) {
+            *stream.last_request.lock().unwrap() = Instant::now();
+        }
+        read_segment(&self.live_dir(index), name).await
+    }
+
+    fn start(self: &Arc<Self>, index: i32) -> LiveStream {
+        let run = self.runs.next();
+        let last_request = Arc::new(Mutex::new(Instant::now()));
+        let (ready_tx, ready) = watch::channel(false);
+        let chunks = self.h264.subscribe(index, HLS_VIEWER);
+
+        println!("Starting HLS packager of camera {} in {}", index, self.live_dir(index).display());
+        let packager = self.clone();
+        let activity = last_request.clone();
+        tokio::spawn(async move {
+            if let Err(e) = packager.package_live(index, chunks, activity, ready_tx).await {
+                eprintln!("HLS packager of camera {} failed: {}", index, e);
+            }
+            packager.live_ended(index, run).await;
+        });
+
+        LiveStream {
+            run,
+            last_request,
+            ready,
+        }
+    }
+
+    /// Cuts the camera's H.264 stream into segments at keyframes, asking
+    /// the encoder for one once a segment is long enough. Timestamps are
+    /// monotonic capture times from the first frame packaged, so clock
+    /// adjustments do not jump them. Returns when the capture ends or the
+    /// stream is idle.
+    async fn package_live(
+        &self,
+        index: i32,
+        mut chunks: ChunkReceiver,
+        last_request: Arc<Mutex<Instant>>,
+        ready: watch::Sender<bool>,
+    ) -> std::io::Result<()> {
+        let dir = self.live_dir(index);
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let target_us = self.config.segment_secs * 1_000_000;
+        let idle_timeout = Duration::from_secs(self.config.idle_timeout_secs);
+        let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        let mut muxer = TsMuxer::new();
+        let mut segment = Vec::new();
+        let mut first_us: Option<u64> = None;
+        let mut segment_start: Option<u64> = None;
+        let mut segments: VecDeque<(u64, f64)> = VecDeque::new();
+        let mut longest = 0.0;
+        let mut next_sequence = 0;
+        let mut need_keyframe = true;
+        let mut keyframe_requested = false;
+
+        loop {
+            tokio::select! {
+                chunk = chunks.recv() => {
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
+                        Err(RecvError::Lagged(_)) => {
+                            need_keyframe = true;
+                            self.h264.request_keyframe(index);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return Ok(()),
+                    };
+                    if need_keyframe && !chunk.keyframe {
+                        continue;
+                    }
+                    need_keyframe = false;
+
+                    if let Some(start) = segment_start {
+                        let elapsed = chunk.monotonic_us.saturating_sub(start);
+                        if chunk.keyframe && elapsed >= target_us {
+                            let name = segment_name(next_sequence);
+                            tokio::fs::write(dir.join(&name), std::mem::take(&mut segment)).await?;
+                            let duration = elapsed as f64 / 1_000_000.0;
+                            longest = f64::max(longest, duration);
+                            segments.push_back((next_sequence, duration));
+                            next_sequence += 1;
+                            while segments.len() > self.config.playlist_segments {
+                                if let Some((sequence, _)) = segments.pop_front() {
+                                    let _ = tokio::fs::remove_file(dir.join(segment_name(sequence))).await;
+                                }
+                            }
+                            let target = target_duration(self.config.segment_secs, longest);
+                            write_playlist(&dir.join(LIVE_PLAYLIST), &render_live_playlist(&segments, target)).await?;
+                            ready.send_replace(true);
+                            segment_start = None;
+                            keyframe_requested = false;
+                        } else if elapsed >= target_us && !keyframe_requested {
+                            self.h264.request_keyframe(index);
+                            keyframe_requested = true;
+                        }
+                    }
+                    segment_start.get_or_insert(chunk.monotonic_us);
+                    let origin = *first_us.get_or_insert(chunk.monotonic_us);
+                    let time_90k = chunk.monotonic_us.saturating_sub(origin) * 9 / 100;
+                    muxer.write_frame(&mut segment, &chunk.data, time_90k, chunk.keyframe);
+                }
+                _ = idle_check.tick() => {
+                    if last_request.lock().unwrap().elapsed() > idle_timeout {
+                        println!("Nobody fetched HLS of camera {} for {} s", index, idle_timeout.as_secs());
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Leaves the encoder and deletes the files. A newer packager on the
+    /// same index keeps both.
+    async fn live_ended(&self, index: i32, run: u64) {
+        let current = {
+            let mut live = self.live.lock().unwrap();
+            if live.get(&index).is_some_and(|stream| stream.run == run) {
+                live.remove(&index);
+                true
+            } else {
+                false
+            }
+        };
+        if current {
+            self.h264.unsubscribe(index, HLS_VIEWER);
+            let _ = tokio::fs::remove_dir_all(self.live_dir(index)).await;
+        }
+        println!("HLS packager of camera {} stopped", index);
+    }
+
+    /// The VOD playlist of a recording, transcoded on the first request.
+    pub async fn vod_playlist(&self, recording: &Recording) -> Result<String, ErrorMessage> {
+        let dir = self.vod_dir(recording.id);
+        let playlist = dir.join(VOD_PLAYLIST);
+        if !playlist.exists() {
+            let _transcoding = self.vod.lock().await;
+            if !playlist.exists() {
+                println!("Transcoding recording {} ({}) for HLS", recording.id, recording.path);
+                let recording = recording.clone();
+                let h264 = self.h264_config.clone();
+                let segment_secs = self.config.segment_secs;
+                tokio::task::spawn_blocking(move || transcode(&recording, &dir, &h264, segment_secs))
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()))
+                    .map_err(|e| ErrorMessage::new(ErrorCode::Internal, format!("Cannot transcode recording: {}", e)))?;
+                self.prune_vod().await;
+            }
+        }
+        read_playlist(&playlist).await
+    }
+
+    pub async fn vod_segment(&self, id: i64, name: &str) -> Result<Vec<u8>, ErrorMessage> {
+        read_segment(&self.vod_dir(id), name).await
+    }
+
+    /// Drops the transcoded copy of a deleted recording.
+    pub async fn forget_recording(&self, id: i64) {
+        let _ = tokio::fs::remove_dir_all(self.vod_dir(id)).await;
+    }
+
+    /// Keeps the `vod_cache_recordings` most recently transcoded recordings.
+    async fn prune_vod(&self) {
+        let mut cached = Vec::new();
+        if let Ok(mut entries) = tokio::fs::read_dir(&self.config.dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !name.starts_with("recording-") || name.ends_with(".tmp") {
+                    continue;
+                }
+                if let Ok(modified) = entry.metadata().await.and_then(|metadata| metadata.modified()) {
+                    cached.push((modified, entry.path()));
+                }
+            }
+        }
+        cached.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, path) in cached.into_iter().skip(self.config.vod_cache_recordings) {
+            let _ = tokio::fs::remove_dir_all(&path).await;
+        }
+    }
+}
+
+fn segment_name(sequence: u64) -> String {
+    format!("segment-{}.ts", sequence)
+}
+
+fn is_segment_name(name: &str) -> bool {
+    name.strip_prefix("segment-")
+        .and_then(|rest| rest.strip_suffix(".ts"))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+async fn read_playlist(path: &Path) -> Result<String, ErrorMessage> {
+    tokio::fs::read_to_string(path).await
+        .map_err(|e| ErrorMessage::new(ErrorCode::Internal, format!("Cannot read playlist: {}", e)))
+}
+
+async fn read_segment(dir: &Path, name: &str) -> Result<Vec<u8>, ErrorMessage> {
+    let not_found = || ErrorMessage::new(ErrorCode::NotFound, format!("Segment {} does not exist", name));
+    if !is_segment_name(name) {
+        return Err(not_found());
+    }
+    tokio::fs::read(dir.join(name)).await.map_err(|_| not_found())
+}
+
+/// Replaces the playlist in one step, players never see half of it.
+async fn write_playlist(path: &Path, content: &str) -> std::io::Result<()> {
+    let tmp = path.with_extension("m3u8.tmp");
+    tokio::fs::write(&tmp, content).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+/// `EXT-X-TARGETDURATION`, which no segment duration rounded to the
+/// nearest second may exceed (RFC 8216, 4.3.3.1). Live playlists keep
+/// it unless a segment runs longer than any before.
+fn target_duration(segment_secs: u64, longest: f64) -> u64 {
+    segment_secs.max(longest.round() as u64).max(1)
+}
+
+fn playlist_header(target: u64, first_sequence: u64) -> String {
+    format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+        target, first_sequence
+    )
+}
+
+fn playlist_entries(playlist: &mut String, segments: &[(u64, f64)]) {
+    for (sequence, duration) in segments {
+        playlist.push_str(&format!("#EXTINF:{:.3},\n{}\n", duration, segment_name(*sequence)));
+    }
+}
+
+fn render_live_playlist(segments: &VecDeque<(u64, f64)>, target: u64) -> String {
+    let segments: Vec<(u64, f64)> = segments.iter().copied().collect();
+    let mut playlist = playlist_header(target, segments.first().map_or(0, |(sequence, _)| *sequence));
+    playlist_entries(&mut playlist, &segments);
+    playlist
+}
+
+/// Blocking. Encodes a recording to segments of `segment_secs` in a
+/// temporary directory and moves it to `dir` once the playlist is
+/// complete.
+fn transcode(recording: &Recording, dir: &Path, h264: &H264Config, segment_secs: u64) -> Result<(), String> {
+    let tmp = dir.with_extension("tmp");
+    let _ = std::fs::remove_dir_all(&tmp);
+    std::fs::create_dir_all(&tmp).map_err(|e| e.to_string())?;
+
+    let mut capture = videoio::VideoCapture::from_file(&recording.path, videoio::CAP_ANY).map_err(|e| e.to_string())?;
+    if !capture.is_opened().map_err(|e| e.to_string())? {
+        return Err(format!("Cannot open {}", recording.path));
+    }
+    let fps = recording.captured_fps()
+        .unwrap_or_else(|| capture.get(videoio::CAP_PROP_FPS).unwrap_or(0.0));
+    let fps = if fps > 0.0 { fps } else { 1.0 };
+    let frames_per_segment = ((segment_secs as f64 * fps).round() as u64).max(1);
+
+    let mut encoder = FrameEncoder::new(h264.clone(), fps);
+    let mut muxer = TsMuxer::new();
+    let mut segments: Vec<(u64, f64)> = Vec::new();
+    let mut segment = Vec::new();
+    let mut segment_frames = 0;
+    let mut frame = core::Mat::default();
+    let mut position: u64 = 0;
+    while capture.read(&mut frame).map_err(|e| e.to_string())? && !frame.empty() {
+        // Every segment starts with a forced keyframe
+        let keyframe = position % frames_per_segment == 0;
+        if keyframe && segment_frames > 0 {
+            let sequence = segments.len() as u64;
+            std::fs::write(tmp.join(segment_name(sequence)), std::mem::take(&mut segment)).map_err(|e| e.to_string())?;
+            segments.push((sequence, segment_frames as f64 / fps));
+            segment_frames = 0;
+        }
+        if let Some((data, is_keyframe)) = encoder.encode(&frame, keyframe)? {
+            let time_90k = (position as f64 * 90_000.0 / fps) as u64;
+            muxer.write_frame(&mut segment, &data, time_90k, is_keyframe);
+        }
+        segment_frames += 1;
+        position += 1;
+    }
+    if segment_frames > 0 {
+        let sequence = segments.len() as u64;
+        std::fs::write(tmp.join(segment_name(sequence)), segment).map_err(|e| e.to_string())?;
+        segments.push((sequence, segment_frames as f64 / fps));
+    }
+    if segments.is_empty() {
+        return Err(format!("{} has no frames", recording.path));
+    }
+
+    let longest = segments.iter().map(|(_, duration)| *duration).fold(0.0, f64::max);
+    let mut playlist = playlist_header(target_duration(segment_secs, longest), 0);
+    playlist.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+    playlist_entries(&mut playlist, &segments);
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    std::fs::write(tmp.join(VOD_PLAYLIST), playlist).map_err(|e| e.to_string())?;
+
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::rename(&tmp, dir).map_err(|e| e.to_string())
+}
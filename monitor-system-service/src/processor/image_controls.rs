@@ -0,0 +1,248 @@
+use crate::processor::capabilities::ControlInfo;
+use crate::storage::{read_toml, write_atomic};
+use opencv::videoio;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// V4L2 menu values of `CAP_PROP_AUTO_EXPOSURE`: manual, and aperture
+/// priority, the automatic mode UVC cameras offer.
+const EXPOSURE_MANUAL: f64 = 1.0;
+const EXPOSURE_AUTO: f64 = 3.0;
+
+/// Image adjustments of a camera. Unset fields are left to the device,
+/// which keeps its own defaults or the last value it was given. Manual
+/// `exposure`, `white_balance` and `focus` only take effect while the
+/// matching automatic mode is off.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ImageControls {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contrast: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saturation: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sharpness: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gain: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_exposure: Option<bool>,
+    /// Exposure time in the device's unit, 100 µs for UVC cameras
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exposure: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_white_balance: Option<bool>,
+    /// Color temperature in Kelvin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub white_balance: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autofocus: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus: Option<f64>,
+}
+
+/// How one field maps to the device: its OpenCV property and the V4L2
+/// control behind it, whose range the device reports.
+#[derive(Debug, Clone, Copy)]
+pub struct Control {
+    pub name: &'static str,
+    pub prop: i32,
+    pub v4l2_id: u32,
+    /// Switches a mode rather than taking a value from a range
+    pub switch: bool,
+}
+
+const BRIGHTNESS: Control = Control { name: "brightness", prop: videoio::CAP_PROP_BRIGHTNESS, v4l2_id: 0x0098_0900, switch: false };
+const CONTRAST: Control = Control { name: "contrast", prop: videoio::CAP_PROP_CONTRAST, v4l2_id: 0x0098_0901, switch: false };
+const SATURATION: Control = Control { name: "saturation", prop: videoio::CAP_PROP_SATURATION, v4l2_id: 0x0098_0902, switch: false };
+const SHARPNESS: Control = Control { name: "sharpness", prop: videoio::CAP_PROP_SHARPNESS, v4l2_id: 0x0098_091b, switch: false };
+const GAIN: Control = Control { name: "gain", prop: videoio::CAP_PROP_GAIN, v4l2_id: 0x0098_0913, switch: false };
+const AUTO_EXPOSURE: Control = Control { name: "auto_exposure", prop: videoio::CAP_PROP_AUTO_EXPOSURE, v4l2_id: 0x009a_0901, switch: true };
+const EXPOSURE: Control = Control { name: "exposure", prop: videoio::CAP_PROP_EXPOSURE, v4l2_id: 0x009a_0902, switch: false };
+const AUTO_WHITE_BALANCE: Control = Control { name: "auto_white_balance", prop: videoio::CAP_PROP_AUTO_WB, v4l2_id: 0x0098_090c, switch: true };
+const WHITE_BALANCE: Control = Control { name: "white_balance", prop: videoio::CAP_PROP_WB_TEMPERATURE, v4l2_id: 0x0098_091a, switch: false };
+const AUTOFOCUS: Control = Control { name: "autofocus", prop: videoio::CAP_PROP_AUTOFOCUS, v4l2_id: 0x009a_090c, switch: true };
+const FOCUS: Control = Control { name: "focus", prop: videoio::CAP_PROP_FOCUS, v4l2_id: 0x009a_090a, switch: false };
+
+fn switch_value(on: bool) -> f64 {
+    if on { 1.0 } else { 0.0 }
+}
+
+impl ImageControls {
+    pub fn is_empty(&self) -> bool {
+        self.values().is_empty()
+    }
+
+    /// These controls with the fields set in `update` replaced.
+    pub fn merged(&self, update: &ImageControls) -> ImageControls {
+        ImageControls {
+            brightness: update.brightness.or(self.brightness),
+            contrast: update.contrast.or(self.contrast),
+            saturation: update.saturation.or(self.saturation),
+            sharpness: update.sharpness.or(self.sharpness),
+            gain: update.gain.or(self.gain),
+            auto_exposure: update.auto_exposure.or(self.auto_exposure),
+            exposure: update.exposure.or(self.exposure),
+            auto_white_balance: update.auto_white_balance.or(self.auto_white_balance),
+            white_balance: update.white_balance.or(self.white_balance),
+            autofocus: update.autofocus.or(self.autofocus),
+            focus: update.focus.or(self.focus),
+        }
+    }
+
+    /// The set fields as property values, automatic modes first so the
+    /// manual values they unlock are accepted.
+    pub fn values(&self) -> Vec<(Control, f64)> {
+        let exposure_mode = |on| if on { EXPOSURE_AUTO } else { EXPOSURE_MANUAL };
+        [
+            (AUTO_EXPOSURE, self.auto_exposure.map(exposure_mode)),
+            (AUTO_WHITE_BALANCE, self.auto_white_balance.map(switch_value)),
+            (AUTOFOCUS, self.autofocus.map(switch_value)),
+            (BRIGHTNESS, self.brightness),
+            (CONTRAST, self.contrast),
+            (SATURATION, self.saturation),
+            (SHARPNESS, self.sharpness),
+            (GAIN, self.gain),
+            (EXPOSURE, self.exposure),
+            (WHITE_BALANCE, self.white_balance),
+            (FOCUS, self.focus),
+        ]
+        .into_iter()
+        .filter_map(|(control, value)| Some((control, value?)))
+        .collect()
+    }
+
+    pub fn properties(&self) -> Vec<(i32, f64)> {
+        self.values().into_iter().map(|(control, value)| (control.prop, value)).collect()
+    }
+
+    /// Reads back the fields that are set here through `get`, which
+    /// returns a property of the open device.
+    pub fn read_back(&self, get: impl Fn(i32) -> Option<f64>) -> ImageControls {
+        let value = |field: Option<f64>, control: Control| field.and(get(control.prop));
+        let switch = |field: Option<bool>, control: Control, off: f64| field.and(get(control.prop)).map(|v| v != off);
+        ImageControls {
+            brightness: value(self.brightness, BRIGHTNESS),
+            contrast: value(self.contrast, CONTRAST),
+            saturation: value(self.saturation, SATURATION),
+            sharpness: value(self.sharpness, SHARPNESS),
+            gain: value(self.gain, GAIN),
+            auto_exposure: switch(self.auto_exposure, AUTO_EXPOSURE, EXPOSURE_MANUAL),
+            exposure: value(self.exposure, EXPOSURE),
+            auto_white_balance: switch(self.auto_white_balance, AUTO_WHITE_BALANCE, 0.0),
+            white_balance: value(self.white_balance, WHITE_BALANCE),
+            autofocus: switch(self.autofocus, AUTOFOCUS, 0.0),
+            focus: value(self.focus, FOCUS),
+        }
+    }
+
+    /// Checks that apply to every device. Device ranges are checked with
+    /// `check_device` when they are known.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (control, value) in self.values() {
+            if !value.is_finite() || value.abs() > 1_000_000.0 {
+                errors.push(format!("{} must be a number between -1000000 and 1000000", control.name));
+            }
+        }
+        let negative = [
+            ("contrast", self.contrast),
+            ("saturation", self.saturation),
+            ("sharpness", self.sharpness),
+            ("gain", self.gain),
+            ("exposure", self.exposure),
+            ("focus", self.focus),
+        ];
+        for (name, value) in negative {
+            if value.is_some_and(|v| v < 0.0) {
+                errors.push(format!("{} must not be negative", name));
+            }
+        }
+        if self.white_balance.is_some_and(|k| !(1000.0..=15000.0).contains(&k)) {
+            errors.push("white_balance must be between 1000 and 15000 Kelvin".to_string());
+        }
+        errors
+    }
+
+    /// Checks the set fields against the controls a V4L2 device reports.
+    pub fn check_device(&self, controls: &[ControlInfo]) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (control, value) in self.values() {
+            let Some(info) = controls.iter().find(|info| info.id == control.v4l2_id) else {
+                errors.push(format!("The camera has no {} control", control.name));
+                continue;
+            };
+            if info.read_only {
+                errors.push(format!("{} is read only on this camera", control.name));
+            } else if !control.switch && (value < info.minimum as f64 || value > info.maximum as f64) {
+                errors.push(format!("{} must be between {} and {} on this camera", control.name, info.minimum, info.maximum));
+            }
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ControlsFile {
+    #[serde(default)]
+    cameras: Vec<StoredControls>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredControls {
+    index: i32,
+    controls: ImageControls,
+}
+
+/// Image controls changed at runtime, kept in a TOML file so they are
+/// applied again after a restart.
+pub struct ControlsStore {
+    path: PathBuf,
+    controls: Mutex<BTreeMap<i32, ImageControls>>,
+}
+
+impl ControlsStore {
+    /// Opens the store at `path`. A missing file is an empty store.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let file: ControlsFile = read_toml(&path)?;
+        let controls = file.cameras.into_iter()
+            .map(|stored| (stored.index, stored.controls))
+            .collect();
+        Ok(Self {
+            path,
+            controls: Mutex::new(controls),
+        })
+    }
+
+    pub fn get(&self, index: i32) -> ImageControls {
+        self.controls.lock().unwrap().get(&index).cloned().unwrap_or_default()
+    }
+
+    pub fn set(&self, index: i32, controls: ImageControls) -> Result<(), String> {
+        let mut stored = self.controls.lock().unwrap();
+        stored.insert(index, controls);
+        self.save(&stored)
+    }
+
+    fn save(&self, stored: &BTreeMap<i32, ImageControls>) -> Result<(), String> {
+        let file = ControlsFile {
+            cameras: stored.iter()
+                .filter(|(_, controls)| !controls.is_empty())
+                .map(|(&index, controls)| StoredControls { index, controls: controls.clone() })
+                .collect(),
+        };
+        let content = toml::to_string_pretty(&file)
+            .map_err(|e| format!("Failed to serialize camera controls: {}", e))?;
+
+        write_atomic(&self.path, &content, None)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
@@ -0,0 +1,58 @@
+use crate::processor::event_log::{EventKind, EventLog};
+use crate::processor::microphone::Microphone;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Shortest time between two `loud_sound` events.
+const EVENT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Listener name loud sound detection holds the microphone with.
+const LOUDNESS_LISTENER: &str = "loudness";
+
+/// Wait before a microphone capture that failed or ended is started again.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// RMS level of 16-bit little-endian PCM in dB below full scale.
+pub fn level_db(chunk: &[u8]) -> f64 {
+    let samples: Vec<f64> = chunk.chunks_exact(2)
+        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f64 / 32768.0)
+        .collect();
+    if samples.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    let rms = (samples.iter().map(|s| s * s).sum::<f64>() / samples.len() as f64).sqrt();
+    20.0 * rms.log10()
+}
+
+/// Listens to the microphone, which keeps it capturing for good, and
+/// logs `loud_sound` when a chunk is louder than `threshold_db`, at most
+/// once per `EVENT_INTERVAL`. A capture that fails or ends is started
+/// again after a delay, so a microphone that was unplugged resumes once
+/// it is back.
+pub fn watch(microphone: Arc<Microphone>, threshold_db: f64, events: Arc<EventLog>) {
+    std::thread::spawn(move || {
+        println!("Listening for sounds above {} dBFS on {}", threshold_db, microphone.name());
+        let mut last_event: Option<Instant> = None;
+        loop {
+            match microphone.subscribe(LOUDNESS_LISTENER) {
+                Ok(mut chunks) => loop {
+                    let chunk = match chunks.blocking_recv() {
+                        Ok(chunk) => chunk,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    };
+                    let level = level_db(&chunk);
+                    if level < threshold_db || last_event.is_some_and(|at| at.elapsed() < EVENT_INTERVAL) {
+                        continue;
+                    }
+                    last_event = Some(Instant::now());
+                    events.record(None, EventKind::LoudSound { level_db: (level * 10.0).round() / 10.0 });
+                },
+                Err(e) => eprintln!("Loud sounds are not detected, the microphone failed to start: {}", e),
+            }
+            println!("Microphone capture ended, listening for loud sounds again in {} s", RETRY_DELAY.as_secs());
+            std::thread::sleep(RETRY_DELAY);
+        }
+    });
+}
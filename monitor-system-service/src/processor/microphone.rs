@@ -0,0 +1,138 @@
+use crate::processor::audio_source::{AudioFormat, AudioSource};
+use crate::processor::camera_registry::RunIds;
+use crate::r#trait::AudioStreamHandle;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Chunks buffered before slow listeners start to lag.
+const CHUNK_BUFFER: usize = 32;
+
+pub type ChunkReceiver = broadcast::Receiver<Arc<Vec<u8>>>;
+
+/// A listener that leaves the microphone when dropped, for consumers
+/// that can go away at any point, like socket tasks.
+pub struct AudioSubscription {
+    pub chunks: ChunkReceiver,
+    microphone: Arc<Microphone>,
+    listener: String,
+}
+
+impl Drop for AudioSubscription {
+    fn drop(&mut self) {
+        self.microphone.unsubscribe(&self.listener);
+    }
+}
+
+struct ActiveCapture {
+    /// Tells a finished capture apart from a newer one
+    run: u64,
+    chunks: broadcast::Sender<Arc<Vec<u8>>>,
+    listeners: HashSet<String>,
+    handle: AudioStreamHandle,
+}
+
+/// Shares one capture of the audio source between ears clients, WebRTC
+/// peers and loud sound detection, like `CameraRegistry` shares a
+/// camera. The capture starts with the first listener and stops when
+/// the last one leaves.
+pub struct Microphone {
+    source: Arc<dyn AudioSource>,
+    format: AudioFormat,
+    active: Mutex<Option<ActiveCapture>>,
+    runs: RunIds,
+}
+
+impl Microphone {
+    pub fn new(source: Arc<dyn AudioSource>, format: AudioFormat) -> Self {
+        Self {
+            source,
+            format,
+            active: Mutex::new(None),
+            runs: RunIds::default(),
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.source.name()
+    }
+
+    pub fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    /// Adds `listener`, starting the capture when it is the first one.
+    /// The receiver closes when the capture ends on its own.
+    pub fn subscribe(self: &Arc<Self>, listener: &str) -> Result<ChunkReceiver, String> {
+        let mut active = self.active.lock().unwrap();
+        if active.is_none() {
+            *active = Some(self.start()?);
+        }
+        let capture = active.as_mut().unwrap();
+        capture.listeners.insert(listener.to_string());
+        println!("Listener {} joined the microphone, {} listening", listener, capture.listeners.len());
+        Ok(capture.chunks.subscribe())
+    }
+
+    /// Like `subscribe`, leaving the microphone once the subscription is dropped.
+    pub fn subscription(self: &Arc<Self>, listener: String) -> Result<AudioSubscription, String> {
+        Ok(AudioSubscription {
+            chunks: self.subscribe(&listener)?,
+            microphone: self.clone(),
+            listener,
+        })
+    }
+
+    pub fn unsubscribe(&self, listener: &str) {
+        let mut active = self.active.lock().unwrap();
+        let empty = match active.as_mut() {
+            Some(capture) => {
+                capture.listeners.remove(listener);
+                capture.listeners.is_empty()
+            }
+            None => return,
+        };
+        if empty {
+            if let Some(capture) = active.take() {
+                println!("No listeners left on the microphone, stopping capture");
+                capture.handle.stop();
+            }
+        }
+    }
+
+    /// Starts the source and a thread handing its chunks to the listeners.
+    fn start(self: &Arc<Self>) -> Result<ActiveCapture, String> {
+        let run = self.runs.next();
+        let (audio_sender, audio_receiver) = crossbeam_channel::bounded(CHUNK_BUFFER);
+        let handle = self.source.start(audio_sender)?;
+        let (chunks, _) = broadcast::channel(CHUNK_BUFFER);
+
+        let microphone = self.clone();
+        let sender = chunks.clone();
+        std::thread::spawn(move || {
+            // Ends once the stopped source drops its sender
+            for chunk in audio_receiver.iter() {
+                let _ = sender.send(Arc::new(chunk));
+            }
+            microphone.capture_ended(run);
+        });
+
+        println!("Started capturing {}", self.source.name());
+        Ok(ActiveCapture {
+            run,
+            chunks,
+            listeners: HashSet::new(),
+            handle,
+        })
+    }
+
+    /// Drops a capture that ended by itself so its listeners' receivers
+    /// close. A newer capture is left alone.
+    fn capture_ended(&self, run: u64) {
+        let mut active = self.active.lock().unwrap();
+        if active.as_ref().is_some_and(|capture| capture.run == run) {
+            println!("Capture of {} ended with listeners still attached", self.source.name());
+            *active = None;
+        }
+    }
+}
@@ -1 +1,21 @@
+pub mod audio_source;
 pub mod camera_control;
+pub mod camera_inventory;
+pub mod capabilities;
+pub mod capture_settings;
+pub mod catalog;
+pub mod clips;
+pub mod event_log;
+pub mod camera_registry;
+pub mod frame_source;
+pub mod h264;
+pub mod hls;
+pub mod image_controls;
+pub mod loudness;
+pub mod microphone;
+pub mod motion;
+pub mod mpegts;
+pub mod playback;
+pub mod recorder;
+pub mod snapshot;
+pub mod webrtc_peer;
@@ -0,0 +1,165 @@
+use crate::config::MotionConfig;
+use crate::processor::event_log::{EventKind, MotionBox};
+use opencv::{
+    core,
+    imgproc,
+    prelude::*,
+    video,
+    Result,
+};
+use std::time::{Duration, Instant};
+
+/// Frames are scaled down to this width before they are compared.
+const ANALYSIS_WIDTH: i32 = 320;
+
+/// Frames the background model learns from before motion is reported.
+const WARM_UP_FRAMES: u32 = 30;
+
+/// Foreground mask values below this are shadows or noise.
+const FOREGROUND_THRESHOLD: f64 = 200.0;
+
+/// Background subtraction on downscaled gray frames. Reports when
+/// movement larger than `min_area` starts inside the zones, and when it
+/// has been still for `end_delay_secs`.
+pub struct MotionDetector {
+    config: MotionConfig,
+    subtractor: core::Ptr<video::BackgroundSubtractorMOG2>,
+    /// Size of the analysed frames and the zone mask drawn for it
+    size: Option<(core::Size, Option<core::Mat>)>,
+    frames_seen: u32,
+    started: Option<Instant>,
+    last_motion: Instant,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Result<Self> {
+        Ok(Self {
+            subtractor: Self::subtractor(&config)?,
+            config,
+            size: None,
+            frames_seen: 0,
+            started: None,
+            last_motion: Instant::now(),
+        })
+    }
+
+    /// Sensitivity 50 gives OpenCV's default variance threshold of 16,
+    /// higher sensitivity reacts to smaller changes.
+    fn subtractor(config: &MotionConfig) -> Result<core::Ptr<video::BackgroundSubtractorMOG2>> {
+        let var_threshold = 2.0 + (100.0 - config.sensitivity as f64) * 0.28;
+        video::create_background_subtractor_mog2(500, var_threshold, true)
+    }
+
+    /// Mask that keeps the pixels inside the zones, `None` for the whole frame.
+    fn zone_mask(&self, size: core::Size) -> Result<Option<core::Mat>> {
+        if self.config.zones.is_empty() {
+            return Ok(None);
+        }
+        let mut mask = core::Mat::new_rows_cols_with_default(size.height, size.width, core::CV_8UC1, core::Scalar::all(0.0))?;
+        let polygons: core::Vector<core::Vector<core::Point>> = self.config.zones.iter()
+            .map(|zone| {
+                zone.iter()
+                    .map(|[x, y]| core::Point::new((x * size.width as f64) as i32, (y * size.height as f64) as i32))
+                    .collect()
+            })
+            .collect();
+        imgproc::fill_poly_def(&mut mask, &polygons, core::Scalar::all(255.0))?;
+        Ok(Some(mask))
+    }
+
+    /// Feeds one captured frame. Returns the event when motion starts or ends.
+    pub fn process(&mut self, frame: &core::Mat) -> Result<Option<EventKind>> {
+        let frame_size = frame.size()?;
+        if frame_size.width <= 0 || frame_size.height <= 0 {
+            return Ok(None);
+        }
+        let scale = (ANALYSIS_WIDTH as f64 / frame_size.width as f64).min(1.0);
+        let size = core::Size::new(
+            (frame_size.width as f64 * scale).round() as i32,
+            (frame_size.height as f64 * scale).round() as i32,
+        );
+
+        // A new resolution means a new background
+        if self.size.as_ref().is_none_or(|(current, _)| *current != size) {
+            self.subtractor = Self::subtractor(&self.config)?;
+            self.size = Some((size, self.zone_mask(size)?));
+            self.frames_seen = 0;
+        }
+
+        let mut small = core::Mat::default();
+        imgproc::resize(frame, &mut small, size, 0.0, 0.0, imgproc::INTER_AREA)?;
+        let mut gray = core::Mat::default();
+        imgproc::cvt_color_def(&small, &mut gray, imgproc::COLOR_BGR2GRAY)?;
+        let mut blurred = core::Mat::default();
+        imgproc::gaussian_blur_def(&gray, &mut blurred, core::Size::new(5, 5), 0.0)?;
+
+        let mut foreground = core::Mat::default();
+        self.subtractor.apply(&blurred, &mut foreground, -1.0)?;
+        self.frames_seen = self.frames_seen.saturating_add(1);
+        if self.frames_seen < WARM_UP_FRAMES {
+            return Ok(None);
+        }
+
+        let mut mask = core::Mat::default();
+        imgproc::threshold(&foreground, &mut mask, FOREGROUND_THRESHOLD, 255.0, imgproc::THRESH_BINARY)?;
+        if let Some((_, Some(zones))) = &self.size {
+            let mut inside = core::Mat::default();
+            core::bitwise_and_def(&mask, zones, &mut inside)?;
+            mask = inside;
+        }
+        let mut dilated = core::Mat::default();
+        imgproc::dilate_def(&mask, &mut dilated, &core::Mat::default())?;
+
+        let boxes = self.moving_boxes(&mut dilated, size, scale)?;
+        Ok(self.update(boxes))
+    }
+
+    /// Bounding boxes of the moving regions large enough to count, in
+    /// pixels of the captured frame.
+    fn moving_boxes(&self, mask: &mut core::Mat, size: core::Size, scale: f64) -> Result<Vec<MotionBox>> {
+        let mut contours = core::Vector::<core::Vector<core::Point>>::new();
+        imgproc::find_contours_def(mask, &mut contours, imgproc::RETR_EXTERNAL, imgproc::CHAIN_APPROX_SIMPLE)?;
+
+        let min_area = (size.width * size.height) as f64 * self.config.min_area / 100.0;
+        let mut boxes = Vec::new();
+        for contour in contours.iter() {
+            if imgproc::contour_area_def(&contour)? < min_area {
+                continue;
+            }
+            let rect = imgproc::bounding_rect(&contour)?;
+            boxes.push(MotionBox {
+                x: (rect.x as f64 / scale) as i32,
+                y: (rect.y as f64 / scale) as i32,
+                width: (rect.width as f64 / scale) as i32,
+                height: (rect.height as f64 / scale) as i32,
+            });
+        }
+        Ok(boxes)
+    }
+
+    fn update(&mut self, boxes: Vec<MotionBox>) -> Option<EventKind> {
+        let now = Instant::now();
+        if !boxes.is_empty() {
+            self.last_motion = now;
+            if self.started.is_none() {
+                self.started = Some(now);
+                return Some(EventKind::MotionStarted { boxes });
+            }
+            return None;
+        }
+
+        let still_for = now.duration_since(self.last_motion);
+        if self.started.is_some() && still_for >= Duration::from_secs(self.config.end_delay_secs) {
+            return self.finish();
+        }
+        None
+    }
+
+    /// Ends ongoing motion, e.g. when the capture stops.
+    pub fn finish(&mut self) -> Option<EventKind> {
+        let started = self.started.take()?;
+        Some(EventKind::MotionEnded {
+            duration_ms: self.last_motion.duration_since(started).as_millis() as u64,
+        })
+    }
+}
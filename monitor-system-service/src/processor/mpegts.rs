@@ -0,0 +1,170 @@
+/// Size of every transport stream packet.
+const PACKET_SIZE: usize = 188;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+
+/// `stream_type` of H.264 video in the PMT.
+const STREAM_TYPE_H264: u8 = 0x1b;
+
+/// Access unit delimiter, HLS players expect one before every frame.
+const AUD: [u8; 6] = [0x00, 0x00, 0x00, 0x01, 0x09, 0xf0];
+
+/// Presentation times run ahead of the clock reference by this much, in
+/// 90 kHz ticks, so decoders have the frame before it is due.
+const PTS_DELAY: u64 = 9000;
+
+/// Timestamps are 33 bits and wrap.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// Packs Annex-B H.264 access units into an MPEG transport stream with a
+/// single program and video stream, the format HLS segments use. PAT and
+/// PMT are repeated before every keyframe, so each segment that starts
+/// at one can be decoded on its own.
+pub struct TsMuxer {
+    pat_counter: u8,
+    pmt_counter: u8,
+    video_counter: u8,
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        Self {
+            pat_counter: 0,
+            pmt_counter: 0,
+            video_counter: 0,
+        }
+    }
+
+    /// Appends one access unit to `out`. `time_90k` is its presentation
+    /// time on the 90 kHz clock.
+    pub fn write_frame(&mut self, out: &mut Vec<u8>, data: &[u8], time_90k: u64, keyframe: bool) {
+        if keyframe {
+            self.write_tables(out);
+        }
+        let pcr = time_90k & TIMESTAMP_MASK;
+        let pts = (time_90k + PTS_DELAY) & TIMESTAMP_MASK;
+
+        let mut pes = Vec::with_capacity(14 + AUD.len() + data.len());
+        // Video PES packets may leave their length unset
+        pes.extend_from_slice(&[0x00, 0x00, 0x01, 0xe0, 0x00, 0x00, 0x80, 0x80, 0x05]);
+        pes.extend_from_slice(&timestamp(0x20, pts));
+        pes.extend_from_slice(&AUD);
+        pes.extend_from_slice(data);
+
+        let mut offset = 0;
+        let mut first = true;
+        while offset < pes.len() {
+            let remaining = pes.len() - offset;
+            // Adaptation field contents after its length byte
+            let mut adaptation = Vec::new();
+            if first {
+                // PCR flag, random access on keyframes
+                adaptation.push(if keyframe { 0x50 } else { 0x10 });
+                adaptation.extend_from_slice(&program_clock(pcr));
+            }
+            let has_adaptation = first || remaining < PACKET_SIZE - 4;
+            let mut capacity = PACKET_SIZE - 4 - if has_adaptation { 1 + adaptation.len() } else { 0 };
+            if has_adaptation && adaptation.is_empty() && remaining < capacity {
+                // Stuffing needs the flags byte in front of it
+                adaptation.push(0x00);
+                capacity -= 1;
+            }
+            let payload = remaining.min(capacity);
+            let stuffing = capacity - payload;
+
+            let counter = next_counter(&mut self.video_counter);
+            let start = if first { 0x40 } else { 0x00 };
+            out.extend_from_slice(&[0x47, start | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8]);
+            if has_adaptation {
+                out.push(0x30 | counter);
+                out.push((adaptation.len() + stuffing) as u8);
+                out.extend_from_slice(&adaptation);
+                out.extend(std::iter::repeat(0xff).take(stuffing));
+            } else {
+                out.push(0x10 | counter);
+            }
+            out.extend_from_slice(&pes[offset..offset + payload]);
+            offset += payload;
+            first = false;
+        }
+    }
+
+    fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let pat = [
+            0x00, // table_id
+            0xb0, 0x0d, // section_length 13
+            0x00, 0x01, // transport_stream_id
+            0xc1, 0x00, 0x00, // version 0, current, section 0 of 0
+            0x00, 0x01, // program_number 1
+            0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8,
+        ];
+        let counter = next_counter(&mut self.pat_counter);
+        write_section(out, PAT_PID, counter, &pat);
+
+        let pmt = [
+            0x02, // table_id
+            0xb0, 0x12, // section_length 18
+            0x00, 0x01, // program_number 1
+            0xc1, 0x00, 0x00,
+            0xe0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, // PCR PID
+            0xf0, 0x00, // no program info
+            STREAM_TYPE_H264,
+            0xe0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8,
+            0xf0, 0x00, // no stream info
+        ];
+        let counter = next_counter(&mut self.pmt_counter);
+        write_section(out, PMT_PID, counter, &pmt);
+    }
+}
+
+fn next_counter(counter: &mut u8) -> u8 {
+    let current = *counter;
+    *counter = (current + 1) & 0x0f;
+    current
+}
+
+/// One PSI section with its CRC in a packet of its own.
+fn write_section(out: &mut Vec<u8>, pid: u16, counter: u8, section: &[u8]) {
+    let start = out.len();
+    out.extend_from_slice(&[0x47, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | counter, 0x00]);
+    out.extend_from_slice(section);
+    out.extend_from_slice(&crc32(section).to_be_bytes());
+    out.resize(start + PACKET_SIZE, 0xff);
+}
+
+/// PTS field with its 4-bit prefix and marker bits.
+fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+    [
+        prefix | ((ts >> 29) as u8 & 0x0e) | 0x01,
+        (ts >> 22) as u8,
+        ((ts >> 14) as u8 & 0xfe) | 0x01,
+        (ts >> 7) as u8,
+        ((ts << 1) as u8 & 0xfe) | 0x01,
+    ]
+}
+
+/// PCR with a zero extension.
+fn program_clock(base: u64) -> [u8; 6] {
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        (((base & 0x01) as u8) << 7) | 0x7e,
+        0x00,
+    ]
+}
+
+/// CRC-32/MPEG-2 of PSI sections.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
@@ -0,0 +1,216 @@
+use crate::processor::camera_control::jpeg_params;
+use crate::processor::catalog::Recording;
+use crate::protocol::{ErrorCode, FrameHeader, PlaybackEvent, PlaybackRequest, VideoCodec};
+use crate::r#trait::ErrorMessage;
+use opencv::{
+    core,
+    imgcodecs,
+    prelude::*,
+    videoio,
+    Result,
+};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+pub const MIN_SPEED: f64 = 0.1;
+pub const MAX_SPEED: f64 = 16.0;
+
+/// Recordings are MJPEG, frames are decoded and encoded again at this quality.
+const PLAYBACK_QUALITY: i32 = 85;
+
+/// How long a paused or finished player waits for a request before it
+/// checks whether the socket is still there.
+const IDLE_WAIT: Duration = Duration::from_millis(500);
+
+/// What the player hands to the socket.
+pub enum PlaybackOutput {
+    /// A JPEG behind its `FrameHeader`, like live frames on version 3
+    Frame(Vec<u8>),
+    Event(PlaybackEvent),
+    /// The recording cannot be read, the player has stopped
+    Failed(ErrorMessage),
+}
+
+struct Player {
+    capture: videoio::VideoCapture,
+    camera: i32,
+    /// Unix milliseconds of the first frame
+    started_ms: u64,
+    fps: f64,
+    frames: u64,
+    /// Index of the next frame read
+    position: u64,
+    paused: bool,
+    ended: bool,
+    speed: f64,
+    output: mpsc::Sender<PlaybackOutput>,
+}
+
+/// Checks a request before it reaches the player.
+pub fn validate(request: &PlaybackRequest) -> Result<(), ErrorMessage> {
+    match request {
+        PlaybackRequest::Speed { speed } if !(MIN_SPEED..=MAX_SPEED).contains(speed) => Err(ErrorMessage::new(
+            ErrorCode::BadRequest,
+            format!("speed must be between {} and {}", MIN_SPEED, MAX_SPEED),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Blocking. Plays `recording` from the start until `requests` or
+/// `output` is closed.
+pub fn run(recording: &Recording, requests: Receiver<PlaybackRequest>, output: mpsc::Sender<PlaybackOutput>) {
+    if let Err(e) = play(recording, requests, &output) {
+        eprintln!("Playback of {} failed: {}", recording.path, e);
+        let error = ErrorMessage::new(ErrorCode::Internal, format!("Cannot play recording {}: {}", recording.id, e));
+        let _ = output.blocking_send(PlaybackOutput::Failed(error));
+    }
+}
+
+fn play(recording: &Recording, requests: Receiver<PlaybackRequest>, output: &mpsc::Sender<PlaybackOutput>) -> Result<()> {
+    let capture = videoio::VideoCapture::from_file(&recording.path, videoio::CAP_ANY)?;
+    if !capture.is_opened()? {
+        return Err(opencv::Error::new(core::StsError, format!("Cannot open {}", recording.path)));
+    }
+
+    let duration_ms = recording.ended_ms.saturating_sub(recording.started_ms);
+    let fps = recording.captured_fps()
+        .unwrap_or_else(|| capture.get(videoio::CAP_PROP_FPS).unwrap_or(0.0));
+    let fps = if fps > 0.0 { fps } else { 1.0 };
+    let frames = match capture.get(videoio::CAP_PROP_FRAME_COUNT) {
+        Ok(count) if count > 0.0 => count as u64,
+        _ => recording.frames,
+    };
+
+    let mut player = Player {
+        capture,
+        camera: recording.camera,
+        started_ms: recording.started_ms,
+        fps,
+        frames,
+        position: 0,
+        paused: false,
+        ended: false,
+        speed: 1.0,
+        output: output.clone(),
+    };
+    let info = PlaybackEvent::Info {
+        id: recording.id,
+        camera: recording.camera,
+        started_ms: recording.started_ms,
+        duration_ms,
+        frames,
+        fps,
+    };
+    if !player.send(PlaybackOutput::Event(info)) {
+        return Ok(());
+    }
+
+    let mut next_frame_at = Instant::now();
+    loop {
+        let wait = if player.paused || player.ended {
+            IDLE_WAIT
+        } else {
+            next_frame_at.saturating_duration_since(Instant::now())
+        };
+        match requests.recv_timeout(wait) {
+            Ok(request) => {
+                if !player.apply(request)? {
+                    return Ok(());
+                }
+                next_frame_at = Instant::now();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) if player.paused || player.ended => {
+                if output.is_closed() {
+                    return Ok(());
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        if !player.next_frame()? {
+            return Ok(());
+        }
+        // A player that fell behind, e.g. on a slow socket, does not rush to catch up
+        let interval = Duration::from_secs_f64(1.0 / (player.fps * player.speed));
+        next_frame_at = (next_frame_at + interval).max(Instant::now());
+    }
+}
+
+impl Player {
+    /// False once the socket is gone.
+    fn send(&self, output: PlaybackOutput) -> bool {
+        self.output.blocking_send(output).is_ok()
+    }
+
+    fn position_ms(&self) -> u64 {
+        self.position_us() / 1000
+    }
+
+    fn position_us(&self) -> u64 {
+        (self.position as f64 * 1_000_000.0 / self.fps) as u64
+    }
+
+    fn apply(&mut self, request: PlaybackRequest) -> Result<bool> {
+        let mut show_frame = false;
+        match request {
+            PlaybackRequest::Seek { position_ms } => {
+                let mut frame = (position_ms as f64 * self.fps / 1000.0) as u64;
+                if self.frames > 0 {
+                    frame = frame.min(self.frames - 1);
+                }
+                self.capture.set(videoio::CAP_PROP_POS_FRAMES, frame as f64)?;
+                self.position = frame;
+                self.ended = false;
+                show_frame = self.paused;
+            }
+            PlaybackRequest::Pause => self.paused = true,
+            PlaybackRequest::Resume => self.paused = false,
+            PlaybackRequest::Speed { speed } => self.speed = speed,
+        }
+
+        let state = PlaybackEvent::State {
+            position_ms: self.position_ms(),
+            paused: self.paused,
+            speed: self.speed,
+        };
+        if !self.send(PlaybackOutput::Event(state)) {
+            return Ok(false);
+        }
+        if show_frame {
+            return self.next_frame();
+        }
+        Ok(true)
+    }
+
+    /// Sends the next frame, or `playback_ended` at the end of the file.
+    fn next_frame(&mut self) -> Result<bool> {
+        let mut frame = core::Mat::default();
+        if !self.capture.read(&mut frame)? || frame.empty() {
+            self.ended = true;
+            return Ok(self.send(PlaybackOutput::Event(PlaybackEvent::Ended {
+                position_ms: self.position_ms(),
+            })));
+        }
+        let frame_us = self.position_us();
+        self.position += 1;
+
+        let mut jpeg = core::Vector::<u8>::new();
+        imgcodecs::imencode(".jpg", &frame, &mut jpeg, &jpeg_params(PLAYBACK_QUALITY))?;
+        // Frame number and recording time stand in for the capture ones
+        let header = FrameHeader {
+            index: self.camera,
+            codec: VideoCodec::Jpeg,
+            keyframe: true,
+            skipped: 0,
+            seq: self.position,
+            timestamp_ms: self.started_ms + frame_us / 1000,
+            monotonic_us: frame_us,
+        };
+        Ok(self.send(PlaybackOutput::Frame(header.frame(jpeg.as_slice()))))
+    }
+}
@@ -0,0 +1,364 @@
+use crate::config::RecordingConfig;
+use crate::processor::camera_registry::{CameraRegistry, VideoFrame};
+use crate::processor::catalog::{Catalog, Recording, RecordingKind};
+use opencv::{
+    core,
+    imgcodecs,
+    prelude::*,
+    videoio,
+    Result,
+};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Viewer name the recorder holds cameras open with.
+const RECORDER_VIEWER: &str = "recorder";
+
+/// Wait before a camera whose capture ended is opened again.
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// How often old segments are looked for besides after every segment.
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+
+const SEGMENT_EXTENSION: &str = "avi";
+
+/// Writes cameras to disk around the clock as MJPEG AVI segments, one
+/// directory per camera. It counts as a viewer, so the captures keep
+/// running without clients, and deletes the oldest segments once the
+/// size or age limits are passed. Finished segments are added to the
+/// catalog and removed from it with their file.
+pub struct Recorder {
+    config: RecordingConfig,
+    cameras: Arc<CameraRegistry>,
+    catalog: Arc<Catalog>,
+    /// Segments being written, never deleted by retention
+    writing: Mutex<HashSet<PathBuf>>,
+}
+
+/// The open file of one camera.
+struct Segment {
+    index: i32,
+    writer: videoio::VideoWriter,
+    path: PathBuf,
+    started: Instant,
+    size: core::Size,
+    /// Capture times of the first and last frame written
+    first_ms: Option<u64>,
+    last_ms: u64,
+    frames: u64,
+}
+
+impl Recorder {
+    pub fn new(config: RecordingConfig, cameras: Arc<CameraRegistry>, catalog: Arc<Catalog>) -> Self {
+        Self {
+            config,
+            cameras,
+            catalog,
+            writing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Starts one recording thread per camera and the retention task.
+    pub fn start(self: Arc<Self>, indexes: Vec<i32>) {
+        println!(
+            "Recording camera(s) {:?} to {} in {} s segments",
+            indexes,
+            self.config.dir.display(),
+            self.config.segment_secs
+        );
+        for index in indexes {
+            let recorder = self.clone();
+            tokio::task::spawn_blocking(move || recorder.record(index));
+        }
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                let recorder = self.clone();
+                let _ = tokio::task::spawn_blocking(move || recorder.enforce_retention()).await;
+            }
+        });
+    }
+
+    fn camera_dir(&self, index: i32) -> PathBuf {
+        self.config.dir.join(format!("camera-{}", index))
+    }
+
+    /// Blocking, runs for the life of the process. A capture that ends,
+    /// e.g. because the camera was unplugged, is retried.
+    fn record(&self, index: i32) {
+        loop {
+            let mut frames = self.cameras.subscribe(index, RECORDER_VIEWER);
+            let mut segment = None;
+            loop {
+                match frames.blocking_recv() {
+                    Ok(frame) => {
+                        if let Err(e) = self.write(index, &frame, &mut segment) {
+                            eprintln!("Recording camera {} failed: {}", index, e);
+                            self.finish(&mut segment);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        println!("Recorder fell behind on camera {}, {} frame(s) not recorded", index, skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            self.finish(&mut segment);
+
+            println!("Capture of camera {} ended, recording again in {} s", index, RETRY_DELAY.as_secs());
+            std::thread::sleep(RETRY_DELAY);
+        }
+    }
+
+    fn write(&self, index: i32, frame: &VideoFrame, segment: &mut Option<Segment>) -> Result<()> {
+        let image = imgcodecs::imdecode(&core::Vector::<u8>::from_slice(&frame.jpeg), imgcodecs::IMREAD_COLOR)?;
+        if image.empty() {
+            return Ok(());
+        }
+        let size = image.size()?;
+
+        let rotate = segment.as_ref().is_none_or(|current| {
+            current.started.elapsed().as_secs() >= self.config.segment_secs || current.size != size
+        });
+        if rotate {
+            self.finish(segment);
+            *segment = Some(self.open(index, size)?);
+        }
+        if let Some(current) = segment.as_mut() {
+            current.writer.write(&image)?;
+            current.first_ms.get_or_insert(frame.timestamp_ms);
+            current.last_ms = frame.timestamp_ms;
+            current.frames += 1;
+        }
+        Ok(())
+    }
+
+    /// Starts a segment named after its UTC start time. The frame rate is
+    /// what the camera delivers, or what it was asked for before it reports.
+    fn open(&self, index: i32, size: core::Size) -> Result<Segment> {
+        let dir = self.camera_dir(index);
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            opencv::Error::new(core::StsError, format!("Cannot create {}: {}", dir.display(), e))
+        })?;
+        let path = segment_path(&dir, SystemTime::now());
+
+        let settings = self.cameras.settings(index);
+        let fps = settings.applied.map_or(settings.requested.fps, |applied| applied.fps);
+        let writer = videoio::VideoWriter::new(
+            &path.to_string_lossy(),
+            videoio::VideoWriter::fourcc('M', 'J', 'P', 'G')?,
+            fps,
+            size,
+            true,
+        )?;
+        if !writer.is_opened()? {
+            return Err(opencv::Error::new(core::StsError, format!("Cannot write {}", path.display())));
+        }
+
+        println!("Recording camera {} to {}", index, path.display());
+        self.writing.lock().unwrap().insert(path.clone());
+        Ok(Segment {
+            index,
+            writer,
+            path,
+            started: Instant::now(),
+            size,
+            first_ms: None,
+            last_ms: 0,
+            frames: 0,
+        })
+    }
+
+    fn finish(&self, segment: &mut Option<Segment>) {
+        let Some(mut finished) = segment.take() else {
+            return;
+        };
+        if let Err(e) = finished.writer.release() {
+            eprintln!("Failed to finish {}: {}", finished.path.display(), e);
+        }
+        self.writing.lock().unwrap().remove(&finished.path);
+        let Some(first_ms) = finished.first_ms else {
+            // Nothing was written, e.g. the first frame failed
+            match std::fs::remove_file(&finished.path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("Failed to delete empty {}: {}", finished.path.display(), e),
+            }
+            return;
+        };
+        let recording = Recording {
+            id: 0,
+            kind: RecordingKind::Segment,
+            camera: finished.index,
+            path: finished.path.to_string_lossy().into_owned(),
+            started_ms: first_ms,
+            ended_ms: finished.last_ms,
+            size_bytes: std::fs::metadata(&finished.path).map_or(0, |metadata| metadata.len()),
+            frames: finished.frames,
+            trigger: None,
+            trigger_id: None,
+            thumbnail: None,
+        };
+        if let Err(e) = self.catalog.add(recording) {
+            eprintln!("{}", e);
+        }
+        self.enforce_retention();
+    }
+
+    /// Deletes finished segments, oldest first, that are older than
+    /// `max_age_hours` or push the total over `max_total_mb`.
+    fn enforce_retention(&self) {
+        let mut segments = list_segments(&self.config.dir);
+        segments.sort_by_key(|segment| segment.modified);
+        let mut total: u64 = segments.iter().map(|segment| segment.bytes).sum();
+
+        let max_total = self.config.max_total_mb * 1024 * 1024;
+        let max_age = Duration::from_secs(self.config.max_age_hours * 3600);
+        let writing = self.writing.lock().unwrap().clone();
+        for segment in segments {
+            let too_old = max_age > Duration::ZERO
+                && segment.modified.elapsed().is_ok_and(|age| age > max_age);
+            let too_big = max_total > 0 && total > max_total;
+            if !too_old && !too_big {
+                break;
+            }
+            if writing.contains(&segment.path) {
+                continue;
+            }
+            match std::fs::remove_file(&segment.path) {
+                Ok(()) => {
+                    println!("Deleted recording {}", segment.path.display());
+                    total -= segment.bytes;
+                    self.forget(&segment.path);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    total -= segment.bytes;
+                    self.forget(&segment.path);
+                }
+                Err(e) => eprintln!("Failed to delete {}: {}", segment.path.display(), e),
+            }
+        }
+    }
+
+    fn forget(&self, path: &Path) {
+        if let Err(e) = self.catalog.remove_path(&path.to_string_lossy()) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+struct SegmentFile {
+    path: PathBuf,
+    modified: SystemTime,
+    bytes: u64,
+}
+
+/// Segments of every camera under `dir`.
+fn list_segments(dir: &Path) -> Vec<SegmentFile> {
+    let camera_dirs = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    camera_dirs
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("camera-"))
+        .filter_map(|entry| std::fs::read_dir(entry.path()).ok())
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != SEGMENT_EXTENSION) {
+                return None;
+            }
+            let metadata = entry.metadata().ok()?;
+            Some(SegmentFile {
+                path,
+                modified: metadata.modified().ok()?,
+                bytes: metadata.len(),
+            })
+        })
+        .collect()
+}
+
+/// `20260131-235959-042.avi` in `dir`, with the milliseconds so that
+/// quick rotations, e.g. on a resolution change, get a file each. A
+/// counter is added if the name is taken anyway.
+fn segment_path(dir: &Path, time: SystemTime) -> PathBuf {
+    let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().subsec_millis();
+    let name = format!("{}-{:03}", utc_timestamp(time), millis);
+    let mut path = dir.join(format!("{}.{}", name, SEGMENT_EXTENSION));
+    let mut counter = 1;
+    while path.exists() {
+        path = dir.join(format!("{}-{}.{}", name, counter, SEGMENT_EXTENSION));
+        counter += 1;
+    }
+    path
+}
+
+/// `20260131-235959`, sorts in time order.
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Civil date from days since 1970-01-01, proleptic Gregorian calendar
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        (rest / 60) % 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn formats_the_epoch() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "19700101-000000");
+    }
+
+    #[test]
+    fn formats_dates_and_times() {
+        assert_eq!(utc_timestamp(at(1_767_225_600)), "20260101-000000");
+        assert_eq!(utc_timestamp(at(946_684_799)), "19991231-235959");
+        assert_eq!(utc_timestamp(at(1_709_210_096)), "20240229-123456");
+        assert_eq!(utc_timestamp(at(951_782_400)), "20000229-000000");
+    }
+
+    #[test]
+    fn ignores_fractions_of_a_second() {
+        assert_eq!(utc_timestamp(at(1_767_225_600) + Duration::from_millis(999)), "20260101-000000");
+    }
+
+    #[test]
+    fn sorts_in_time_order() {
+        let times = [at(946_684_799), at(946_684_800), at(1_709_210_096), at(1_767_225_600)];
+        let names: Vec<String> = times.iter().map(|&time| utc_timestamp(time)).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::processor::camera_control::jpeg_params;
+use crate::processor::capture_settings;
+use opencv::{
+    core,
+    imgcodecs,
+    imgproc,
+    prelude::*,
+    Result,
+};
+use serde::Deserialize;
+
+/// Optional size and quality of a snapshot. Without any of them the
+/// captured JPEG is returned as it is.
+#[derive(Debug, Default, Deserialize)]
+pub struct SnapshotOptions {
+    /// With only one of `width` and `height` set, the other keeps the
+    /// aspect ratio
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// JPEG encode quality, 1 to 100
+    pub quality: Option<i32>,
+}
+
+impl SnapshotOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        match capture_settings::validate(self.width, self.height, None, self.quality, None).into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.width.is_none() && self.height.is_none() && self.quality.is_none()
+    }
+}
+
+/// Blocking. Scales and encodes a captured frame as asked.
+pub fn render(jpeg: &[u8], options: &SnapshotOptions) -> Result<Vec<u8>> {
+    if options.is_empty() {
+        return Ok(jpeg.to_vec());
+    }
+    let image = imgcodecs::imdecode(&core::Vector::<u8>::from_slice(jpeg), imgcodecs::IMREAD_COLOR)?;
+    let size = image.size()?;
+    let target = match (options.width, options.height) {
+        (Some(width), Some(height)) => core::Size::new(width, height),
+        (Some(width), None) => core::Size::new(width, scaled(size.height, width, size.width)),
+        (None, Some(height)) => core::Size::new(scaled(size.width, height, size.height), height),
+        (None, None) => size,
+    };
+    let image = if target == size {
+        image
+    } else {
+        let mut resized = core::Mat::default();
+        imgproc::resize(&image, &mut resized, target, 0.0, 0.0, imgproc::INTER_AREA)?;
+        resized
+    };
+
+    let params = jpeg_params(options.quality.unwrap_or(capture_settings::DEFAULT_QUALITY));
+    let mut buffer = core::Vector::<u8>::new();
+    imgcodecs::imencode(".jpg", &image, &mut buffer, &params)?;
+    Ok(buffer.to_vec())
+}
+
+/// `length * numerator / denominator`, at least one pixel.
+fn scaled(length: i32, numerator: i32, denominator: i32) -> i32 {
+    ((length as i64 * numerator as i64) / denominator.max(1) as i64).max(1) as i32
+}
@@ -0,0 +1,429 @@
+use crate::config::WebRtcConfig;
+use crate::processor::audio_source::AudioFormat;
+use crate::processor::h264::{ChunkReceiver, H264Encoders};
+use crate::processor::microphone::{AudioSubscription, Microphone};
+use crate::protocol::PeerState;
+use bytes::Bytes;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS};
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::api::{APIBuilder, API};
+use webrtc::ice::network_type::NetworkType;
+use webrtc::ice::udp_network::{EphemeralUDP, UDPNetwork};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::interceptor::registry::Registry;
+use webrtc::media::Sample;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+/// Opus runs at 48 kHz whatever the microphone delivers.
+const OPUS_RATE: u32 = 48_000;
+
+/// Samples per channel in one 20 ms Opus frame.
+const OPUS_FRAME: usize = 960;
+
+/// Largest encoded Opus frame.
+const OPUS_MAX_PACKET: usize = 4000;
+
+/// Duration of a video sample before the second frame tells the real one.
+const FIRST_FRAME_DURATION: Duration = Duration::from_millis(33);
+
+/// Answers WebRTC offers from `/ws` clients with the server as the other
+/// peer. Only host candidates are gathered and no STUN or TURN server is
+/// used, so peers must reach the server directly, e.g. on the same LAN.
+pub struct WebRtcPeers {
+    config: WebRtcConfig,
+    api: API,
+    h264: Arc<H264Encoders>,
+    microphone: Arc<Microphone>,
+}
+
+impl WebRtcPeers {
+    pub fn new(
+        config: WebRtcConfig,
+        h264: Arc<H264Encoders>,
+        microphone: Arc<Microphone>,
+    ) -> Result<Self, String> {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs()
+            .map_err(|e| format!("Failed to register WebRTC codecs: {}", e))?;
+        let interceptors = register_default_interceptors(Registry::new(), &mut media_engine)
+            .map_err(|e| format!("Failed to register WebRTC interceptors: {}", e))?;
+
+        let mut settings = SettingEngine::default();
+        settings.set_network_types(vec![NetworkType::Udp4, NetworkType::Udp6]);
+        if config.udp_port_min != 0 {
+            let ports = EphemeralUDP::new(config.udp_port_min, config.udp_port_max)
+                .map_err(|e| format!("Invalid WebRTC port range: {}", e))?;
+            settings.set_udp_network(UDPNetwork::Ephemeral(ports));
+        }
+
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(interceptors)
+            .with_setting_engine(settings)
+            .build();
+        Ok(Self {
+            config,
+            api,
+            h264,
+            microphone,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Creates a peer for `offer` sending camera `index` as H.264 and,
+    /// with `audio`, the microphone as Opus. It takes candidates right
+    /// away; `PeerSession::answer` makes the SDP answer. `on_state` gets
+    /// connection changes.
+    pub async fn open(
+        &self,
+        index: i32,
+        offer: String,
+        audio: bool,
+        on_state: impl Fn(PeerState) + Send + Sync + 'static,
+    ) -> Result<PeerSession, String> {
+        let connection = Arc::new(self.api.new_peer_connection(RTCConfiguration::default()).await
+            .map_err(|e| format!("Failed to create peer connection: {}", e))?);
+        let viewer = format!("webrtc-{}", uuid::Uuid::new_v4());
+        let (closed_tx, closed) = watch::channel(false);
+        let closed_tx = Arc::new(closed_tx);
+
+        let video = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_string(),
+                clock_rate: 90_000,
+                // openh264 writes Constrained Baseline
+                sdp_fmtp_line: "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f".to_string(),
+                ..Default::default()
+            },
+            "video".to_string(),
+            format!("camera-{}", index),
+        ));
+        let video_sender = add_track(&connection, video.clone()).await?;
+        let audio_track = if audio {
+            let track = Arc::new(TrackLocalStaticSample::new(
+                RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_OPUS.to_string(),
+                    clock_rate: OPUS_RATE,
+                    channels: 2,
+                    sdp_fmtp_line: "minptime=10;useinbandfec=1".to_string(),
+                    ..Default::default()
+                },
+                "audio".to_string(),
+                format!("camera-{}", index),
+            ));
+            let sender = add_track(&connection, track.clone()).await?;
+            tokio::spawn(drain_rtcp(sender, None));
+            Some(track)
+        } else {
+            None
+        };
+
+        // A failed or closed connection leaves the camera right away, not
+        // only when the socket goes
+        let h264 = self.h264.clone();
+        let state_viewer = viewer.clone();
+        let state_closed = closed_tx.clone();
+        connection.on_peer_connection_state_change(Box::new(move |state| {
+            println!("WebRTC peer {} on camera {} is {}", state_viewer, index, state);
+            let peer_state = match state {
+                RTCPeerConnectionState::Connecting => Some(PeerState::Connecting),
+                RTCPeerConnectionState::Connected => Some(PeerState::Connected),
+                RTCPeerConnectionState::Disconnected => Some(PeerState::Disconnected),
+                RTCPeerConnectionState::Failed => Some(PeerState::Failed),
+                RTCPeerConnectionState::Closed => Some(PeerState::Closed),
+                _ => None,
+            };
+            if matches!(peer_state, Some(PeerState::Failed | PeerState::Closed)) {
+                state_closed.send_replace(true);
+                h264.unsubscribe(index, &state_viewer);
+            }
+            if let Some(peer_state) = peer_state {
+                on_state(peer_state);
+            }
+            Box::pin(async {})
+        }));
+
+        // After the state handler, so no change during negotiation is missed
+        if let Err(e) = set_offer(&connection, offer).await {
+            let _ = connection.close().await;
+            return Err(e);
+        }
+
+        let chunks = self.h264.subscribe(index, &viewer);
+        tokio::spawn(drain_rtcp(video_sender, Some((self.h264.clone(), index))));
+        tokio::spawn(send_video(
+            video,
+            chunks,
+            self.h264.clone(),
+            index,
+            connection.clone(),
+            closed.clone(),
+        ));
+        if let Some(track) = audio_track {
+            match self.microphone.subscription(viewer.clone()) {
+                Ok(audio) => {
+                    let format = self.microphone.format();
+                    let closed = closed.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = send_audio(track, audio, format, closed).await {
+                            eprintln!("WebRTC audio of camera {} failed: {}", index, e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("WebRTC audio of camera {} failed: {}", index, e),
+            }
+        }
+
+        println!("Opened WebRTC peer {} for camera {}{}", viewer, index, if audio { " with audio" } else { "" });
+        Ok(PeerSession {
+            index,
+            viewer,
+            connection,
+            h264: self.h264.clone(),
+            closed: closed_tx,
+        })
+    }
+}
+
+async fn set_offer(connection: &RTCPeerConnection, offer: String) -> Result<(), String> {
+    let offer = RTCSessionDescription::offer(offer).map_err(|e| format!("Invalid offer: {}", e))?;
+    connection.set_remote_description(offer).await
+        .map_err(|e| format!("Cannot answer offer: {}", e))
+}
+
+/// One answered offer. Closing it, or the connection failing, stops its
+/// tracks and leaves the camera's encoder.
+pub struct PeerSession {
+    pub index: i32,
+    viewer: String,
+    connection: Arc<RTCPeerConnection>,
+    h264: Arc<H264Encoders>,
+    closed: Arc<watch::Sender<bool>>,
+}
+
+impl PeerSession {
+    /// The SDP answer, once candidate gathering is done, so it carries
+    /// every local candidate and the client needs no trickled ones from
+    /// the server. Gathering can take a while, the future owns what it
+    /// needs to run in a task of its own. A failed answer closes the peer.
+    pub fn answer(&self) -> impl Future<Output = Result<String, String>> + Send + 'static {
+        let connection = self.connection.clone();
+        let viewer = self.viewer.clone();
+        let mut closed = self.closed.subscribe();
+        async move {
+            let negotiated = async {
+                let answer = connection.create_answer(None).await?;
+                let mut gathered = connection.gathering_complete_promise().await;
+                connection.set_local_description(answer).await?;
+                let _ = gathered.recv().await;
+                Ok::<_, webrtc::Error>(connection.local_description().await)
+            };
+            let result = tokio::select! {
+                _ = closed.wait_for(|closed| *closed) => return Err("WebRTC session closed before answering".to_string()),
+                result = negotiated => result,
+            };
+            let error = match result {
+                Ok(Some(answer)) => {
+                    println!("Answered WebRTC offer as {}", viewer);
+                    return Ok(answer.sdp);
+                }
+                Ok(None) => "No local description after gathering".to_string(),
+                Err(e) => format!("Cannot answer offer: {}", e),
+            };
+            let _ = connection.close().await;
+            Err(error)
+        }
+    }
+
+    /// A candidate trickled by the client. The empty end-of-candidates
+    /// marker is ignored.
+    pub async fn add_candidate(&self, candidate: RTCIceCandidateInit) -> Result<(), String> {
+        if candidate.candidate.is_empty() {
+            return Ok(());
+        }
+        self.connection.add_ice_candidate(candidate).await
+            .map_err(|e| format!("Invalid ICE candidate: {}", e))
+    }
+
+    pub async fn close(self) {
+        self.closed.send_replace(true);
+        self.h264.unsubscribe(self.index, &self.viewer);
+        if let Err(e) = self.connection.close().await {
+            eprintln!("Failed to close WebRTC peer {}: {}", self.viewer, e);
+        }
+    }
+}
+
+async fn add_track(connection: &RTCPeerConnection, track: Arc<TrackLocalStaticSample>) -> Result<Arc<RTCRtpSender>, String> {
+    connection.add_track(track as Arc<dyn TrackLocal + Send + Sync>).await
+        .map_err(|e| format!("Failed to add track: {}", e))
+}
+
+/// RTCP has to be read for the interceptors to work. A picture loss
+/// report from the browser asks the encoder for a keyframe.
+async fn drain_rtcp(sender: Arc<RTCRtpSender>, keyframes: Option<(Arc<H264Encoders>, i32)>) {
+    while let Ok((packets, _)) = sender.read_rtcp().await {
+        if let Some((h264, index)) = &keyframes {
+            if packets.iter().any(|packet| packet.as_any().downcast_ref::<PictureLossIndication>().is_some()) {
+                h264.request_keyframe(*index);
+            }
+        }
+    }
+}
+
+/// Writes the camera's access units to the video track, starting at a
+/// keyframe and again after a gap. Closes the connection when the
+/// capture ends.
+async fn send_video(
+    track: Arc<TrackLocalStaticSample>,
+    mut chunks: ChunkReceiver,
+    h264: Arc<H264Encoders>,
+    index: i32,
+    connection: Arc<RTCPeerConnection>,
+    mut closed: watch::Receiver<bool>,
+) {
+    let mut need_keyframe = true;
+    let mut last_timestamp: Option<u64> = None;
+    loop {
+        let chunk = tokio::select! {
+            _ = closed.wait_for(|closed| *closed) => return,
+            chunk = chunks.recv() => chunk,
+        };
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(RecvError::Lagged(_)) => {
+                need_keyframe = true;
+                h264.request_keyframe(index);
+                continue;
+            }
+            Err(RecvError::Closed) => {
+                println!("Camera {} stopped, closing its WebRTC peer", index);
+                let _ = connection.close().await;
+                return;
+            }
+        };
+        if need_keyframe && !chunk.keyframe {
+            continue;
+        }
+        need_keyframe = false;
+
+        let duration = last_timestamp
+            .map_or(FIRST_FRAME_DURATION, |last| Duration::from_millis(chunk.timestamp_ms.saturating_sub(last)));
+        last_timestamp = Some(chunk.timestamp_ms);
+        let sample = Sample {
+            data: Bytes::copy_from_slice(&chunk.data),
+            duration,
+            ..Default::default()
+        };
+        if let Err(e) = track.write_sample(&sample).await {
+            eprintln!("Failed to write video sample of camera {}: {}", index, e);
+        }
+    }
+}
+
+/// Resamples the shared microphone capture to 48 kHz stereo and writes
+/// 20 ms Opus frames to the audio track until the peer closes. Leaves
+/// the microphone when done.
+async fn send_audio(
+    track: Arc<TrackLocalStaticSample>,
+    mut audio: AudioSubscription,
+    format: AudioFormat,
+    mut closed: watch::Receiver<bool>,
+) -> Result<(), String> {
+    let mut encoder = opus::Encoder::new(OPUS_RATE, opus::Channels::Stereo, opus::Application::Voip)
+        .map_err(|e| format!("Failed to create Opus encoder: {}", e))?;
+
+    let mut resampler = Resampler::new(format.sample_rate);
+    let mut pending: Vec<i16> = Vec::new();
+    let frame_duration = Duration::from_millis(20);
+    loop {
+        let chunk = tokio::select! {
+            _ = closed.wait_for(|closed| *closed) => return Ok(()),
+            chunk = audio.chunks.recv() => chunk,
+        };
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let frames: Vec<[f32; 2]> = chunk
+            .chunks_exact(2 * format.channels as usize)
+            .map(|frame| {
+                let left = i16::from_le_bytes([frame[0], frame[1]]) as f32;
+                let right = if format.channels > 1 { i16::from_le_bytes([frame[2], frame[3]]) as f32 } else { left };
+                [left, right]
+            })
+            .collect();
+        resampler.process(&frames, &mut pending);
+
+        while pending.len() >= OPUS_FRAME * 2 {
+            let packet = encoder.encode_vec(&pending[..OPUS_FRAME * 2], OPUS_MAX_PACKET)
+                .map_err(|e| format!("Opus encoding failed: {}", e))?;
+            pending.drain(..OPUS_FRAME * 2);
+            let sample = Sample {
+                data: Bytes::from(packet),
+                duration: frame_duration,
+                ..Default::default()
+            };
+            if let Err(e) = track.write_sample(&sample).await {
+                eprintln!("Failed to write audio sample: {}", e);
+            }
+        }
+    }
+}
+
+/// Linear interpolation from the microphone rate to 48 kHz, carried over
+/// chunk boundaries.
+struct Resampler {
+    /// Input frames per output frame
+    step: f64,
+    /// Next output position, relative to `last`
+    position: f64,
+    last: Option<[f32; 2]>,
+}
+
+impl Resampler {
+    fn new(input_rate: u32) -> Self {
+        Self {
+            step: input_rate as f64 / OPUS_RATE as f64,
+            position: 0.0,
+            last: None,
+        }
+    }
+
+    /// Appends interleaved stereo samples to `out`.
+    fn process(&mut self, input: &[[f32; 2]], out: &mut Vec<i16>) {
+        let frames: Vec<[f32; 2]> = self.last.into_iter().chain(input.iter().copied()).collect();
+        if frames.is_empty() {
+            return;
+        }
+        while self.position + 1.0 < frames.len() as f64 {
+            let i = self.position as usize;
+            let fraction = (self.position - i as f64) as f32;
+            for channel in 0..2 {
+                let (a, b) = (frames[i][channel], frames[i + 1][channel]);
+                out.push((a + (b - a) * fraction) as i16);
+            }
+            self.position += self.step;
+        }
+        self.position -= (frames.len() - 1) as f64;
+        self.last = frames.last().copied();
+    }
+}
@@ -0,0 +1,586 @@
+use crate::auth::permissions::Role;
+use crate::processor::camera_inventory::{CameraDevice, InventoryEvent};
+use crate::processor::camera_registry::{CameraControls, CameraSettings};
+use crate::processor::capture_settings::CaptureSettingsUpdate;
+use crate::processor::event_log::Event;
+use crate::processor::image_controls::ImageControls;
+use crate::r#trait::ErrorMessage;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Newest `/ws` protocol version, negotiated with a `hello` message.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// First version whose binary frames start with a `FrameHeader`.
+pub const FRAME_HEADER_VERSION: u32 = 3;
+
+/// Clients that never send `hello` speak version 1: camera status as plain
+/// text and errors as JSON.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Every `code` an error message can carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Missing or wrong credentials, or an invalid session token
+    Unauthorized,
+    TokenExpired,
+    TokenRevoked,
+    /// The user's role does not allow `action`
+    Forbidden,
+    /// Too many failed logins, see `retry_after_secs`
+    LockedOut,
+    BadRequest,
+    NotFound,
+    Conflict,
+    Internal,
+    /// The text is not a valid message of the negotiated protocol
+    InvalidMessage,
+    /// An unknown command on the audio socket
+    InvalidAction,
+    /// A negative or malformed camera index
+    InvalidCamera,
+    /// A watched camera stopped capturing, e.g. because it was unplugged
+    CameraStopped,
+    /// The `hello` version is older than the server supports
+    UnsupportedVersion,
+    /// The device refused new capture settings or image controls, the
+    /// previous ones stay
+    SettingsRejected,
+}
+
+impl ErrorCode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::TokenExpired => "token_expired",
+            ErrorCode::TokenRevoked => "token_revoked",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::LockedOut => "locked_out",
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::Internal => "internal",
+            ErrorCode::InvalidMessage => "invalid_message",
+            ErrorCode::InvalidAction => "invalid_action",
+            ErrorCode::InvalidCamera => "invalid_camera",
+            ErrorCode::CameraStopped => "camera_stopped",
+            ErrorCode::UnsupportedVersion => "unsupported_version",
+            ErrorCode::SettingsRejected => "settings_rejected",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Text messages a client sends on `/ws`. Typed messages carry a `type`,
+/// signaling messages an `event`.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ClientMessage {
+    Request(ClientRequest),
+    Signal(SignalMessage),
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientRequest {
+    /// Picks the protocol version. May carry `Basic` credentials on sockets
+    /// opened without a session token.
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        authorization: Option<String>,
+    },
+    /// Binary frames of a camera. From version 3 on several cameras may
+    /// be watched at once, earlier versions switch to the new camera.
+    /// `on` with another codec for a watched camera switches, `off`
+    /// without an index stops every camera.
+    Control {
+        action: ControlAction,
+        #[serde(default)]
+        index: Option<i32>,
+        /// Codec of the binary frames, JPEG unless set
+        #[serde(default)]
+        codec: VideoCodec,
+    },
+    /// Changes resolution, frame rate, JPEG quality or pixel format of a
+    /// camera. Without `settings` it only reports the current ones.
+    CameraSettings {
+        index: i32,
+        #[serde(default)]
+        settings: CaptureSettingsUpdate,
+    },
+    /// Saves a clip of the camera from pre-roll through post-roll, as if
+    /// motion had started. Confirmed by the `manual_trigger` event.
+    TriggerClip {
+        index: i32,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Changes brightness, exposure, focus and the other image controls
+    /// of a camera. Without `controls` it only reports the current ones.
+    CameraControls {
+        index: i32,
+        #[serde(default)]
+        controls: ImageControls,
+    },
+    /// Offers a WebRTC session to the server, which answers with an H.264
+    /// track of the camera and, with `audio`, an Opus track of the
+    /// microphone. Replaces the client's previous session.
+    WebrtcOffer {
+        index: i32,
+        sdp: String,
+        #[serde(default)]
+        audio: bool,
+    },
+    /// A trickled ICE candidate of the client, as in `RTCIceCandidateInit`
+    WebrtcCandidate {
+        candidate: String,
+        #[serde(default)]
+        sdp_mid: Option<String>,
+        #[serde(default)]
+        sdp_mline_index: Option<u16>,
+    },
+    /// Ends the client's WebRTC session
+    WebrtcClose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlAction {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    /// One standalone JPEG per binary message
+    #[default]
+    Jpeg,
+    /// One Annex-B access unit per binary message, starting at a keyframe
+    /// with SPS and PPS. Only when `h264.enabled` is set.
+    H264,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Signal {
+    #[serde(default)]
+    pub data: String,
+    #[serde(default)]
+    pub room: String,
+    #[serde(default)]
+    pub from: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+}
+
+/// WebRTC signaling, chat and base64 camera frames. Clients send the
+/// first seven events, the server relays them and adds the rest.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event")]
+pub enum SignalMessage {
+    /// Enters the room as `from`
+    #[serde(rename = "join")]
+    Join(Signal),
+    /// Chat text in `data`, sent to everybody
+    #[serde(rename = "message")]
+    Message(Signal),
+    #[serde(rename = "offer")]
+    Offer(Signal),
+    #[serde(rename = "answer")]
+    Answer(Signal),
+    #[serde(rename = "ice-candidate")]
+    IceCandidate(Signal),
+    /// Base64 frames of the camera index in `data`, empty for the default camera
+    #[serde(rename = "start-camera")]
+    StartCamera(Signal),
+    /// Stops the camera index in `data`, empty for all of them
+    #[serde(rename = "stop-camera")]
+    StopCamera(Signal),
+    /// Server only: peer `data` joined
+    #[serde(rename = "user_joined")]
+    UserJoined(Signal),
+    /// Server only: peer `data` left
+    #[serde(rename = "user_left")]
+    UserLeft(Signal),
+    /// Server only: base64 JPEG in `data`, `from` is `camera-<index>`
+    #[serde(rename = "camera-frame")]
+    CameraFrame(Signal),
+}
+
+impl SignalMessage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SignalMessage::Join(_) => "join",
+            SignalMessage::Message(_) => "message",
+            SignalMessage::Offer(_) => "offer",
+            SignalMessage::Answer(_) => "answer",
+            SignalMessage::IceCandidate(_) => "ice-candidate",
+            SignalMessage::StartCamera(_) => "start-camera",
+            SignalMessage::StopCamera(_) => "stop-camera",
+            SignalMessage::UserJoined(_) => "user_joined",
+            SignalMessage::UserLeft(_) => "user_left",
+            SignalMessage::CameraFrame(_) => "camera-frame",
+        }
+    }
+
+    pub fn signal(&self) -> &Signal {
+        match self {
+            SignalMessage::Join(signal)
+            | SignalMessage::Message(signal)
+            | SignalMessage::Offer(signal)
+            | SignalMessage::Answer(signal)
+            | SignalMessage::IceCandidate(signal)
+            | SignalMessage::StartCamera(signal)
+            | SignalMessage::StopCamera(signal)
+            | SignalMessage::UserJoined(signal)
+            | SignalMessage::UserLeft(signal)
+            | SignalMessage::CameraFrame(signal) => signal,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Text messages the server sends on `/ws`. Camera frames requested with
+/// `control` arrive as binary JPEG or H.264 messages, behind a
+/// `FrameHeader` from version 3 on, and are not described here.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub enum ServerMessage {
+    Event(ServerEvent),
+    Error(ErrorMessage),
+    Signal(SignalMessage),
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// Sent once authenticated and in reply to every `hello`
+    Welcome {
+        protocol_version: u32,
+        min_protocol_version: u32,
+        max_protocol_version: u32,
+        client_id: String,
+        username: String,
+        role: Role,
+    },
+    /// Version 2 and later, version 1 clients get the status as plain text
+    CameraStatus {
+        index: i32,
+        status: StreamStatus,
+    },
+    /// Reply to `camera_settings`
+    CameraSettings(CameraSettings),
+    /// Reply to `camera_controls`
+    CameraControls(CameraControls),
+    /// Version 2 and later: a camera was plugged in
+    CameraAdded(CameraDevice),
+    /// Version 2 and later: a camera was unplugged, viewers also get `camera_stopped`
+    CameraRemoved(CameraDevice),
+    /// Reply to `webrtc_offer`, with every server candidate in `sdp`
+    WebrtcAnswer {
+        index: i32,
+        sdp: String,
+    },
+    /// The WebRTC session changed state; `failed` and `closed` end it
+    WebrtcState {
+        index: i32,
+        state: PeerState,
+    },
+    /// Version 3 and later: `skipped` frames of the camera were not sent,
+    /// because the client fell behind or the encoder dropped them. Sent
+    /// right before frame `seq`, the first after the gap; H.264 resumes
+    /// at a keyframe.
+    FramesSkipped {
+        index: i32,
+        skipped: u64,
+        seq: u64,
+    },
+    /// Version 2 and later: motion, loud sounds and saved clips of any
+    /// camera or the microphone, as kept by `GET /events`. `type` is
+    /// the event's own.
+    #[serde(untagged)]
+    CameraEvent(Event),
+}
+
+impl From<InventoryEvent> for ServerEvent {
+    fn from(event: InventoryEvent) -> Self {
+        match event {
+            InventoryEvent::Added(device) => ServerEvent::CameraAdded(device),
+            InventoryEvent::Removed(device) => ServerEvent::CameraRemoved(device),
+        }
+    }
+}
+
+impl From<Event> for ServerEvent {
+    fn from(event: Event) -> Self {
+        ServerEvent::CameraEvent(event)
+    }
+}
+
+impl ServerEvent {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamStatus {
+    /// This client started the capture
+    Started,
+    /// The capture was already running for someone else
+    Joined,
+    /// This client was the last viewer, the capture stopped
+    Stopped,
+    /// This client left, others are still watching
+    Left,
+}
+
+impl StreamStatus {
+    /// The plain text version 1 clients expect
+    pub fn legacy_text(self) -> &'static str {
+        match self {
+            StreamStatus::Started => "Video stream started",
+            StreamStatus::Joined => "Joined existing stream",
+            StreamStatus::Stopped => "Eyes turned off",
+            StreamStatus::Left => "Stopped viewing. Other clients are still viewing.",
+        }
+    }
+}
+
+/// Start of every binary frame on protocol version 3 and later, all
+/// numbers big-endian, followed by the JPEG or H.264 payload:
+///
+/// | Offset | Size | Field |
+/// |--------|------|-------|
+/// | 0 | 2 | magic `MF` |
+/// | 2 | 1 | header version, 1 |
+/// | 3 | 1 | header length in bytes, 40; skip to it for the payload |
+/// | 4 | 1 | codec, 0 JPEG, 1 H.264 |
+/// | 5 | 1 | flags, bit 0 keyframe (always set for JPEG) |
+/// | 6 | 2 | reserved, 0 |
+/// | 8 | 4 | camera index, signed |
+/// | 12 | 4 | frames skipped right before this one |
+/// | 16 | 8 | sequence number, from 1 for every capture run |
+/// | 24 | 8 | capture time, Unix milliseconds |
+/// | 32 | 8 | capture time, monotonic microseconds |
+#[derive(Debug, Clone, Copy)]
+pub struct FrameHeader {
+    pub index: i32,
+    pub codec: VideoCodec,
+    pub keyframe: bool,
+    pub skipped: u32,
+    pub seq: u64,
+    pub timestamp_ms: u64,
+    pub monotonic_us: u64,
+}
+
+impl FrameHeader {
+    pub const MAGIC: [u8; 2] = *b"MF";
+    pub const VERSION: u8 = 1;
+    pub const LEN: usize = 40;
+
+    /// The header followed by `payload`, one binary message.
+    pub fn frame(&self, payload: &[u8]) -> Vec<u8> {
+        let codec = match self.codec {
+            VideoCodec::Jpeg => 0,
+            VideoCodec::H264 => 1,
+        };
+        let mut frame = Vec::with_capacity(Self::LEN + payload.len());
+        frame.extend_from_slice(&Self::MAGIC);
+        frame.extend_from_slice(&[Self::VERSION, Self::LEN as u8, codec, self.keyframe as u8, 0, 0]);
+        frame.extend_from_slice(&self.index.to_be_bytes());
+        frame.extend_from_slice(&self.skipped.to_be_bytes());
+        frame.extend_from_slice(&self.seq.to_be_bytes());
+        frame.extend_from_slice(&self.timestamp_ms.to_be_bytes());
+        frame.extend_from_slice(&self.monotonic_us.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerState {
+    Connecting,
+    Connected,
+    /// Connectivity was lost and may come back
+    Disconnected,
+    Failed,
+    Closed,
+}
+
+/// Text messages a client sends on `/recordings/<id>/play`. Frames
+/// arrive as binary JPEG messages behind a `FrameHeader`, with the
+/// frame number as `seq` and the position in the recording as
+/// monotonic time.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlaybackRequest {
+    /// Jumps to `position_ms` from the start of the recording. A paused
+    /// player sends the frame found there and stays paused.
+    Seek { position_ms: u64 },
+    Pause,
+    Resume,
+    /// Playback rate, 1.0 is real time, from 0.1 to 16
+    Speed { speed: f64 },
+}
+
+/// Text messages the server sends on `/recordings/<id>/play`, besides
+/// `error` messages for bad requests and unreadable recordings.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlaybackEvent {
+    /// Sent once the recording is open, before the first frame
+    #[serde(rename = "playback_info")]
+    Info {
+        id: i64,
+        camera: i32,
+        /// Unix milliseconds of the first frame
+        started_ms: u64,
+        duration_ms: u64,
+        frames: u64,
+        fps: f64,
+    },
+    /// Reply to every request
+    #[serde(rename = "playback_state")]
+    State {
+        position_ms: u64,
+        paused: bool,
+        speed: f64,
+    },
+    /// The last frame was sent. A `seek` starts playing again.
+    #[serde(rename = "playback_ended")]
+    Ended { position_ms: u64 },
+}
+
+impl PlaybackEvent {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl ClientMessage {
+    /// Parses one text frame. Typed and signaling messages are told apart
+    /// by their `type` or `event` field, so errors name the actual problem
+    /// instead of "did not match any variant".
+    pub fn parse(text: &str) -> Result<ClientMessage, ErrorMessage> {
+        let invalid = |message: String| ErrorMessage::new(ErrorCode::InvalidMessage, message);
+        let value: serde_json::Value = serde_json::from_str(text)
+            .map_err(|e| invalid(format!("Message is not JSON: {}", e)))?;
+
+        if value.get("type").is_some() {
+            serde_json::from_value(value)
+                .map(ClientMessage::Request)
+                .map_err(|e| invalid(format!("Invalid message: {}", e)))
+        } else if value.get("event").is_some() {
+            let signal: SignalMessage = serde_json::from_value(value)
+                .map_err(|e| invalid(format!("Invalid signaling message: {}", e)))?;
+            match signal {
+                SignalMessage::UserJoined(_) | SignalMessage::UserLeft(_) | SignalMessage::CameraFrame(_) => {
+                    Err(invalid(format!("'{}' is sent by the server only", signal.name())))
+                }
+                signal => Ok(ClientMessage::Signal(signal)),
+            }
+        } else {
+            Err(invalid("Message has neither a 'type' nor an 'event'".to_string()))
+        }
+    }
+}
+
+/// Both directions of the protocol, the root of the served schema.
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct Protocol {
+    client: ClientMessage,
+    server: ServerMessage,
+    playback_client: PlaybackRequest,
+    playback_server: PlaybackEvent,
+}
+
+/// JSON Schema of every `/ws` text message, for generating client bindings.
+pub fn schema() -> RootSchema {
+    let mut schema = schemars::schema_for!(Protocol);
+    let metadata = schema.schema.metadata();
+    metadata.title = Some("monitor-system /ws protocol".to_string());
+    metadata.description = Some(format!(
+        "Protocol versions {} to {}. `client` lists the messages clients send, `server` the ones they receive. `playback_client` and `playback_server` are the same for `/recordings/<id>/play`.",
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+    ));
+    schema
+}
+
+/// Schema of the constant `"type": "error"` field of `ErrorMessage`.
+pub fn error_type_schema(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        const_value: Some(serde_json::json!("error")),
+        ..Default::default()
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_header_layout() {
+        let header = FrameHeader {
+            index: -2,
+            codec: VideoCodec::H264,
+            keyframe: true,
+            skipped: 3,
+            seq: 0x0102_0304_0506_0708,
+            timestamp_ms: 1_700_000_000_123,
+            monotonic_us: 42,
+        };
+        let frame = header.frame(&[0xAA, 0xBB]);
+
+        assert_eq!(frame.len(), FrameHeader::LEN + 2);
+        assert_eq!(&frame[0..2], b"MF");
+        assert_eq!(frame[2], FrameHeader::VERSION);
+        assert_eq!(frame[3] as usize, FrameHeader::LEN);
+        assert_eq!(frame[4], 1);
+        assert_eq!(frame[5], 1);
+        assert_eq!(&frame[6..8], &[0, 0]);
+        assert_eq!(&frame[8..12], &[0xFF, 0xFF, 0xFF, 0xFE]);
+        assert_eq!(&frame[12..16], &[0, 0, 0, 3]);
+        assert_eq!(&frame[16..24], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(&frame[24..32], &1_700_000_000_123u64.to_be_bytes());
+        assert_eq!(&frame[32..40], &[0, 0, 0, 0, 0, 0, 0, 42]);
+        assert_eq!(&frame[40..], &[0xAA, 0xBB]);
+    }
+
+    #[test]
+    fn jpeg_frame_header_codec_and_flags() {
+        let header = FrameHeader {
+            index: 0,
+            codec: VideoCodec::Jpeg,
+            keyframe: false,
+            skipped: 0,
+            seq: 1,
+            timestamp_ms: 0,
+            monotonic_us: 0,
+        };
+        let frame = header.frame(&[]);
+
+        assert_eq!(frame.len(), FrameHeader::LEN);
+        assert_eq!(frame[4], 0);
+        assert_eq!(frame[5], 0);
+    }
+}
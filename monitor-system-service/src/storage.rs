@@ -0,0 +1,49 @@
+use serde::de::DeserializeOwned;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+pub fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Parses a TOML store. A missing file is the default, an empty store.
+pub fn read_toml<T: DeserializeOwned + Default>(path: &Path) -> Result<T, String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => toml::from_str::<T>(&content)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Creates `path`, which must not exist yet. `mode` sets its Unix
+/// permissions from the start, so a private file is never readable by
+/// others, not even before it is complete.
+pub fn create_file(path: &Path, content: &str, mode: Option<u32>) -> Result<(), String> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if let Some(mode) = mode {
+            options.mode(mode);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Writes next to the target and renames, so readers and crashes never
+/// see a partial file. `mode` is as for `create_file`.
+pub fn write_atomic(path: &Path, content: &str, mode: Option<u32>) -> Result<(), String> {
+    let tmp_path = path.with_extension("tmp");
+    // Left over by an interrupted write, possibly with other permissions
+    let _ = std::fs::remove_file(&tmp_path);
+    create_file(&tmp_path, content, mode)?;
+    std::fs::rename(&tmp_path, path)
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
@@ -0,0 +1,104 @@
+use crate::config::TlsConfig;
+use crate::storage::{create_file, modified_time};
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::Path;
+use std::time::Duration;
+
+/// Names the self-signed certificate is valid for: localhost, the
+/// configured host unless it is a wildcard address, and the machine name.
+fn subject_alt_names(host: &str) -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string(), "::1".to_string()];
+    if !matches!(host, "0.0.0.0" | "::" | "") {
+        names.push(host.to_string());
+    }
+    if let Ok(hostname) = sys_info::hostname() {
+        names.push(hostname);
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn write_private(path: &Path, content: &str) -> Result<(), String> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    create_file(path, content, Some(0o600))
+}
+
+/// Generates a self-signed certificate when neither file exists yet.
+/// Existing files are never overwritten.
+fn ensure_certificate(config: &TlsConfig, host: &str) -> Result<(), String> {
+    let cert_exists = config.cert_path.exists();
+    let key_exists = config.key_path.exists();
+    match (cert_exists, key_exists) {
+        (true, true) => return Ok(()),
+        (false, false) if config.self_signed => {}
+        (false, false) => {
+            return Err(format!(
+                "TLS certificate {} not found and tls.self_signed is off",
+                config.cert_path.display()
+            ))
+        }
+        _ => {
+            return Err(format!(
+                "Only one of {} and {} exists, provide both or remove both to generate a self-signed pair",
+                config.cert_path.display(),
+                config.key_path.display()
+            ))
+        }
+    }
+
+    let names = subject_alt_names(host);
+    let generated = rcgen::generate_simple_self_signed(names.clone())
+        .map_err(|e| format!("Failed to generate a self-signed certificate: {}", e))?;
+    write_private(&config.key_path, &generated.key_pair.serialize_pem())?;
+    write_private(&config.cert_path, &generated.cert.pem())?;
+    println!(
+        "Generated a self-signed certificate for {} in {}, browsers will warn until it is trusted",
+        names.join(", "),
+        config.cert_path.display()
+    );
+    Ok(())
+}
+
+/// Loads the certificate and key, generating a self-signed pair on the
+/// first run when allowed.
+pub async fn load(config: &TlsConfig, host: &str) -> Result<RustlsConfig, String> {
+    // Only the ring provider is compiled in, make it the process default
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    ensure_certificate(config, host)?;
+    RustlsConfig::from_pem_file(&config.cert_path, &config.key_path)
+        .await
+        .map_err(|e| format!(
+            "Failed to load TLS certificate {} and key {}: {}",
+            config.cert_path.display(),
+            config.key_path.display(),
+            e
+        ))
+}
+
+/// Polls the certificate and key for changes and swaps them in without a
+/// restart. New connections use the new pair, open ones keep theirs. A
+/// pair that fails to load leaves the previous one in place.
+pub fn watch(rustls: RustlsConfig, config: TlsConfig) {
+    tokio::spawn(async move {
+        let mut seen = (modified_time(&config.cert_path), modified_time(&config.key_path));
+        let mut interval = tokio::time::interval(Duration::from_secs(config.reload_interval_secs));
+        loop {
+            interval.tick().await;
+            let current = (modified_time(&config.cert_path), modified_time(&config.key_path));
+            if current == seen {
+                continue;
+            }
+            seen = current;
+
+            match rustls.reload_from_pem_file(&config.cert_path, &config.key_path).await {
+                Ok(()) => println!("Reloaded TLS certificate from {}", config.cert_path.display()),
+                Err(e) => eprintln!("Keeping previous TLS certificate: {}", e),
+            }
+        }
+    });
+}
@@ -1,18 +1,46 @@
-use std::collections::HashSet;
-use opencv::videoio;
 use serde::{Deserialize, Serialize};
+use axum::extract::ws::Message;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, RwLock};
+use cpal::traits::StreamTrait;
 use cpal::Stream;
-use tokio::sync::{broadcast, Mutex as TokioMutex};
-use crate::handlers::camera::Users;
+use crate::auth::permissions::{Action, Role};
+use crate::auth::lockout::LoginGuard;
+use crate::auth::session::SessionManager;
+use crate::auth::user_store::UserStore;
+use crate::config::Config;
+use crate::processor::microphone::Microphone;
+use crate::processor::camera_inventory::CameraInventory;
+use crate::processor::camera_registry::CameraRegistry;
+use crate::processor::catalog::Catalog;
+use crate::processor::event_log::EventLog;
+use crate::processor::h264::H264Encoders;
+use crate::processor::hls::HlsPackager;
+use crate::processor::webrtc_peer::WebRtcPeers;
+use crate::protocol::{error_type_schema, ErrorCode};
+use schemars::JsonSchema;
+
+/// Signaling peers by the id they joined with.
+pub type Users = Arc<RwLock<HashMap<String, mpsc::UnboundedSender<Message>>>>;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub eyes: Arc<EyesState>,
-    pub current_camera_index: Arc<TokioMutex<Option<i32>>>,
     pub os_type: String,
-    pub video_state: Arc<VideoState>,
-    pub user_sate: Users
+    pub cameras: Arc<CameraRegistry>,
+    pub inventory: Arc<CameraInventory>,
+    pub events: Arc<EventLog>,
+    pub catalog: Arc<Catalog>,
+    pub h264: Arc<H264Encoders>,
+    pub hls: Arc<HlsPackager>,
+    pub webrtc: Arc<WebRtcPeers>,
+    /// Peers in the signaling room
+    pub signal_peers: Users,
+    pub config: Arc<Config>,
+    pub user_store: Arc<UserStore>,
+    pub sessions: Arc<SessionManager>,
+    pub login_guard: Arc<LoginGuard>,
+    pub microphone: Arc<Microphone>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,12 +58,6 @@ pub struct EyeInfo {
     pub status: CameraStatus,
 }
 
-pub struct EyesState {
-    pub eyes_io: Arc<TokioMutex<Option<videoio::VideoCapture>>>,
-    pub status: Arc<TokioMutex<bool>>,
-}
-
-
 #[derive(Serialize, Deserialize)]
 pub struct SystemInfo {
     pub os_type: String,
@@ -43,18 +65,55 @@ pub struct SystemInfo {
     pub eyes: Vec<EyeInfo>,
 }
 
-#[derive(Debug, Clone)]
-pub enum VideoCommand {
-    Frame(Vec<u8>),
-    Error(String),
+/// Machine-readable error sent as a JSON text frame on the sockets and as
+/// the body of failed REST requests.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ErrorMessage {
+    #[serde(rename = "type")]
+    #[schemars(schema_with = "error_type_schema")]
+    pub message_type: &'static str,
+    pub code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ControlMessage {
-    #[serde(rename = "type")]
-    pub(crate) message_type: String,
-    pub(crate) action: String,
-    pub(crate) index: Option<i32>,
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            message_type: "error",
+            code,
+            action: None,
+            message: message.into(),
+            retry_after_secs: None,
+        }
+    }
+
+    pub fn locked_out(retry_after_secs: u64) -> Self {
+        Self {
+            retry_after_secs: Some(retry_after_secs),
+            ..Self::new(ErrorCode::LockedOut, "Too many failed logins, try again later")
+        }
+    }
+
+    pub fn forbidden(action: Action, role: Role) -> Self {
+        Self {
+            action: Some(action),
+            ..Self::new(ErrorCode::Forbidden, format!("Role '{}' is not allowed to do this", role))
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub role: Role,
 }
 
 
@@ -62,29 +121,26 @@ pub struct ControlMessage {
 pub enum AudioCommand {
     Data(Vec<u8>),
     Text(String),
+    /// Sends the error and closes the socket
+    Close(ErrorMessage),
 }
 
 pub struct AudioStreamHandle {
-    pub stream: Arc<Stream>,
+    /// Only set for cpal input, generator sources run on their own thread.
+    pub stream: Option<Arc<Stream>>,
     pub stop_signal: Arc<Mutex<bool>>,
 }
 
 unsafe impl Send for AudioStreamHandle {}
 
-
-pub struct VideoState {
-    pub broadcast_tx: broadcast::Sender<VideoCommand>,
-    pub authenticated_clients: Arc<TokioMutex<HashSet<String>>>,
-    pub viewing_clients: Arc<TokioMutex<HashSet<String>>>,
-}
-
-impl VideoState {
-    pub fn new() -> Self {
-        let (broadcast_tx, _) = broadcast::channel(10);
-        Self {
-            broadcast_tx,
-            authenticated_clients: Arc::new(TokioMutex::new(HashSet::new())),
-            viewing_clients: Arc::new(TokioMutex::new(HashSet::new())),
+impl AudioStreamHandle {
+    pub fn stop(&self) {
+        println!("[AUDIO] Stopping stream");
+        *self.stop_signal.lock().unwrap() = true;
+        if let Some(stream) = &self.stream {
+            if let Err(e) = stream.pause() {
+                println!("[AUDIO] Error stopping stream: {:?}", e);
+            }
         }
     }
 }
@@ -1,22 +1,37 @@
-use crate::auth::authenticate_basic;
-use crate::r#trait::{AppState, AudioCommand, AudioState, AudioStreamHandle, ControlMessage, VideoCommand};
+use crate::auth::permissions::Action;
+use crate::auth::session::{close_message, Session};
+use crate::auth::{authenticate, login_failure_text, AuthUser};
+use crate::processor::audio_source::{AudioFormat, AudioSource};
+use crate::processor::camera_registry::{CameraRegistry, FrameReceiver};
+use crate::processor::capture_settings::CaptureSettingsUpdate;
+use crate::processor::catalog::Recording;
+use crate::processor::event_log::EventKind;
+use crate::processor::h264::{ChunkReceiver, H264Encoders};
+use crate::processor::image_controls::ImageControls;
+use crate::processor::microphone::AudioSubscription;
+use crate::processor::playback::{self, PlaybackOutput};
+use crate::processor::webrtc_peer::PeerSession;
+use crate::protocol::{
+    ClientMessage, ClientRequest, ControlAction, ErrorCode, FrameHeader, PlaybackRequest, ServerEvent, Signal,
+    SignalMessage, StreamStatus, VideoCodec, FRAME_HEADER_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use crate::r#trait::{AppState, AudioCommand, AudioState, ErrorMessage, Users};
 use axum::extract::ws::{Message, WebSocket};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use futures::{SinkExt, StreamExt};
-use opencv::{core::{Mat, Vector}, imgcodecs, prelude::*, videoio};
+use std::collections::HashMap;
 use std::io::Write;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use tokio::sync::{mpsc, Mutex as TokioMutex};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, Mutex as TokioMutex};
 use tokio::task::JoinHandle;
-use tokio::time::{interval, Duration};
+use tokio::time::Duration;
 
-const SAMPLE_RATE: u32 = 44100;
-const CHANNELS: u16 = 2;
-const BUFFER_SIZE: usize = 2048; // Smaller chunks for lower latency
-const LATENCY_MS: u64 = 30;
+/// Camera a viewer may start when nothing is streaming yet.
+const DEFAULT_CAMERA_INDEX: i32 = 0;
 
-fn write_wav_header(file: &mut std::fs::File, data_len: u32) -> std::io::Result<()> {
+fn write_wav_header(file: &mut std::fs::File, format: &AudioFormat, data_len: u32) -> std::io::Result<()> {
     // RIFF header
     file.write_all(b"RIFF")?;
     file.write_all(&(data_len + 36).to_le_bytes())?; // File size - 8
@@ -26,10 +41,10 @@ fn write_wav_header(file: &mut std::fs::File, data_len: u32) -> std::io::Result<
     file.write_all(b"fmt ")?;
     file.write_all(&(16u32).to_le_bytes())?; // Chunk size
     file.write_all(&(1u16).to_le_bytes())?;  // Audio format (PCM)
-    file.write_all(&(CHANNELS).to_le_bytes())?;
-    file.write_all(&(SAMPLE_RATE).to_le_bytes())?;
-    file.write_all(&(SAMPLE_RATE * CHANNELS as u32 * 2).to_le_bytes())?; // Byte rate
-    file.write_all(&(CHANNELS * 2).to_le_bytes())?; // Block align
+    file.write_all(&(format.channels).to_le_bytes())?;
+    file.write_all(&(format.sample_rate).to_le_bytes())?;
+    file.write_all(&(format.sample_rate * format.channels as u32 * 2).to_le_bytes())?; // Byte rate
+    file.write_all(&(format.channels * 2).to_le_bytes())?; // Block align
     file.write_all(&(16u16).to_le_bytes())?; // Bits per sample
 
     // data chunk
@@ -39,64 +54,35 @@ fn write_wav_header(file: &mut std::fs::File, data_len: u32) -> std::io::Result<
     Ok(())
 }
 
-pub fn test_audio_capture() -> Result<(), String> {
+pub fn test_audio_capture(source: &dyn AudioSource, format: &AudioFormat) -> Result<(), String> {
     println!("Starting direct audio capture test...");
+    println!("Using audio source: {}", source.name());
 
-    let host = cpal::default_host();
-    let device = host.default_input_device()
-        .ok_or_else(|| "No input device available".to_string())?;
-
-    println!("Using input device: {}", device.name().unwrap_or_default());
-
-    let config = cpal::StreamConfig {
-        channels: CHANNELS,
-        sample_rate: cpal::SampleRate(SAMPLE_RATE),
-        buffer_size: cpal::BufferSize::Default,
-    };
-
-    println!("Testing with config: {:?}", config);
-
-    // Create temporary file for raw data first
-    let temp_file = std::fs::File::create("temp_audio.raw")
-        .map_err(|e| format!("Failed to create temp file: {}", e))?;
-    let temp_file = Arc::new(Mutex::new(temp_file));
-
-    let stream = device.build_input_stream(
-        &config,
-        move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            let file = temp_file.clone();
-            let mut file = file.lock().unwrap();
-            for &sample in data {
-                let scaled = (sample * 32768.0) as i16;
-                file.write_all(&scaled.to_le_bytes()).unwrap();
-            }
-        },
-        move |err| eprintln!("Error in audio stream: {:?}", err),
-        Some(Duration::from_millis(5000)), // 5 second test duration
-    ).map_err(|e| format!("Failed to build input stream: {:?}", e))?;
+    let (audio_sender, audio_receiver) = crossbeam_channel::bounded(64);
+    let handle = source.start(audio_sender)?;
 
     println!("Recording for 5 seconds...");
-    stream.play().map_err(|e| format!("Failed to play stream: {:?}", e))?;
-    std::thread::sleep(Duration::from_secs(5));
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let mut raw_data = Vec::new();
+    while let Some(remaining) = deadline.checked_duration_since(std::time::Instant::now()) {
+        if let Ok(chunk) = audio_receiver.recv_timeout(remaining) {
+            raw_data.extend(chunk);
+        }
+    }
+    handle.stop();
 
     // Now create the final WAV file
-    let raw_data = std::fs::read("temp_audio.raw")
-        .map_err(|e| format!("Failed to read temp file: {}", e))?;
     let data_len = raw_data.len() as u32;
 
     let mut wav_file = std::fs::File::create("test_audio.wav")
         .map_err(|e| format!("Failed to create WAV file: {}", e))?;
 
-    write_wav_header(&mut wav_file, data_len)
+    write_wav_header(&mut wav_file, format, data_len)
         .map_err(|e| format!("Failed to write WAV header: {}", e))?;
 
     wav_file.write_all(&raw_data)
         .map_err(|e| format!("Failed to write audio data: {}", e))?;
 
-    // Clean up temp file
-    std::fs::remove_file("temp_audio.raw")
-        .map_err(|e| format!("Failed to remove temp file: {}", e))?;
-
     println!("Audio recording completed and saved as 'test_audio.wav'");
     println!("\nYou can play the audio file using any of these commands:");
     println!("1. Using ffplay (if installed):");
@@ -112,272 +98,908 @@ pub fn test_audio_capture() -> Result<(), String> {
     Ok(())
 }
 
-pub async fn handle_video_socket(socket: WebSocket, state: AppState) {
+/// Binary frames queued per client before the oldest are dropped.
+const FRAME_QUEUE: usize = 4;
+
+/// How a subscription delivers frames: raw JPEG or H.264 binary
+/// messages, or the base64 `camera-frame` events older signaling clients
+/// expect.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameFormat {
+    Binary,
+    H264,
+    Base64Json,
+}
+
+struct Client {
+    id: String,
+    state: AppState,
+    /// Negotiated with `hello`, clients that never send one speak version 1
+    version: u32,
+    ctl_tx: mpsc::UnboundedSender<Message>,
+    frame_tx: mpsc::Sender<Message>,
+    /// JPEG or H.264 binary frames by camera index. Clients below
+    /// `FRAME_HEADER_VERSION` have at most one, `on` switches cameras.
+    binary: HashMap<i32, (FrameFormat, JoinHandle<()>)>,
+    base64: HashMap<i32, JoinHandle<()>>,
+    /// Id the client joined the signaling room with
+    peer_id: Option<String>,
+    /// The server's WebRTC peer for this client
+    webrtc: Option<PeerSession>,
+}
+
+impl Client {
+    fn send_text(&self, text: impl Into<String>) {
+        let _ = self.ctl_tx.send(Message::Text(text.into()));
+    }
+
+    fn send_error(&self, error: &ErrorMessage) {
+        self.send_text(error.to_json());
+    }
+
+    fn send_status(&self, index: i32, status: StreamStatus) {
+        if self.version >= 2 {
+            self.send_text(ServerEvent::CameraStatus { index, status }.to_json());
+        } else {
+            self.send_text(status.legacy_text());
+        }
+    }
+
+    fn send_welcome(&self, user: &AuthUser) {
+        let welcome = ServerEvent::Welcome {
+            protocol_version: self.version,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            max_protocol_version: PROTOCOL_VERSION,
+            client_id: self.id.clone(),
+            username: user.username.clone(),
+            role: user.role,
+        };
+        self.send_text(welcome.to_json());
+    }
+
+    fn watch_action(&self, index: i32) -> Action {
+        watch_action(&self.state.cameras, index)
+    }
+
+    fn watch(&mut self, user: &AuthUser, index: i32, format: FrameFormat) {
+        if index < 0 {
+            self.send_error(&ErrorMessage::new(ErrorCode::InvalidCamera, format!("{} is not a camera index", index)));
+            return;
+        }
+        let already = match format {
+            FrameFormat::Binary | FrameFormat::H264 => {
+                self.binary.get(&index).is_some_and(|(current_format, _)| *current_format == format)
+            }
+            FrameFormat::Base64Json => self.base64.contains_key(&index),
+        };
+        if already {
+            return;
+        }
+        if format == FrameFormat::H264 && !self.state.h264.enabled() {
+            self.send_error(&ErrorMessage::new(ErrorCode::BadRequest, "H.264 is not enabled on this server"));
+            return;
+        }
+        if let Err(e) = user.check(self.watch_action(index)) {
+            self.send_error(&e);
+            return;
+        }
+
+        let already_running = self.state.cameras.is_active(index);
+        if format != FrameFormat::Base64Json && self.version < FRAME_HEADER_VERSION {
+            // Without a frame header the client cannot tell cameras apart
+            let others: Vec<i32> = self.binary.keys().copied().filter(|other| *other != index).collect();
+            for other in others {
+                self.stop_binary(other);
+                self.release(other);
+            }
+        }
+        match format {
+            FrameFormat::Binary => {
+                // Switching codec on the same camera keeps the camera
+                self.stop_binary(index);
+                let frames = self.state.cameras.subscribe(index, &self.id);
+                let forward = forward_frames(index, frames, format, self.version, self.frame_tx.clone(), self.ctl_tx.clone());
+                self.binary.insert(index, (format, forward));
+            }
+            FrameFormat::H264 => {
+                if self.stop_binary(index) {
+                    self.release(index);
+                }
+                let chunks = self.state.h264.subscribe(index, &self.id);
+                let forward = forward_h264(
+                    index,
+                    chunks,
+                    self.state.h264.clone(),
+                    self.version,
+                    self.frame_tx.clone(),
+                    self.ctl_tx.clone(),
+                );
+                self.binary.insert(index, (format, forward));
+            }
+            FrameFormat::Base64Json => {
+                let frames = self.state.cameras.subscribe(index, &self.id);
+                let forward = forward_frames(index, frames, format, self.version, self.frame_tx.clone(), self.ctl_tx.clone());
+                self.base64.insert(index, forward);
+            }
+        }
+
+        let status = if already_running { StreamStatus::Joined } else { StreamStatus::Started };
+        self.send_status(index, status);
+    }
+
+    fn unwatch(&mut self, index: i32) {
+        let mut stopped = self.stop_binary(index);
+        if let Some(forward) = self.base64.remove(&index) {
+            forward.abort();
+            stopped = true;
+        }
+        if !stopped {
+            return;
+        }
+
+        self.release(index);
+        let status = if self.state.cameras.is_active(index) { StreamStatus::Left } else { StreamStatus::Stopped };
+        self.send_status(index, status);
+    }
+
+    fn unwatch_all(&mut self) {
+        let mut indexes: Vec<i32> = self.base64.keys().chain(self.binary.keys()).copied().collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        for index in indexes {
+            self.unwatch(index);
+        }
+    }
+
+    /// Ends the binary subscription of one camera, if there is one. H.264
+    /// viewers leave the encoder here, `release` leaves the camera.
+    fn stop_binary(&mut self, index: i32) -> bool {
+        let (format, forward) = match self.binary.remove(&index) {
+            Some(subscription) => subscription,
+            None => return false,
+        };
+        forward.abort();
+        if format == FrameFormat::H264 {
+            self.state.h264.unsubscribe(index, &self.id);
+        }
+        true
+    }
+
+    /// Leaves the camera unless another subscription of this client uses it
+    fn release(&self, index: i32) {
+        let binary_uses = self.binary.get(&index).is_some_and(|(format, _)| *format == FrameFormat::Binary);
+        if !binary_uses && !self.base64.contains_key(&index) {
+            self.state.cameras.unsubscribe(index, &self.id);
+        }
+    }
+
+    fn handle_control(&mut self, user: &AuthUser, action: ControlAction, index: Option<i32>, codec: VideoCodec) {
+        match action {
+            ControlAction::On => {
+                println!("Received ON command with index {:?} and codec {:?} from client {}", index, codec, self.id);
+                let format = match codec {
+                    VideoCodec::Jpeg => FrameFormat::Binary,
+                    VideoCodec::H264 => FrameFormat::H264,
+                };
+                match index {
+                    Some(index) => self.watch(user, index, format),
+                    None => self.send_error(&ErrorMessage::new(ErrorCode::InvalidCamera, "'on' needs a camera index")),
+                }
+            }
+            ControlAction::Off => {
+                println!("Received OFF command with index {:?} from client {}", index, self.id);
+                match index {
+                    Some(index) => self.unwatch(index),
+                    // Without an index every camera of this client stops
+                    None => self.unwatch_all(),
+                }
+            }
+        }
+    }
+
+    /// Applies in the background, reopening a device takes a moment and
+    /// the socket keeps serving meanwhile.
+    fn handle_settings(&self, user: &AuthUser, index: i32, update: CaptureSettingsUpdate) {
+        let action = if update.is_empty() { Action::Watch } else { Action::ConfigureCamera };
+        if let Err(e) = user.check(action) {
+            self.send_error(&e);
+            return;
+        }
+
+        let cameras = self.state.cameras.clone();
+        let ctl_tx = self.ctl_tx.clone();
+        tokio::spawn(async move {
+            let reply = match cameras.update_settings(index, &update).await {
+                Ok(settings) => ServerEvent::CameraSettings(settings).to_json(),
+                Err(e) => e.to_json(),
+            };
+            let _ = ctl_tx.send(Message::Text(reply));
+        });
+    }
+
+    fn handle_controls(&self, user: &AuthUser, index: i32, update: ImageControls) {
+        let action = if update.is_empty() { Action::Watch } else { Action::ConfigureCamera };
+        if let Err(e) = user.check(action) {
+            self.send_error(&e);
+            return;
+        }
+
+        let cameras = self.state.cameras.clone();
+        let ctl_tx = self.ctl_tx.clone();
+        tokio::spawn(async move {
+            let reply = match cameras.update_controls(index, &update).await {
+                Ok(controls) => ServerEvent::CameraControls(controls).to_json(),
+                Err(e) => e.to_json(),
+            };
+            let _ = ctl_tx.send(Message::Text(reply));
+        });
+    }
+
+    fn handle_trigger(&self, user: &AuthUser, index: i32, reason: Option<String>) {
+        if let Err(e) = user.check(Action::TriggerClip) {
+            self.send_error(&e);
+            return;
+        }
+        let config = &self.state.config;
+        if !config.clips.enabled || !config.clip_cameras().contains(&index) {
+            self.send_error(&ErrorMessage::new(ErrorCode::NotFound, format!("Clips are not saved for camera {}", index)));
+            return;
+        }
+        let username = user.username.clone();
+        self.state.events.record(Some(index), EventKind::ManualTrigger { username, reason });
+    }
+
+    /// Opens the peer inline, so candidates that follow the offer find
+    /// it, and answers in the background once gathering is done.
+    async fn handle_webrtc_offer(&mut self, user: &AuthUser, index: i32, sdp: String, audio: bool) {
+        if !self.state.webrtc.enabled() {
+            self.send_error(&ErrorMessage::new(ErrorCode::BadRequest, "WebRTC is not enabled on this server"));
+            return;
+        }
+        if index < 0 {
+            self.send_error(&ErrorMessage::new(ErrorCode::InvalidCamera, format!("{} is not a camera index", index)));
+            return;
+        }
+        let allowed = user.check(self.watch_action(index))
+            .and_then(|()| if audio { user.check(Action::Listen) } else { Ok(()) });
+        if let Err(e) = allowed {
+            self.send_error(&e);
+            return;
+        }
+        self.close_webrtc().await;
+
+        let ctl_tx = self.ctl_tx.clone();
+        let on_state = move |state| {
+            let _ = ctl_tx.send(Message::Text(ServerEvent::WebrtcState { index, state }.to_json()));
+        };
+        let session = match self.state.webrtc.open(index, sdp, audio, on_state).await {
+            Ok(session) => session,
+            Err(e) => {
+                self.send_error(&ErrorMessage::new(ErrorCode::BadRequest, e));
+                return;
+            }
+        };
+        let answer = session.answer();
+        self.webrtc = Some(session);
+
+        let ctl_tx = self.ctl_tx.clone();
+        tokio::spawn(async move {
+            let reply = match answer.await {
+                Ok(sdp) => ServerEvent::WebrtcAnswer { index, sdp }.to_json(),
+                Err(e) => ErrorMessage::new(ErrorCode::BadRequest, e).to_json(),
+            };
+            let _ = ctl_tx.send(Message::Text(reply));
+        });
+    }
+
+    async fn handle_webrtc_candidate(&self, candidate: RTCIceCandidateInit) {
+        let result = match &self.webrtc {
+            Some(session) => session.add_candidate(candidate).await,
+            None => Err("No WebRTC session, send webrtc_offer first".to_string()),
+        };
+        if let Err(e) = result {
+            self.send_error(&ErrorMessage::new(ErrorCode::BadRequest, e));
+        }
+    }
+
+    async fn close_webrtc(&mut self) {
+        if let Some(session) = self.webrtc.take() {
+            println!("Closing WebRTC session of client {} on camera {}", self.id, session.index);
+            session.close().await;
+        }
+    }
+
+    async fn handle_signal(&mut self, user: &AuthUser, msg: SignalMessage) {
+        println!("Received message: {:?}", msg.name());
+        let users = &self.state.signal_peers;
+        match &msg {
+            // Older clients ask for frames over signaling, they get base64 JSON frames
+            SignalMessage::StartCamera(signal) => match camera_index(&signal.data) {
+                Some(index) => self.watch(user, index, FrameFormat::Base64Json),
+                None => {
+                    let error = ErrorMessage::new(ErrorCode::InvalidCamera, format!("'{}' is not a camera index", signal.data));
+                    self.send_error(&error);
+                }
+            },
+            SignalMessage::StopCamera(signal) => match signal.data.trim().parse::<i32>() {
+                Ok(index) => self.unwatch(index),
+                // Without an index every camera of this client stops
+                Err(_) => self.unwatch_all(),
+            },
+            SignalMessage::UserJoined(_) | SignalMessage::UserLeft(_) | SignalMessage::CameraFrame(_) => {
+                // Rejected by ClientMessage::parse
+            }
+            _ => {
+                if let Err(e) = user.check(Action::Signal) {
+                    self.send_error(&e);
+                    return;
+                }
+                match &msg {
+                    SignalMessage::Join(signal) => {
+                        let peer_id = signal.from.clone();
+                        if self.peer_id.as_ref() == Some(&peer_id) {
+                            return;
+                        }
+                        {
+                            let mut peers = users.write().await;
+                            // Another connection's id would take over its messages
+                            if peers.contains_key(&peer_id) {
+                                drop(peers);
+                                let error = ErrorMessage::new(ErrorCode::Conflict, format!("Peer id '{}' is already in use", peer_id));
+                                self.send_error(&error);
+                                return;
+                            }
+                            peers.insert(peer_id.clone(), self.ctl_tx.clone());
+                        }
+                        // Joining again under a new id leaves with the old one
+                        self.leave_signaling().await;
+
+                        let user_joined_msg = SignalMessage::UserJoined(Signal {
+                            data: peer_id.clone(),
+                            room: signal.room.clone(),
+                            from: "server".to_string(),
+                            to: None,
+                        });
+                        broadcast_message(&self.state.signal_peers, &user_joined_msg.to_json(), Some(&peer_id)).await;
+                        self.peer_id = Some(peer_id);
+                    }
+                    SignalMessage::Message(_) => {
+                        broadcast_message(users, &msg.to_json(), None).await;
+                    }
+                    _ => {
+                        let signal = msg.signal();
+                        if let Some(to) = &signal.to {
+                            if let Some(peer_tx) = users.read().await.get(to) {
+                                let _ = peer_tx.send(Message::Text(msg.to_json()));
+                            }
+                        } else {
+                            broadcast_message(users, &msg.to_json(), Some(&signal.from)).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn cleanup(&mut self) {
+        self.close_webrtc().await;
+        let indexes: Vec<i32> = self.binary.keys().copied().collect();
+        for index in indexes {
+            self.stop_binary(index);
+        }
+        for (_, forward) in self.base64.drain() {
+            forward.abort();
+        }
+        self.state.cameras.unsubscribe_all(&self.id);
+        self.leave_signaling().await;
+    }
+
+    /// Removes this connection's peer id and tells the others it left.
+    async fn leave_signaling(&mut self) {
+        if let Some(peer_id) = self.peer_id.take() {
+            let users = &self.state.signal_peers;
+            users.write().await.remove(&peer_id);
+
+            let user_left_msg = SignalMessage::UserLeft(Signal {
+                data: peer_id,
+                room: "default-room".to_string(),
+                from: "server".to_string(),
+                to: None,
+            });
+            broadcast_message(users, &user_left_msg.to_json(), None).await;
+        }
+    }
+}
+
+/// `start-camera` and `stop-camera` carry the camera index in `data`.
+fn camera_index(data: &str) -> Option<i32> {
+    match data.trim() {
+        "" => Some(DEFAULT_CAMERA_INDEX),
+        index => index.parse().ok().filter(|index| *index >= 0),
+    }
+}
+
+/// What to do with a text frame that arrives before authentication.
+enum Login {
+    /// `hello` without credentials, keep waiting for them
+    Pending,
+    Done(AuthUser),
+    Failed(ErrorMessage),
+}
+
+/// Joining a running camera or starting the default one is watching
+pub fn watch_action(cameras: &CameraRegistry, index: i32) -> Action {
+    if cameras.is_active(index) || index == DEFAULT_CAMERA_INDEX {
+        Action::Watch
+    } else {
+        Action::StartCamera
+    }
+}
+
+/// The single `/ws` endpoint: camera control, binary frames and WebRTC
+/// signaling over one authenticated socket. Sockets opened with a session
+/// token are authenticated already, otherwise the first text frame must
+/// carry Basic credentials, bare or in a `hello`.
+pub async fn handle_video_socket(socket: WebSocket, state: AppState, session: Option<Session>, client_ip: IpAddr) {
     println!("New video websocket connection established");
     let (mut sender, mut receiver) = socket.split();
 
-    let video_state = state.video_state.clone();
-    let mut broadcast_rx = video_state.broadcast_tx.subscribe();
-
-    let (tx, mut rx) = mpsc::channel::<VideoCommand>(10);
-    let tx = Arc::new(tx); // Wrap in Arc for sharing
+    // Control and signaling messages are never dropped, frames are when a client falls behind
+    let (ctl_tx, mut ctl_rx) = mpsc::unbounded_channel::<Message>();
+    let (frame_tx, mut frame_rx) = mpsc::channel::<Message>(FRAME_QUEUE);
 
     let client_id = uuid::Uuid::new_v4().to_string();
     let client_id_for_sender = client_id.clone();
-    let mut _video_task: Option<JoinHandle<()>> = None;
-    let is_authenticated = Arc::new(TokioMutex::new(false));
-    let is_authenticated_sender = is_authenticated.clone();
-    let is_viewing = Arc::new(TokioMutex::new(false));
-    let is_viewing_sender = is_viewing.clone();
 
     // Handle sending messages to client
-    let sender_task = tokio::spawn(async move {
+    let mut sender_task = tokio::spawn(async move {
         println!("Sender task started for client {}", client_id_for_sender);
         loop {
-            tokio::select! {
-                Some(cmd) = rx.recv() => {
-                    let msg = match cmd {
-                        VideoCommand::Frame(data) => Message::Binary(data),
-                        VideoCommand::Error(err) => {
-                            println!("Sending error to client {}: {}", client_id_for_sender, err);
-                            Message::Text(err)
-                        },
-                    };
-
-                    if sender.send(msg).await.is_err() {
-                        println!("Failed to send message to client {}, breaking sender task", client_id_for_sender);
-                        break;
-                    }
-                }
-                Ok(cmd) = broadcast_rx.recv() => {
-                    let is_auth = *is_authenticated_sender.lock().await;
-                    let is_view = *is_viewing_sender.lock().await;
-                    if !is_auth || !is_view {
-                        continue;
-                    }
-
-                    let msg = match cmd {
-                        VideoCommand::Frame(data) => Message::Binary(data),
-                        VideoCommand::Error(err) => Message::Text(err),
-                    };
+            let msg = tokio::select! {
+                biased;
+                Some(msg) = ctl_rx.recv() => msg,
+                Some(msg) = frame_rx.recv() => msg,
+                else => break,
+            };
 
-                    if sender.send(msg).await.is_err() {
-                        break;
-                    }
-                }
+            let closing = matches!(msg, Message::Close(_));
+            if sender.send(msg).await.is_err() {
+                println!("Failed to send message to client {}, breaking sender task", client_id_for_sender);
+                break;
+            }
+            if closing {
+                break;
             }
         }
         println!("Sender task ended for client {}", client_id_for_sender);
     });
 
-    let tx_for_handler = tx.clone(); // Clone for message handling loop
+    let mut client = Client {
+        id: client_id.clone(),
+        state: state.clone(),
+        version: MIN_PROTOCOL_VERSION,
+        ctl_tx: ctl_tx.clone(),
+        frame_tx,
+        binary: HashMap::new(),
+        base64: HashMap::new(),
+        peer_id: None,
+        webrtc: None,
+    };
+
+    let mut auth_user: Option<AuthUser> = None;
+    if let Some(session) = &session {
+        // Authenticated on upgrade with a session token
+        println!("Client {} authenticated by token as {} ({})", client_id, session.user.username, session.user.role);
+        auth_user = Some(session.user.clone());
+        client.send_text("Authenticated");
+        client.send_welcome(&session.user);
+    }
+    let session_end = state.sessions.clone().ended(state.user_store.clone(), session);
+    tokio::pin!(session_end);
+    let mut closing = false;
+    let mut inventory_events = state.inventory.subscribe();
+    let mut camera_events = state.events.subscribe();
+
     println!("Starting message handling loop for client {}", client_id);
-    while let Some(Ok(msg)) = receiver.next().await {
-        match msg {
-            Message::Text(text) => {
-                let mut is_auth = is_authenticated.lock().await;
-                if !*is_auth {
-                    println!("Attempting authentication for client {}", client_id);
-                    if authenticate_basic(&text).is_ok() {
-                        *is_auth = true;
-                        video_state.authenticated_clients.lock().await.insert(client_id.clone());
-                        println!("Authentication successful for client {}", client_id);
-                        let _ = tx_for_handler.send(VideoCommand::Error("Authenticated".to_string())).await;
-                    } else {
-                        println!("Authentication failed for client {}", client_id);
-                        let _ = tx_for_handler.send(VideoCommand::Error("Unauthorized".to_string())).await;
-                        break;
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            error = &mut session_end => {
+                client.send_error(&error);
+                let _ = ctl_tx.send(close_message(&error));
+                closing = true;
+                break;
+            }
+            event = inventory_events.recv() => {
+                if let Ok(event) = event {
+                    if auth_user.is_some() && client.version >= 2 {
+                        client.send_text(ServerEvent::from(event).to_json());
                     }
-                    continue;
                 }
-                drop(is_auth);
-
-                if let Ok(control_msg) = serde_json::from_str::<ControlMessage>(&text) {
-                    if control_msg.message_type == "control" {
-                        match control_msg.action.as_str() {
-                            "on" => {
-                                println!("Received ON command with index {:?} from client {}", control_msg.index, client_id);
-                                if let Some(index) = control_msg.index {
-                                    let mut viewing_clients = video_state.viewing_clients.lock().await;
-
-                                    // Add client to viewing list
-                                    viewing_clients.insert(client_id.clone());
-                                    *is_viewing.lock().await = true;
-                                    println!("Client {} added to viewing list. Total viewers: {}", client_id, viewing_clients.len());
-
-                                    if viewing_clients.len() == 1 {
-                                        // Create a new channel for the video task
-                                        let (_video_tx, _video_rx) = mpsc::channel::<VideoCommand>(10);
-
-                                        // Start video task if first viewer
-                                        let state = state.clone();
-                                        let broadcast_tx = video_state.broadcast_tx.clone();
-                                        let tx_for_video = tx.clone(); // Clone for video task
-                                        _video_task = Some(tokio::spawn(async move {
-                                            println!("Video streaming task started");
-                                            let mut interval = interval(Duration::from_millis(33));
-
-                                            let io = match state.os_type.as_str() {
-                                                "Linux" => videoio::CAP_V4L2,
-                                                "Windows" => videoio::CAP_WINRT,
-                                                "Darwin" => videoio::CAP_AVFOUNDATION,
-                                                _ => videoio::CAP_ANY,
-                                            };
-
-
-                                            let mut camera = match videoio::VideoCapture::new(index, io) {
-                                                Ok(mut cap) => {
-
-                                                    if cap.is_opened().unwrap_or(false) {
-                                                        println!("Camera initialized successfully");
-
-                                                        if io == videoio::CAP_V4L2 {
-                                                            // Set V4L2 buffer size
-                                                            cap.set(videoio::CAP_PROP_BUFFERSIZE, 3.0).unwrap();
-
-                                                            // Set timeout
-                                                            cap.set(videoio::CAP_PROP_FOURCC, videoio::VideoWriter::fourcc('M','J','P','G').unwrap() as f64).unwrap();
-
-                                                            // Set frame rate
-                                                            cap.set(videoio::CAP_PROP_FPS, 30.0).unwrap();
-                                                        }
-
-                                                        let mut camera_guard = state.eyes.eyes_io.lock().await;
-                                                        *camera_guard = Some(cap);
-                                                        *state.current_camera_index.lock().await = Some(index);
-                                                        let _ = tx_for_video.send(VideoCommand::Error("Video stream started".to_string())).await;
-                                                        camera_guard.take().unwrap()
-                                                    } else {
-                                                        println!("Failed to open camera");
-                                                        let _ = tx_for_video.send(VideoCommand::Error("Failed to open camera".to_string())).await;
-                                                        return;
-                                                    }
-                                                },
-                                                Err(e) => {
-                                                    println!("Error creating camera: {:?}", e);
-                                                    let _ = tx_for_video.send(VideoCommand::Error("Failed to create camera".to_string())).await;
-                                                    return;
-                                                }
-                                            };
-
-                                            let mut frame = Mat::default();
-                                            let mut buf = Vector::new();
-                                            let mut encode_params = Vector::new();
-                                            encode_params.push(imgcodecs::IMWRITE_JPEG_QUALITY);
-                                            encode_params.push(75);
-
-                                            let mut last_frame_time = std::time::Instant::now();
-
-                                            loop {
-                                                interval.tick().await;
-
-                                                if last_frame_time.elapsed() < Duration::from_millis(30) {
-                                                    continue;
-                                                }
-
-                                                match camera.read(&mut frame) {
-                                                    Ok(true) => {
-                                                        // Clear buffer before reuse
-                                                        buf.clear();
-
-                                                        if imgcodecs::imencode(".jpg", &frame, &mut buf, &encode_params).unwrap_or(false) {
-                                                            if broadcast_tx.send(VideoCommand::Frame(buf.to_vec())).is_err() {
-                                                                println!("Failed to broadcast frame");
-                                                                break;
-                                                            }
-                                                            last_frame_time = std::time::Instant::now();
-                                                        }
-                                                    },
-                                                    Ok(false) => {
-                                                        println!("Failed to read frame");
-                                                        tokio::time::sleep(Duration::from_millis(10)).await;
-                                                    },
-                                                    Err(e) => {
-                                                        println!("Error reading frame: {:?}", e);
-                                                        tokio::time::sleep(Duration::from_millis(10)).await;
-                                                    },
-                                                }
-
-                                                let viewing_count = state.video_state.viewing_clients.lock().await.len();
-                                                if viewing_count == 0 {
-                                                    println!("No viewers remaining, stopping stream");
-                                                    break;
-                                                }
-                                            }
-
-                                            // Cleanup camera
-                                            let _ = camera.release();
-                                            let mut camera_guard = state.eyes.eyes_io.lock().await;
-                                            *camera_guard = None;
-                                            *state.current_camera_index.lock().await = None;
-                                            println!("Video streaming task ended");
-                                        }));
-                                    } else {
-                                        let _ = tx_for_handler.send(VideoCommand::Error(
-                                            "Joined existing stream".to_string()
-                                        )).await;
-                                    }
-                                }
-                            }
-                            "off" => {
-                                println!("Received OFF command from client {}", client_id);
-                                let mut viewing_clients = video_state.viewing_clients.lock().await;
-
-                                // Remove client from viewing list
-                                viewing_clients.remove(&client_id);
-                                *is_viewing.lock().await = false;
-                                println!("Client {} removed from viewing list. Remaining viewers: {}", client_id, viewing_clients.len());
-
-                                if viewing_clients.is_empty() {
-                                    *state.eyes.status.lock().await = false;
-                                    let _ = tx_for_handler.send(VideoCommand::Error("Eyes turned off".to_string())).await;
-                                } else {
-                                    let _ = tx_for_handler.send(VideoCommand::Error(
-                                        "Stopped viewing. Other clients are still viewing.".to_string()
-                                    )).await;
-                                }
-                            }
-                            _ => {
-                                let _ = tx_for_handler.send(VideoCommand::Error("Invalid action".to_string())).await;
-                            }
-                        }
+                continue;
+            }
+            event = camera_events.recv() => {
+                if let Ok(event) = event {
+                    if auth_user.is_some() && client.version >= 2 {
+                        client.send_text(ServerEvent::from(event).to_json());
                     }
                 }
+                continue;
             }
+        };
+        let text = match msg {
+            Message::Text(text) => text,
             Message::Close(_) => {
                 println!("Received close message from client {}", client_id);
                 break;
             }
             _ => continue,
+        };
+
+        let user = match &auth_user {
+            Some(user) => user.clone(),
+            None => {
+                println!("Attempting authentication for client {}", client_id);
+                match login(&mut client, &text, client_ip).await {
+                    Login::Pending => continue,
+                    Login::Done(user) => {
+                        println!("Authentication successful for client {} as {} ({})", client_id, user.username, user.role);
+                        auth_user = Some(user);
+                        continue;
+                    }
+                    Login::Failed(e) => {
+                        println!("Authentication failed for client {} from {}: {}", client_id, client_ip, e.message);
+                        closing = e.code == ErrorCode::UnsupportedVersion;
+                        if closing {
+                            client.send_error(&e);
+                            let _ = ctl_tx.send(close_message(&e));
+                        } else if client.version >= 2 {
+                            client.send_error(&e);
+                        } else {
+                            client.send_text(login_failure_text(&e));
+                        }
+                        break;
+                    }
+                }
+            }
+        };
+
+        match ClientMessage::parse(&text) {
+            Ok(ClientMessage::Request(ClientRequest::Hello { protocol_version, .. })) => {
+                if let Err(e) = negotiate(&mut client, protocol_version) {
+                    client.send_error(&e);
+                    let _ = ctl_tx.send(close_message(&e));
+                    closing = true;
+                    break;
+                }
+                client.send_welcome(&user);
+            }
+            Ok(ClientMessage::Request(ClientRequest::Control { action, index, codec })) => {
+                client.handle_control(&user, action, index, codec);
+            }
+            Ok(ClientMessage::Request(ClientRequest::CameraSettings { index, settings })) => {
+                client.handle_settings(&user, index, settings);
+            }
+            Ok(ClientMessage::Request(ClientRequest::TriggerClip { index, reason })) => {
+                client.handle_trigger(&user, index, reason);
+            }
+            Ok(ClientMessage::Request(ClientRequest::CameraControls { index, controls })) => {
+                client.handle_controls(&user, index, controls);
+            }
+            Ok(ClientMessage::Request(ClientRequest::WebrtcOffer { index, sdp, audio })) => {
+                client.handle_webrtc_offer(&user, index, sdp, audio).await;
+            }
+            Ok(ClientMessage::Request(ClientRequest::WebrtcCandidate { candidate, sdp_mid, sdp_mline_index })) => {
+                let candidate = RTCIceCandidateInit { candidate, sdp_mid, sdp_mline_index, username_fragment: None };
+                client.handle_webrtc_candidate(candidate).await;
+            }
+            Ok(ClientMessage::Request(ClientRequest::WebrtcClose)) => client.close_webrtc().await,
+            Ok(ClientMessage::Signal(signal_msg)) => client.handle_signal(&user, signal_msg).await,
+            Err(e) => client.send_error(&e),
         }
     }
 
     // Cleanup
     println!("Cleaning up websocket handler for client {}", client_id);
+    client.cleanup().await;
+    drop(client);
+    drop(ctl_tx);
 
-    // Remove from both authenticated and viewing clients
-    video_state.authenticated_clients.lock().await.remove(&client_id);
-    video_state.viewing_clients.lock().await.remove(&client_id);
-
+    // Let a pending error and close frame go out before dropping the socket
+    let wait = if closing { Duration::from_secs(1) } else { Duration::from_millis(100) };
+    let _ = tokio::time::timeout(wait, &mut sender_task).await;
     sender_task.abort();
     println!("Video websocket handler terminated for client {}", client_id);
 }
 
-pub async fn handle_audio_socket(socket: WebSocket) {
+/// Settles the protocol version, newer clients get the newest one we speak.
+fn negotiate(client: &mut Client, requested: u32) -> Result<(), ErrorMessage> {
+    if requested < MIN_PROTOCOL_VERSION {
+        return Err(ErrorMessage::new(
+            ErrorCode::UnsupportedVersion,
+            format!("Protocol version {} is not supported, use {} to {}", requested, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+        ));
+    }
+    client.version = requested.min(PROTOCOL_VERSION);
+    Ok(())
+}
+
+/// Handles the first text frames of a socket opened without a session
+/// token: bare `Basic` credentials (version 1) or a `hello`.
+async fn login(client: &mut Client, text: &str, client_ip: IpAddr) -> Login {
+    let hello = match ClientMessage::parse(text) {
+        Ok(ClientMessage::Request(ClientRequest::Hello { protocol_version, authorization })) => {
+            Some((protocol_version, authorization))
+        }
+        _ => None,
+    };
+
+    match hello {
+        Some((protocol_version, authorization)) => {
+            if let Err(e) = negotiate(client, protocol_version) {
+                return Login::Failed(e);
+            }
+            let authorization = match authorization {
+                Some(authorization) => authorization,
+                None => return Login::Pending,
+            };
+            match authenticate(&authorization, &client.state, client_ip).await {
+                Ok(user) => {
+                    client.send_welcome(&user);
+                    Login::Done(user)
+                }
+                Err(e) => Login::Failed(e),
+            }
+        }
+        None => match authenticate(text, &client.state, client_ip).await {
+            Ok(user) => {
+                if client.version < 2 {
+                    client.send_text("Authenticated");
+                }
+                client.send_welcome(&user);
+                Login::Done(user)
+            }
+            Err(e) => Login::Failed(e),
+        },
+    }
+}
+
+/// Frames between the last one sent and `seq`. Sequence numbers start
+/// again at 1 with every capture run, which is no gap.
+fn skipped_before(last_sent: Option<u64>, seq: u64) -> u64 {
+    last_sent.map_or(0, |last| seq.saturating_sub(last + 1))
+}
+
+/// Queues a frame, preceded by a `frames_skipped` notice for the gap in
+/// front of it. Both go on the frame queue so the notice cannot overtake
+/// older frames still waiting there, and either both fit or neither is
+/// queued.
+fn queue_frame(
+    frame_tx: &mpsc::Sender<Message>,
+    notice: Option<ServerEvent>,
+    msg: Message,
+) -> Result<(), mpsc::error::TrySendError<()>> {
+    let notice_permit = match &notice {
+        Some(_) => Some(frame_tx.try_reserve()?),
+        None => None,
+    };
+    let frame_permit = frame_tx.try_reserve()?;
+    if let (Some(permit), Some(notice)) = (notice_permit, notice) {
+        permit.send(Message::Text(notice.to_json()));
+    }
+    frame_permit.send(msg);
+    Ok(())
+}
+
+/// Copies one camera's frames to a client. From version 3 on binary
+/// frames get a `FrameHeader`, and frames lost to a lagging receiver or
+/// a full queue are reported with `frames_skipped` right before the next
+/// frame that is sent.
+fn forward_frames(
+    index: i32,
+    mut frames: FrameReceiver,
+    format: FrameFormat,
+    version: u32,
+    frame_tx: mpsc::Sender<Message>,
+    ctl_tx: mpsc::UnboundedSender<Message>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let framed = version >= FRAME_HEADER_VERSION && format != FrameFormat::Base64Json;
+        let mut last_sent: Option<u64> = None;
+        loop {
+            let frame = match frames.recv().await {
+                Ok(frame) => frame,
+                // The gap shows in the sequence number of the next frame
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => {
+                    let error = ErrorMessage::new(ErrorCode::CameraStopped, format!("Camera {} stopped", index));
+                    let _ = ctl_tx.send(Message::Text(error.to_json()));
+                    break;
+                }
+            };
+
+            let skipped = skipped_before(last_sent, frame.seq);
+            let notice = (framed && skipped > 0).then(|| ServerEvent::FramesSkipped { index, skipped, seq: frame.seq });
+            let msg = match format {
+                // H.264 subscriptions are fed by `forward_h264`
+                FrameFormat::Binary | FrameFormat::H264 if framed => {
+                    let header = FrameHeader {
+                        index,
+                        codec: VideoCodec::Jpeg,
+                        keyframe: true,
+                        skipped: skipped.min(u32::MAX as u64) as u32,
+                        seq: frame.seq,
+                        timestamp_ms: frame.timestamp_ms,
+                        monotonic_us: frame.monotonic_us,
+                    };
+                    Message::Binary(header.frame(&frame.jpeg))
+                }
+                FrameFormat::Binary | FrameFormat::H264 => Message::Binary(frame.jpeg.clone()),
+                FrameFormat::Base64Json => {
+                    let frame_msg = SignalMessage::CameraFrame(Signal {
+                        data: BASE64.encode(&frame.jpeg),
+                        room: "default-room".to_string(),
+                        from: format!("camera-{}", index),
+                        to: None,
+                    });
+                    Message::Text(frame_msg.to_json())
+                }
+            };
+            match queue_frame(&frame_tx, notice, msg) {
+                Ok(()) => last_sent = Some(frame.seq),
+                Err(mpsc::error::TrySendError::Full(_)) => {}
+                Err(mpsc::error::TrySendError::Closed(_)) => break,
+            }
+        }
+    })
+}
+
+/// Copies the H.264 stream of one camera to a client. Decoding needs a
+/// keyframe first and again after any gap, so a client that missed a
+/// frame skips ahead to the next keyframe and asks for one.
+fn forward_h264(
+    index: i32,
+    mut chunks: ChunkReceiver,
+    encoders: Arc<H264Encoders>,
+    version: u32,
+    frame_tx: mpsc::Sender<Message>,
+    ctl_tx: mpsc::UnboundedSender<Message>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let framed = version >= FRAME_HEADER_VERSION;
+        let mut last_sent: Option<u64> = None;
+        let mut need_keyframe = true;
+        loop {
+            let chunk = match chunks.recv().await {
+                Ok(chunk) => chunk,
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    need_keyframe = true;
+                    encoders.request_keyframe(index);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    let error = ErrorMessage::new(ErrorCode::CameraStopped, format!("Camera {} stopped", index));
+                    let _ = ctl_tx.send(Message::Text(error.to_json()));
+                    break;
+                }
+            };
+            if need_keyframe && !chunk.keyframe {
+                continue;
+            }
+            need_keyframe = false;
+
+            let skipped = skipped_before(last_sent, chunk.seq);
+            let notice = (framed && skipped > 0).then(|| ServerEvent::FramesSkipped { index, skipped, seq: chunk.seq });
+            let msg = if framed {
+                let header = FrameHeader {
+                    index,
+                    codec: VideoCodec::H264,
+                    keyframe: chunk.keyframe,
+                    skipped: skipped.min(u32::MAX as u64) as u32,
+                    seq: chunk.seq,
+                    timestamp_ms: chunk.timestamp_ms,
+                    monotonic_us: chunk.monotonic_us,
+                };
+                Message::Binary(header.frame(&chunk.data))
+            } else {
+                Message::Binary(chunk.data.clone())
+            };
+            match queue_frame(&frame_tx, notice, msg) {
+                Ok(()) => last_sent = Some(chunk.seq),
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    need_keyframe = true;
+                    encoders.request_keyframe(index);
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => break,
+            }
+        }
+    })
+}
+
+/// `/recordings/<id>/play`: a stored recording as binary JPEG frames
+/// behind a `FrameHeader`, paced by a player thread and steered with
+/// `PlaybackRequest`s. The socket is authenticated before the upgrade.
+pub async fn handle_playback_socket(socket: WebSocket, state: AppState, session: Option<Session>, user: AuthUser, recording: Recording) {
+    println!("{} started playing recording {} ({})", user.username, recording.id, recording.path);
+    let (mut sender, mut receiver) = socket.split();
+    let (request_tx, request_rx) = std::sync::mpsc::channel::<PlaybackRequest>();
+    let (output_tx, mut output_rx) = mpsc::channel::<PlaybackOutput>(FRAME_QUEUE);
+    let id = recording.id;
+    tokio::task::spawn_blocking(move || playback::run(&recording, request_rx, output_tx));
+
+    let session_end = state.sessions.clone().ended(state.user_store.clone(), session);
+    tokio::pin!(session_end);
+    loop {
+        tokio::select! {
+            output = output_rx.recv() => {
+                let msg = match output {
+                    Some(PlaybackOutput::Frame(frame)) => Message::Binary(frame),
+                    Some(PlaybackOutput::Event(event)) => Message::Text(event.to_json()),
+                    Some(PlaybackOutput::Failed(error)) => {
+                        let _ = sender.send(Message::Text(error.to_json())).await;
+                        break;
+                    }
+                    None => break,
+                };
+                if sender.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            msg = receiver.next() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let request = serde_json::from_str::<PlaybackRequest>(&text)
+                    .map_err(|e| ErrorMessage::new(ErrorCode::InvalidMessage, format!("Invalid message: {}", e)))
+                    .and_then(|request| playback::validate(&request).map(|_| request));
+                match request {
+                    Ok(request) => {
+                        if request_tx.send(request).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        if sender.send(Message::Text(e.to_json())).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            error = &mut session_end => {
+                let _ = sender.send(Message::Text(error.to_json())).await;
+                let _ = sender.send(close_message(&error)).await;
+                break;
+            }
+        }
+    }
+    println!("{} stopped playing recording {}", user.username, id);
+}
+
+pub async fn broadcast_message(state: &Users, message: &str, exclude_user: Option<&str>) {
+    let users = state.read().await;
+    for (user_id, tx) in users.iter() {
+        if exclude_user.is_none_or(|excluded| user_id != excluded) {
+            let _ = tx.send(Message::Text(message.to_string()));
+        }
+    }
+}
+
+pub async fn handle_audio_socket(socket: WebSocket, app_state: AppState, session: Option<Session>, client_ip: IpAddr) {
     println!("[WS] New audio WebSocket connection established");
     let (mut ws_sender, mut ws_receiver) = socket.split();
     let (tx, mut rx) = mpsc::channel::<AudioCommand>(32); // Increased channel size
 
     let audio_state = Arc::new(TokioMutex::new(AudioState::new()));
-    let stream_handle = Arc::new(TokioMutex::new(None));
-    let audio_buffer = Arc::new(Mutex::new(Vec::with_capacity(BUFFER_SIZE)));
-
-    // Flag to control audio streaming
-    let is_streaming = Arc::new(AtomicBool::new(false));
-    let is_streaming_sender = is_streaming.clone();
+    // Forwards the shared microphone capture while streaming
+    let mut forward_task: Option<JoinHandle<()>> = None;
 
     // Sender task
-    let sender_handle = tokio::spawn(async move {
+    let mut sender_handle = tokio::spawn(async move {
         println!("[WS] Starting sender task");
         while let Some(cmd) = rx.recv().await {
             match &cmd {
@@ -387,11 +1009,17 @@ pub async fn handle_audio_socket(socket: WebSocket) {
                     }
                 }
                 AudioCommand::Text(text) => println!("[WS] Sending text: {}", text),
+                AudioCommand::Close(err) => println!("[WS] Closing: {}", err.message),
             }
 
             let msg = match cmd {
                 AudioCommand::Data(data) => Message::Binary(data),
                 AudioCommand::Text(text) => Message::Text(text),
+                AudioCommand::Close(err) => {
+                    let _ = ws_sender.send(Message::Text(err.to_json())).await;
+                    let _ = ws_sender.send(close_message(&err)).await;
+                    break;
+                }
             };
 
             if let Err(e) = ws_sender.send(msg).await {
@@ -402,77 +1030,91 @@ pub async fn handle_audio_socket(socket: WebSocket) {
         println!("[WS] Sender task ended");
     });
 
+    let mut auth_user: Option<AuthUser> = None;
+    if let Some(session) = &session {
+        println!("[WS] Authenticated by token as {} ({})", session.user.username, session.user.role);
+        audio_state.lock().await.is_authenticated = true;
+        auth_user = Some(session.user.clone());
+        let _ = tx.send(AudioCommand::Text("Authenticated".to_string())).await;
+    }
+    let session_end = app_state.sessions.clone().ended(app_state.user_store.clone(), session);
+    tokio::pin!(session_end);
+    let mut session_ended = false;
+
     // Message handling loop
-    while let Some(Ok(msg)) = ws_receiver.next().await {
+    loop {
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            error = &mut session_end => {
+                let _ = tx.send(AudioCommand::Close(error)).await;
+                session_ended = true;
+                break;
+            }
+        };
         match msg {
             Message::Text(text) => {
                 println!("[WS] Received text: {}", text);
                 let mut state = audio_state.lock().await;
 
                 if !state.is_authenticated {
-                    if authenticate_basic(&text).is_ok() {
-                        state.is_authenticated = true;
-                        drop(state);
-                        let _ = tx.send(AudioCommand::Text("Authenticated".to_string())).await;
-                    } else {
-                        drop(state);
-                        let _ = tx.send(AudioCommand::Text("Unauthorized".to_string())).await;
-                        break;
+                    match authenticate(&text, &app_state, client_ip).await {
+                        Ok(user) => {
+                            state.is_authenticated = true;
+                            auth_user = Some(user);
+                            drop(state);
+                            let _ = tx.send(AudioCommand::Text("Authenticated".to_string())).await;
+                        }
+                        Err(e) => {
+                            drop(state);
+                            println!("[WS] Authentication failed from {}: {}", client_ip, e.message);
+                            let _ = tx.send(AudioCommand::Text(login_failure_text(&e))).await;
+                            break;
+                        }
                     }
                 } else {
+                    drop(state);
+                    let user = match &auth_user {
+                        Some(user) => user,
+                        None => break,
+                    };
                     match text.as_str() {
                         "start_audio" => {
-                            if !is_streaming.load(Ordering::SeqCst) {
-                                println!("[AUDIO] Starting audio stream");
-                                let mut handle = stream_handle.lock().await;
-
-                                if handle.is_none() {
-                                    let (audio_sender, audio_receiver) = crossbeam_channel::bounded(32);
-
-                                    // Audio forwarding task
-                                    let forward_task = {
-                                        let tx = tx.clone();
-                                        let is_streaming = is_streaming_sender.clone();
-                                        tokio::spawn(async move {
-                                            println!("[AUDIO] Starting forward task");
-                                            while let Ok(data) = audio_receiver.recv() {
-                                                if !is_streaming.load(Ordering::SeqCst) {
-                                                    println!("[AUDIO] Streaming stopped, ending forward task");
-                                                    break;
-                                                }
-                                                if let Err(e) = tx.send(AudioCommand::Data(data)).await {
-                                                    println!("[AUDIO] Forward task error: {:?}", e);
-                                                    break;
-                                                }
-                                            }
-                                            println!("[AUDIO] Forward task ended");
-                                        })
-                                    };
-
-                                    match setup_audio_stream(audio_buffer.clone(), audio_sender.clone()) {
-                                        Ok(new_handle) => {
-                                            *handle = Some(new_handle);
-                                            is_streaming.store(true, Ordering::SeqCst);
-                                            let _ = tx.send(AudioCommand::Text("Audio started".to_string())).await;
-                                        }
-                                        Err(e) => {
-                                            let _ = tx.send(AudioCommand::Text(format!("Failed to start audio: {}", e))).await;
-                                            forward_task.abort();
-                                        }
-                                    }
+                            if let Err(e) = user.check(Action::Listen) {
+                                let _ = tx.send(AudioCommand::Text(e.to_json())).await;
+                                continue;
+                            }
+                            if forward_task.as_ref().is_some_and(|task| !task.is_finished()) {
+                                continue;
+                            }
+                            println!("[AUDIO] Starting audio stream");
+                            // A new name each time, an aborted task may leave the microphone late
+                            let listener = format!("ears-{}", uuid::Uuid::new_v4());
+                            match app_state.microphone.subscription(listener) {
+                                Ok(audio) => {
+                                    forward_task = Some(tokio::spawn(forward_audio(audio, tx.clone())));
+                                    let _ = tx.send(AudioCommand::Text("Audio started".to_string())).await;
+                                }
+                                Err(e) => {
+                                    let _ = tx.send(AudioCommand::Text(format!("Failed to start audio: {}", e))).await;
                                 }
                             }
                         }
                         "stop_audio" => {
                             println!("[AUDIO] Stopping audio stream");
-                            is_streaming.store(false, Ordering::SeqCst);
-                            let mut handle = stream_handle.lock().await;
-                            if let Some(h) = handle.take() {
-                                stop_audio_stream(h);
+                            if let Some(task) = forward_task.take() {
+                                // Dropping the subscription leaves the microphone
+                                task.abort();
                                 let _ = tx.send(AudioCommand::Text("Audio stopped".to_string())).await;
                             }
                         }
-                        _ => println!("[WS] Unknown command: {}", text),
+                        command => {
+                            println!("[WS] Unknown command: {}", command);
+                            let error = ErrorMessage::new(ErrorCode::InvalidAction, format!("Unknown command '{}'", command));
+                            let _ = tx.send(AudioCommand::Text(error.to_json())).await;
+                        }
                     }
                 }
             }
@@ -486,109 +1128,57 @@ pub async fn handle_audio_socket(socket: WebSocket) {
 
     // Cleanup
     println!("[WS] Cleaning up connection");
-    is_streaming.store(false, Ordering::SeqCst);
-    let mut handle = stream_handle.lock().await;
-    if let Some(h) = handle.take() {
-        stop_audio_stream(h);
+    if let Some(task) = forward_task.take() {
+        task.abort();
+    }
+    if session_ended {
+        let _ = tokio::time::timeout(Duration::from_secs(1), &mut sender_handle).await;
     }
     sender_handle.abort();
 }
 
-fn setup_audio_stream(
-    audio_buffer: Arc<Mutex<Vec<u8>>>,
-    audio_sender: crossbeam_channel::Sender<Vec<u8>>,
-) -> Result<AudioStreamHandle, String> {
-    let host = cpal::default_host();
-
-    // Get default input device
-    let device = host.default_input_device()
-        .ok_or_else(|| "No input device available".to_string())?;
-
-    println!("[AUDIO] Using device: {}", device.name().unwrap_or_default());
-
-    // Use explicit config
-    let config = cpal::StreamConfig {
-        channels: CHANNELS,
-        sample_rate: cpal::SampleRate(SAMPLE_RATE),
-        buffer_size: cpal::BufferSize::Fixed(BUFFER_SIZE as u32),
-    };
-
-    println!("[AUDIO] Stream config: {:?}", config);
-
-    let stop_signal = Arc::new(Mutex::new(false));
-    let stop_signal_clone = stop_signal.clone();
-
-    // Ring buffer for audio processing
-    let ring_buffer = Arc::new(Mutex::new(Vec::with_capacity(BUFFER_SIZE * 2)));
-    let ring_buffer_clone = ring_buffer.clone();
-
-    let stream = device.build_input_stream(
-        &config,
-        move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            if *stop_signal_clone.lock().unwrap() {
-                return;
-            }
-
-            let mut buffer = ring_buffer_clone.lock().unwrap();
-
-            // Convert samples with noise gate and normalization
-            let mut max_amplitude = 0.0f32;
-            let mut has_audio = false;
-
-            let audio_data: Vec<u8> = data.iter()
-                .map(|&sample| {
-                    // Update max amplitude
-                    max_amplitude = max_amplitude.max(sample.abs());
-
-                    // Apply noise gate
-                    let gated = if sample.abs() < 0.01 { 0.0 } else { sample };
-                    if gated != 0.0 {
-                        has_audio = true;
-                    }
+/// Sends the microphone's chunks to an ears client until the capture
+/// ends or the socket goes.
+async fn forward_audio(mut audio: AudioSubscription, tx: mpsc::Sender<AudioCommand>) {
+    println!("[AUDIO] Starting forward task");
+    loop {
+        let chunk = match audio.chunks.recv().await {
+            Ok(chunk) => chunk,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if let Err(e) = tx.send(AudioCommand::Data(chunk.to_vec())).await {
+            println!("[AUDIO] Forward task error: {:?}", e);
+            break;
+        }
+    }
+    println!("[AUDIO] Forward task ended");
+}
 
-                    // Convert to i16
-                    let normalized = if max_amplitude > 1.0 {
-                        gated / max_amplitude
-                    } else {
-                        gated
-                    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    let scaled = (normalized * 32767.0) as i16;
-                    scaled.to_le_bytes()
-                })
-                .flatten()
-                .collect();
-
-            // Only send if we have actual audio
-            if has_audio {
-                buffer.extend(audio_data);
-
-                // Send when we have enough data
-                if buffer.len() >= BUFFER_SIZE {
-                    let data_to_send = buffer.split_off(0);
-                    if let Err(e) = audio_sender.try_send(data_to_send) {
-                        eprintln!("[AUDIO] Send error: {:?}", e);
-                    }
-                }
-            }
-        },
-        move |err| eprintln!("[AUDIO] Stream error: {:?}", err),
-        Some(Duration::from_millis(LATENCY_MS)),
-    ).map_err(|e| format!("Failed to build input stream: {:?}", e))?;
+    #[test]
+    fn nothing_skipped_before_the_first_frame() {
+        assert_eq!(skipped_before(None, 1), 0);
+        assert_eq!(skipped_before(None, 42), 0);
+    }
 
-    stream.play().map_err(|e| format!("Failed to start stream: {:?}", e))?;
-    println!("[AUDIO] Stream started successfully");
+    #[test]
+    fn consecutive_frames_skip_nothing() {
+        assert_eq!(skipped_before(Some(7), 8), 0);
+    }
 
-    Ok(AudioStreamHandle {
-        stream: Arc::new(stream),
-        stop_signal,
-    })
-}
+    #[test]
+    fn gaps_count_the_missing_frames() {
+        assert_eq!(skipped_before(Some(7), 9), 1);
+        assert_eq!(skipped_before(Some(7), 20), 12);
+    }
 
-fn stop_audio_stream(handle: AudioStreamHandle) {
-    println!("[AUDIO] Stopping stream");
-    *handle.stop_signal.lock().unwrap() = true;
-    if let Err(e) = handle.stream.pause() {
-        println!("[AUDIO] Error stopping stream: {:?}", e);
+    #[test]
+    fn a_new_capture_run_is_no_gap() {
+        assert_eq!(skipped_before(Some(500), 1), 0);
+        assert_eq!(skipped_before(Some(500), 500), 0);
     }
-}
\ No newline at end of file
+}